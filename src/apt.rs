@@ -0,0 +1,108 @@
+use std::fs;
+
+use hyper::Uri;
+
+use crate::TestResult;
+
+/// File at the root of Debian and Ubuntu archives downloaded to test a mirror,
+/// a listing of the whole archive that is large enough to measure.
+pub const TEST_PATH: &str = "ls-lR.gz";
+
+/// Where the lines of a `sources.list` snippet point mirrors at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+  /// Codename like `bookworm` or `noble`
+  pub suite: String,
+  pub components: Vec<String>,
+  /// Path tested below the base URL of each mirror
+  pub test_path: String,
+}
+
+impl Default for Release {
+  /// The release of this machine from `/etc/os-release`, `stable` if unknown.
+  fn default() -> Self {
+    let codename = fs::read_to_string("/etc/os-release")
+      .ok()
+      .and_then(|content| {
+        content.lines().find_map(|line| {
+          let codename = line.strip_prefix("VERSION_CODENAME=")?;
+          Some(codename.trim_matches('"').to_string())
+        })
+      })
+      .filter(|codename| !codename.is_empty());
+    Release {
+      suite: codename.unwrap_or_else(|| "stable".to_string()),
+      components: vec!["main".to_string()],
+      test_path: TEST_PATH.to_string(),
+    }
+  }
+}
+
+impl Release {
+  /// The URL testing the mirror at `base`.
+  pub fn test_url(&self, base: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), self.test_path)
+  }
+
+  /// The base URL of a tested mirror, undoing [`Release::test_url`].
+  pub fn base_url(&self, uri: &Uri) -> String {
+    let url = uri.to_string();
+    match url.strip_suffix(&self.test_path) {
+      Some(base) => base.to_string(),
+      None => url,
+    }
+  }
+
+  /// A `sources.list` snippet with the mirrors in the order of `results`,
+  /// failed ones commented out.
+  pub fn sources_list(&self, results: &[TestResult]) -> String {
+    let components = self.components.join(" ");
+    let mut out = String::new();
+    for data in results {
+      let base = self.base_url(&data.uri);
+      let line = format!("deb {base} {} {components}", self.suite);
+      match &data.error {
+        None => out.push_str(&format!("{line}\n")),
+        Some(error) => {
+          let error = error.lines().next().unwrap_or_default();
+          out.push_str(&format!("# {error}\n# {line}\n"));
+        },
+      }
+    }
+    out
+  }
+}
+
+/// Base URLs of `mirrors.ubuntu.com/mirrors.txt`, one per line.
+pub fn parse_ubuntu_mirrors(content: &str) -> Vec<String> {
+  content
+    .lines()
+    .map(str::trim)
+    .filter(|line| line.starts_with("http://") || line.starts_with("https://"))
+    .map(str::to_string)
+    .collect()
+}
+
+/// Base URLs of the HTTP archive mirrors of Debian's `Mirrors.masterlist`,
+/// whose entries are blocks of `Key: value` lines like
+///
+/// ```text
+/// Site: ftp.de.debian.org
+/// Type: Push-Primary
+/// Archive-http: /debian/
+/// ```
+pub fn parse_debian_masterlist(content: &str) -> Vec<String> {
+  let mut mirrors = Vec::new();
+  for entry in content.split("\n\n") {
+    let field = |name: &str| {
+      entry.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == name).then(|| value.trim())
+      })
+    };
+    if let (Some(site), Some(path)) = (field("Site"), field("Archive-http")) {
+      mirrors.push(format!("http://{site}{path}"));
+    }
+  }
+  mirrors
+}
@@ -0,0 +1,117 @@
+use std::{
+  convert::Infallible,
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use clap::ValueEnum;
+use http_body_util::Full;
+use hyper::body::{Bytes, Frame, SizeHint};
+
+/// Size of the chunks a generated payload is sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Request body, either given in full or generated for `--upload`.
+#[derive(Debug, Clone)]
+pub enum Body {
+  Full(Full<Bytes>),
+  Generated(Payload),
+}
+
+impl Body {
+  pub fn new(data: Bytes) -> Body {
+    Body::Full(Full::new(data))
+  }
+}
+
+impl Default for Body {
+  fn default() -> Self {
+    Body::Full(Full::default())
+  }
+}
+
+impl From<String> for Body {
+  fn from(s: String) -> Body {
+    Body::Full(Full::from(s))
+  }
+}
+
+impl hyper::body::Body for Body {
+  type Data = Bytes;
+  type Error = Infallible;
+
+  fn poll_frame(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+    match self.get_mut() {
+      Body::Full(full) => Pin::new(full).poll_frame(cx),
+      Body::Generated(payload) => {
+        Poll::Ready(payload.next_chunk().map(|chunk| Ok(Frame::data(chunk))))
+      },
+    }
+  }
+
+  fn is_end_stream(&self) -> bool {
+    match self {
+      Body::Full(full) => full.is_end_stream(),
+      Body::Generated(payload) => payload.remaining == 0,
+    }
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    match self {
+      Body::Full(full) => full.size_hint(),
+      Body::Generated(payload) => SizeHint::with_exact(payload.remaining),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PayloadKind {
+  /// Pseudo-random bytes, which compression on the way can't shrink
+  #[default]
+  Random,
+  /// Zero bytes
+  Zero,
+}
+
+/// A payload of a given size, streamed in chunks without holding it in memory.
+#[derive(Debug, Clone)]
+pub struct Payload {
+  chunk: Bytes,
+  remaining: u64,
+}
+
+impl Payload {
+  pub fn new(size: u64, kind: PayloadKind) -> Payload {
+    let chunk = match kind {
+      PayloadKind::Zero => vec![0; CHUNK_SIZE],
+      PayloadKind::Random => {
+        // xorshift64, seeded randomly so every run sends different bytes
+        let mut state = crate::random_u64() | 1;
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        while chunk.len() < CHUNK_SIZE {
+          state ^= state << 13;
+          state ^= state >> 7;
+          state ^= state << 17;
+          chunk.extend_from_slice(&state.to_le_bytes());
+        }
+        chunk
+      },
+    };
+    Payload {
+      chunk: Bytes::from(chunk),
+      remaining: size,
+    }
+  }
+
+  fn next_chunk(&mut self) -> Option<Bytes> {
+    if self.remaining == 0 {
+      return None;
+    }
+    let len = self.remaining.min(self.chunk.len() as u64);
+    self.remaining -= len;
+    Some(self.chunk.slice(..len as usize))
+  }
+}
@@ -0,0 +1,159 @@
+use std::{
+  cell::Cell,
+  future::Future,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use console::style;
+use hyper::{Method, Request};
+use tokio::sync::watch;
+
+use crate::{
+  clone_request, latency, output, printer::Printer, Body, TestOptions, TestResult, TlsHyper,
+};
+
+/// Probes measuring the latency before the transfer starts.
+const IDLE_SAMPLES: usize = 5;
+/// Time between probes while the transfer runs.
+const LOADED_INTERVAL: Duration = Duration::from_millis(200);
+/// Probes under load needed for a grade, fewer come from transfers too short
+/// to tell.
+const MIN_LOADED_SAMPLES: usize = 3;
+
+/// Tells [`test`] when the transfer started loading the connection, which is
+/// once the body flows rather than while connecting.
+#[derive(Debug, Clone)]
+pub struct Load(Arc<watch::Sender<bool>>);
+
+impl Load {
+  pub fn new() -> Load {
+    Load(Arc::new(watch::Sender::new(false)))
+  }
+
+  pub fn start(&self) {
+    self
+      .0
+      .send_if_modified(|started| !std::mem::replace(started, true));
+  }
+
+  async fn started(&self) {
+    let _ = self.0.subscribe().wait_for(|started| *started).await;
+  }
+}
+
+impl Default for Load {
+  fn default() -> Self {
+    Load::new()
+  }
+}
+
+/// Latency of a server before and while a transfer loaded the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bufferbloat {
+  /// Median latency before the transfer
+  pub idle: Duration,
+  /// Median latency during the transfer
+  pub loaded: Duration,
+}
+
+impl Bufferbloat {
+  /// How much the transfer increased the latency.
+  pub fn increase(&self) -> Duration {
+    self.loaded.saturating_sub(self.idle)
+  }
+
+  /// Letter grade of the increase, on the scale of the Waveform bufferbloat test.
+  pub fn grade(&self) -> &'static str {
+    match self.increase().as_millis() {
+      0..5 => "A+",
+      5..30 => "A",
+      30..60 => "B",
+      60..200 => "C",
+      200..400 => "D",
+      _ => "F",
+    }
+  }
+}
+
+/// Runs `test` while probing the latency of the server of `request` once
+/// `load` started, after measuring its latency without load.
+pub async fn test(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+  load: &Load,
+  test: impl Future<Output = anyhow::Result<TestResult>>,
+) -> anyhow::Result<TestResult> {
+  let mut idle = Vec::new();
+  for _ in 0..IDLE_SAMPLES {
+    idle.push(probe(client, request, options).await?);
+  }
+  let idle = latency::median(&mut idle).context("No idle latency")?;
+  printer.println(format_args!(
+    "Idle latency {}",
+    output::format_millis(idle, options.time_precision)
+  ));
+
+  let done = Cell::new(false);
+  let testing = async {
+    let result = test.await;
+    done.set(true);
+    // Probing waits for the load, which a failed test never started
+    load.start();
+    result
+  };
+  let probing = async {
+    load.started().await;
+    let mut loaded = Vec::new();
+    while !done.get() {
+      let started = Instant::now();
+      // Probes timing out under load only fail to count
+      if let Ok(latency) = probe(client, request, options).await {
+        loaded.push(latency);
+      }
+      tokio::time::sleep(LOADED_INTERVAL.saturating_sub(started.elapsed())).await;
+    }
+    loaded
+  };
+  let (result, mut loaded) = tokio::join!(testing, probing);
+  let mut result = result?;
+  if loaded.len() < MIN_LOADED_SAMPLES {
+    printer.println(
+      style(format!(
+        "Transfer too short to grade bufferbloat, {} of {MIN_LOADED_SAMPLES} loaded probes",
+        loaded.len()
+      ))
+      .yellow(),
+    );
+  } else if let Some(loaded) = latency::median(&mut loaded) {
+    let bufferbloat = Bufferbloat { idle, loaded };
+    printer.println(format_args!(
+      "Loaded latency {} (+{}, grade {})",
+      output::format_millis(loaded, options.time_precision),
+      output::format_millis(bufferbloat.increase(), options.time_precision),
+      bufferbloat.grade()
+    ));
+    result.bufferbloat = Some(bufferbloat);
+  }
+  Ok(result)
+}
+
+/// Time until a HEAD request to the server of `request` is answered.
+async fn probe(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+) -> anyhow::Result<Duration> {
+  let mut head = clone_request(request);
+  *head.method_mut() = Method::HEAD;
+  *head.body_mut() = Body::default();
+  let start = Instant::now();
+  tokio::time::timeout(options.request_timeout, client.request(head))
+    .await
+    .with_context(|| format!("Timed out for {:?}", options.request_timeout))?
+    .context("Failed to send request")?;
+  Ok(start.elapsed())
+}
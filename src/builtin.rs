@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use clap::ValueEnum;
+use hyper::Method;
+
+use spt::{fast, Target, TlsHyper};
+
+use crate::request_targets;
+
+const CLOUDFLARE: &str = "https://speed.cloudflare.com";
+/// Sizes of the Cloudflare downloads, the largest its endpoint serves
+const CLOUDFLARE_SIZES: [u64; 3] = [10_000_000, 25_000_000, 100_000_000];
+
+/// Public speed test services to test against instead of given URLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Builtin {
+  /// speed.cloudflare.com, downloading 10, 25 and 100 MB or uploading to it
+  /// with `--upload`
+  #[value(alias = "cf")]
+  Cloudflare,
+  /// fast.com, downloading from the Netflix servers its API picks, all at
+  /// once like fast.com does
+  Fast,
+}
+
+impl Builtin {
+  /// Method of uploads unless `--upload-method` says otherwise.
+  pub fn upload_method(self) -> Method {
+    match self {
+      Builtin::Cloudflare | Builtin::Fast => Method::POST,
+    }
+  }
+
+  /// Number of URLs to test at once unless `--concurrency` says otherwise.
+  pub fn concurrency(self) -> Option<u16> {
+    match self {
+      Builtin::Cloudflare => None,
+      Builtin::Fast => Some(fast::URL_COUNT),
+    }
+  }
+
+  /// How long to download each body unless `--duration` says otherwise, so
+  /// slow connections are measured without running into the time limit.
+  pub fn duration(self) -> Option<Duration> {
+    match self {
+      Builtin::Cloudflare | Builtin::Fast => Some(Duration::from_secs(15)),
+    }
+  }
+
+  /// Targets downloading from the service, or uploading if `upload`, asking
+  /// its API for servers with `client`.
+  pub async fn targets(self, client: &TlsHyper, upload: bool) -> anyhow::Result<Vec<Target>> {
+    let urls = match (self, upload) {
+      (Builtin::Cloudflare, true) => vec![format!("{CLOUDFLARE}/__up")],
+      (Builtin::Cloudflare, false) => CLOUDFLARE_SIZES
+        .iter()
+        .map(|size| format!("{CLOUDFLARE}/__down?bytes={size}"))
+        .collect(),
+      (Builtin::Fast, true) => bail!("Uploads to fast.com are not supported"),
+      (Builtin::Fast, false) => fast::urls(client).await?,
+    };
+    let mut targets = Vec::new();
+    for url in urls {
+      targets.extend(request_targets(Method::GET, &url, &[])?);
+    }
+    Ok(targets)
+  }
+}
@@ -0,0 +1,88 @@
+use std::{fmt, str::FromStr};
+
+use aws_lc_rs::digest::{Context, SHA256};
+
+/// A SHA-256 digest, written as 64 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sha256(pub [u8; 32]);
+
+impl FromStr for Sha256 {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    if s.len() != 64 {
+      return Err(format!("expected 64 hex digits, got {s}"));
+    }
+    // `from_str_radix` would also take a sign
+    if !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+      return Err(format!("invalid hex digits: {s}"));
+    }
+    let mut digest = [0; 32];
+    for (idx, byte) in digest.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&s[idx * 2..idx * 2 + 2], 16)
+        .map_err(|_| format!("invalid hex digits: {s}"))?;
+    }
+    Ok(Sha256(digest))
+  }
+}
+
+impl fmt::Display for Sha256 {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for byte in self.0 {
+      write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+  }
+}
+
+/// Hashes a body as it streams by.
+pub struct Hasher(Context);
+
+impl Hasher {
+  pub fn new() -> Hasher {
+    Hasher(Context::new(&SHA256))
+  }
+
+  pub fn update(&mut self, chunk: &[u8]) {
+    self.0.update(chunk);
+  }
+
+  pub fn finish(self) -> Sha256 {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(self.0.finish().as_ref());
+    Sha256(digest)
+  }
+}
+
+impl Default for Hasher {
+  fn default() -> Self {
+    Hasher::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// SHA-256 of `abc`, from FIPS 180-2.
+  const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+  #[test]
+  fn hashes_chunks() {
+    let mut hasher = Hasher::new();
+    hasher.update(b"a");
+    hasher.update(b"bc");
+    assert_eq!(hasher.finish().to_string(), ABC);
+  }
+
+  #[test]
+  fn parses_digests() {
+    let digest = Sha256::from_str(&format!(" {} ", ABC.to_uppercase())).unwrap();
+    assert_eq!(digest.to_string(), ABC);
+    assert!(Sha256::from_str(&ABC[1..]).is_err());
+    assert!(Sha256::from_str(&format!("{}xy", &ABC[2..])).is_err());
+    assert!(Sha256::from_str(&format!("+f{}", &ABC[2..])).is_err());
+    assert!(Sha256::from_str(&format!("é{}", &ABC[2..])).is_err());
+  }
+}
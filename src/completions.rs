@@ -0,0 +1,501 @@
+use std::fmt::Write as _;
+
+use clap::{Arg, Command, CommandFactory, ValueEnum, ValueHint};
+
+use crate::Cli;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+  Bash,
+  Zsh,
+  Fish,
+  Powershell,
+}
+
+/// What an option or a command is followed by.
+enum Value {
+  None,
+  Any,
+  Url,
+  Path,
+  Choices(Vec<String>),
+}
+
+struct Opt {
+  short: Option<char>,
+  long: Option<String>,
+  /// First line of the help, for shells which show it besides the option
+  help: String,
+  value: Value,
+}
+
+impl Opt {
+  fn names(&self) -> impl Iterator<Item = String> + '_ {
+    let short = self.short.map(|short| format!("-{short}"));
+    let long = self.long.as_ref().map(|long| format!("--{long}"));
+    short.into_iter().chain(long)
+  }
+}
+
+/// `spt` or one of its subcommands, which have options of their own.
+struct Node {
+  /// Names of the subcommands leading here, empty for `spt` itself
+  path: Vec<String>,
+  /// The options, without the hidden ones
+  options: Vec<Opt>,
+  /// Names and first help lines of the subcommands
+  subcommands: Vec<(String, String)>,
+  /// What the positional arguments are
+  positional: Value,
+}
+
+impl Node {
+  /// The command line words naming the command, like `spt mirrors arch`.
+  fn name(&self) -> String {
+    std::iter::once("spt")
+      .chain(self.path.iter().map(String::as_str))
+      .collect::<Vec<_>>()
+      .join(" ")
+  }
+
+  /// Subcommand names and positional choices, which complete words not
+  /// starting with a dash.
+  fn words(&self) -> Vec<String> {
+    let mut words: Vec<String> = self
+      .subcommands
+      .iter()
+      .map(|(name, _)| name.clone())
+      .collect();
+    if let Value::Choices(choices) = &self.positional {
+      words.extend(choices.iter().cloned());
+    }
+    words
+  }
+}
+
+/// `spt` and all its subcommands, parents before their children.
+fn nodes() -> Vec<Node> {
+  let mut command = Cli::command();
+  command.build();
+  let mut nodes = Vec::new();
+  walk(&command, Vec::new(), &mut nodes);
+  nodes
+}
+
+fn walk(command: &Command, path: Vec<String>, nodes: &mut Vec<Node>) {
+  let subcommands: Vec<&Command> = command
+    .get_subcommands()
+    .filter(|subcommand| !subcommand.is_hide_set() && subcommand.get_name() != "help")
+    .collect();
+  nodes.push(Node {
+    path: path.clone(),
+    options: command
+      .get_arguments()
+      .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+      .map(|arg| Opt {
+        short: arg.get_short(),
+        long: arg.get_long().map(str::to_string),
+        help: first_line(arg.get_help().map(|help| help.to_string())),
+        value: value(arg),
+      })
+      .collect(),
+    subcommands: subcommands
+      .iter()
+      .map(|subcommand| {
+        (
+          subcommand.get_name().to_string(),
+          first_line(subcommand.get_about().map(|about| about.to_string())),
+        )
+      })
+      .collect(),
+    positional: command
+      .get_positionals()
+      .find(|arg| !arg.is_hide_set())
+      .map_or(Value::None, value),
+  });
+  for subcommand in subcommands {
+    let mut path = path.clone();
+    path.push(subcommand.get_name().to_string());
+    walk(subcommand, path, nodes);
+  }
+}
+
+/// A shell function name for the command at `path`, like `_spt_mirrors_arch`.
+fn function(path: &[String]) -> String {
+  std::iter::once("_spt")
+    .chain(path.iter().map(String::as_str))
+    .collect::<Vec<_>>()
+    .join("_")
+    .replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_")
+}
+
+fn first_line(help: Option<String>) -> String {
+  help
+    .unwrap_or_default()
+    .lines()
+    .next()
+    .unwrap_or_default()
+    .trim_end_matches('.')
+    .to_string()
+}
+
+fn value(arg: &Arg) -> Value {
+  if !arg.get_num_args().unwrap_or_default().takes_values() {
+    return Value::None;
+  }
+  let choices: Vec<String> = arg
+    .get_possible_values()
+    .iter()
+    .filter(|value| !value.is_hide_set())
+    .map(|value| value.get_name().to_string())
+    .collect();
+  if !choices.is_empty() {
+    return Value::Choices(choices);
+  }
+  match arg.get_value_hint() {
+    ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath => Value::Path,
+    ValueHint::Url => Value::Url,
+    _ => Value::Any,
+  }
+}
+
+/// A completion script for `shell`, to be sourced from its startup files.
+pub fn generate(shell: Shell) -> String {
+  let nodes = nodes();
+  match shell {
+    Shell::Bash => bash(&nodes),
+    Shell::Zsh => zsh(&nodes),
+    Shell::Fish => fish(&nodes),
+    Shell::Powershell => powershell(&nodes),
+  }
+}
+
+fn bash(nodes: &[Node]) -> String {
+  let mut descend = String::new();
+  let mut values = String::new();
+  let mut flags = String::new();
+  let mut words = String::new();
+  for node in nodes {
+    let name = node.name();
+    for (subcommand, _) in &node.subcommands {
+      let _ = writeln!(
+        descend,
+        "      '{name} {subcommand}') cmd='{name} {subcommand}' ;;"
+      );
+    }
+    for opt in &node.options {
+      let names = opt
+        .names()
+        .map(|option| format!("'{name} {option}'"))
+        .collect::<Vec<_>>()
+        .join("|");
+      let reply = match &opt.value {
+        Value::None => continue,
+        Value::Any | Value::Url => "COMPREPLY=()".to_string(),
+        Value::Path => r#"COMPREPLY=($(compgen -f -- "$cur"))"#.to_string(),
+        Value::Choices(choices) => {
+          format!(
+            r#"COMPREPLY=($(compgen -W "{}" -- "$cur"))"#,
+            choices.join(" ")
+          )
+        },
+      };
+      let _ = writeln!(values, "    {names})\n      {reply}\n      return ;;");
+    }
+    let _ = writeln!(
+      flags,
+      r#"      '{name}') COMPREPLY=($(compgen -W "{}" -- "$cur")) ;;"#,
+      node
+        .options
+        .iter()
+        .flat_map(Opt::names)
+        .collect::<Vec<_>>()
+        .join(" ")
+    );
+    let _ = writeln!(
+      words,
+      r#"      '{name}') COMPREPLY=($(compgen -W "{}" -- "$cur")) ;;"#,
+      node.words().join(" ")
+    );
+  }
+  format!(
+    r#"_spt() {{
+  local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}" cmd=spt word
+  for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+    case "$cmd $word" in
+{descend}    esac
+  done
+  case "$cmd $prev" in
+{values}  esac
+  if [[ $cur == -* ]]; then
+    case "$cmd" in
+{flags}    esac
+  else
+    case "$cmd" in
+{words}    esac
+  fi
+}}
+complete -o default -F _spt spt
+"#
+  )
+}
+
+fn zsh(nodes: &[Node]) -> String {
+  let escape = |s: &str| {
+    s.replace('\'', "'\\''")
+      .replace('[', "\\[")
+      .replace(']', "\\]")
+      .replace(':', "\\:")
+  };
+  let mut functions = String::new();
+  for node in nodes {
+    let mut specs = String::new();
+    for opt in &node.options {
+      let action = match &opt.value {
+        Value::None => String::new(),
+        Value::Any => ": :".to_string(),
+        Value::Url => ": :_urls".to_string(),
+        Value::Path => ": :_files".to_string(),
+        Value::Choices(choices) => format!(": :({})", choices.join(" ")),
+      };
+      for name in opt.names() {
+        let equals = if name.starts_with("--") && !action.is_empty() {
+          "="
+        } else {
+          ""
+        };
+        let _ = writeln!(
+          specs,
+          "    '{name}{equals}[{}]{action}' \\",
+          escape(&opt.help)
+        );
+      }
+    }
+    let positional = match &node.positional {
+      Value::None => "",
+      Value::Any => "_default",
+      Value::Url => "_urls",
+      Value::Path => "_files",
+      Value::Choices(_) => "",
+    };
+    let mut first = String::new();
+    if !node.subcommands.is_empty() {
+      let subcommands = node
+        .subcommands
+        .iter()
+        .map(|(name, about)| format!("'{}:{}'", escape(name), about.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ");
+      let _ = write!(
+        first,
+        "\n    local -a subcommands=({subcommands})\n    _describe -t subcommands subcommand subcommands"
+      );
+    }
+    if let Value::Choices(choices) = &node.positional {
+      let _ = write!(first, "\n    compadd -- {}", choices.join(" "));
+    }
+    if !positional.is_empty() {
+      let _ = write!(first, "\n    {positional}");
+    }
+    if first.is_empty() {
+      first.push_str("\n    :");
+    }
+    let mut rest = String::new();
+    for (name, _) in &node.subcommands {
+      let mut path = node.path.clone();
+      path.push(name.clone());
+      let _ = writeln!(rest, "      {name}) {} ;;", function(&path));
+    }
+    if !positional.is_empty() {
+      let _ = writeln!(rest, "      *) {positional} ;;");
+    }
+    let _ = write!(
+      functions,
+      r#"{function}() {{
+  local curcontext="$curcontext" state line
+  _arguments -s -C \
+{specs}    '*::arg:->args'
+  [[ $state == args ]] || return
+  if (( CURRENT == 1 )); then{first}
+  else
+    case $words[1] in
+{rest}    esac
+  fi
+}}
+
+"#,
+      function = function(&node.path),
+    );
+  }
+  format!(
+    r#"#compdef spt
+
+{functions}if [ "$funcstack[1]" = "_spt" ]; then
+  _spt "$@"
+else
+  compdef _spt spt
+fi
+"#
+  )
+}
+
+fn fish(nodes: &[Node]) -> String {
+  let mut script = String::new();
+  for node in nodes {
+    // Seen all subcommands leading here and none leading further
+    let mut condition = node
+      .path
+      .iter()
+      .map(|name| format!("__fish_seen_subcommand_from {name}"))
+      .collect::<Vec<_>>();
+    if !node.subcommands.is_empty() {
+      let subcommands = node
+        .subcommands
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+      condition.push(format!("not __fish_seen_subcommand_from {subcommands}"));
+    }
+    let prefix = format!("complete -c spt -n '{}'", condition.join("; and "));
+    for (name, about) in &node.subcommands {
+      let _ = writeln!(
+        script,
+        "{prefix} -f -a {name} -d '{}'",
+        about.replace('\'', "\\'")
+      );
+    }
+    if let Value::Choices(choices) = &node.positional {
+      let _ = writeln!(script, "{prefix} -f -a '{}'", choices.join(" "));
+    }
+    for opt in &node.options {
+      let mut line = prefix.clone();
+      if let Some(short) = opt.short {
+        let _ = write!(line, " -s {short}");
+      }
+      if let Some(long) = &opt.long {
+        let _ = write!(line, " -l {long}");
+      }
+      match &opt.value {
+        Value::None => {},
+        Value::Any | Value::Url => line.push_str(" -r -f"),
+        Value::Path => line.push_str(" -r -F"),
+        Value::Choices(choices) => {
+          let _ = write!(line, " -r -f -a '{}'", choices.join(" "));
+        },
+      }
+      if !opt.help.is_empty() {
+        let _ = write!(line, " -d '{}'", opt.help.replace('\'', "\\'"));
+      }
+      script.push_str(&line);
+      script.push('\n');
+    }
+  }
+  script
+}
+
+fn powershell(nodes: &[Node]) -> String {
+  let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+  let mut commands = String::new();
+  for node in nodes {
+    let mut flags = String::new();
+    let mut values = String::new();
+    for opt in &node.options {
+      let help = if opt.help.is_empty() {
+        " ".to_string()
+      } else {
+        opt.help.clone()
+      };
+      for name in opt.names() {
+        let _ = writeln!(flags, "        ,@({}, {})", quote(&name), quote(&help));
+        if let Value::Choices(choices) = &opt.value {
+          let choices = choices.iter().map(|c| quote(c)).collect::<Vec<_>>();
+          let _ = writeln!(
+            values,
+            "        {} = @({})",
+            quote(&name),
+            choices.join(", ")
+          );
+        }
+      }
+    }
+    let words = node
+      .words()
+      .iter()
+      .map(|word| quote(word))
+      .collect::<Vec<_>>()
+      .join(", ");
+    let _ = writeln!(
+      commands,
+      r#"    {} = @{{
+      Flags = @(
+{flags}      )
+      Values = @{{
+{values}      }}
+      Words = @({words})
+    }}"#,
+      quote(&node.name())
+    );
+  }
+  format!(
+    r#"Register-ArgumentCompleter -Native -CommandName spt -ScriptBlock {{
+  param($wordToComplete, $commandAst, $cursorPosition)
+  $commands = @{{
+{commands}  }}
+  $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})
+  $last = if ($wordToComplete) {{ $words.Count - 2 }} else {{ $words.Count - 1 }}
+  $path = 'spt'
+  for ($i = 1; $i -le $last; $i++) {{
+    if ($commands.ContainsKey("$path $($words[$i])")) {{
+      $path = "$path $($words[$i])"
+    }}
+  }}
+  $command = $commands[$path]
+  $previous = $words[$last]
+  if ($command.Values.ContainsKey($previous)) {{
+    $candidates = $command.Values[$previous]
+  }} elseif ($wordToComplete -like '-*') {{
+    $command.Flags | Where-Object {{ $_[0] -like "$wordToComplete*" }} | ForEach-Object {{
+      [System.Management.Automation.CompletionResult]::new($_[0], $_[0], 'ParameterName', $_[1])
+    }}
+    return
+  }} else {{
+    $candidates = $command.Words
+  }}
+  $candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+    [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+  }}
+}}
+"#
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn walks_subcommands() {
+    let nodes = nodes();
+    let node = |path: &[&str]| {
+      nodes
+        .iter()
+        .find(|node| node.path == path)
+        .unwrap_or_else(|| panic!("no node for {path:?}"))
+    };
+    let long = |node: &Node, name: &str| {
+      node
+        .options
+        .iter()
+        .any(|opt| opt.long.as_deref() == Some(name))
+    };
+    assert!(long(node(&["serve"]), "port"));
+    assert!(long(node(&["mirrors", "arch"]), "country"));
+    assert!(!long(node(&[]), "country"));
+    assert_eq!(node(&["mirrors"]).words(), ["apt", "arch", "registry"]);
+    assert_eq!(
+      node(&["completions"]).words(),
+      ["bash", "zsh", "fish", "powershell"]
+    );
+  }
+}
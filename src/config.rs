@@ -0,0 +1,452 @@
+use std::{
+  ffi::OsString,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use clap::{parser::ValueSource, ArgAction, CommandFactory, Id};
+
+use crate::Cli;
+
+/// A value of the TOML subset config files are written in.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+  String(String),
+  Integer(i64),
+  Float(f64),
+  Bool(bool),
+  Array(Vec<Value>),
+}
+
+/// Defaults for command line options, read from a TOML file like
+///
+/// ```toml
+/// concurrency = 4
+/// header = ["User-Agent: spt"]
+///
+/// [profiles.work]
+/// proxy = "http://proxy.corp:3128"
+/// ```
+///
+/// Keys are long option names, and only single-line values are supported.
+#[derive(Debug, Default)]
+pub struct Config {
+  defaults: Vec<(String, Value)>,
+  /// Named profiles, whose options replace the defaults
+  profiles: Vec<(String, Vec<(String, Value)>)>,
+}
+
+impl Config {
+  /// `$XDG_CONFIG_HOME/spt/config.toml`, else `~/.config/spt/config.toml`.
+  pub fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+      Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+      _ => {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        Path::new(&home).join(".config")
+      },
+    };
+    Some(dir.join("spt").join("config.toml"))
+  }
+
+  pub fn from_path(path: &Path) -> anyhow::Result<Config> {
+    let content = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    Config::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+  }
+
+  pub fn parse(content: &str) -> anyhow::Result<Config> {
+    let mut config = Config::default();
+    let mut profile: Option<usize> = None;
+    for (idx, line) in content.lines().enumerate() {
+      let line = strip_comment(line).trim();
+      if line.is_empty() {
+        continue;
+      }
+      let lineno = idx + 1;
+      if let Some(table) = line.strip_prefix('[') {
+        let Some(table) = table.strip_suffix(']') else {
+          bail!("Line {lineno}: unterminated table header");
+        };
+        let Some(name) = table.trim().strip_prefix("profiles.") else {
+          bail!("Line {lineno}: unknown table [{table}], expected [profiles.NAME]");
+        };
+        let name = name.trim().trim_matches('"').to_string();
+        config.profiles.push((name, Vec::new()));
+        profile = Some(config.profiles.len() - 1);
+        continue;
+      }
+
+      let Some((key, value)) = line.split_once('=') else {
+        bail!("Line {lineno}: expected key = value");
+      };
+      let key = key.trim().trim_matches('"').replace('_', "-");
+      let (value, rest) = parse_value(value.trim()).with_context(|| format!("Line {lineno}"))?;
+      if !rest.trim().is_empty() {
+        bail!("Line {lineno}: unexpected {} after the value", rest.trim());
+      }
+      match profile {
+        Some(profile) => config.profiles[profile].1.push((key, value)),
+        None => config.defaults.push((key, value)),
+      }
+    }
+    Ok(config)
+  }
+
+  /// Command line arguments for the defaults, overridden by those of `profile`.
+  /// Options are left out if `explicit` has them, unless they add to a list,
+  /// or has options conflicting with them.
+  pub fn args(&self, profile: Option<&str>, explicit: &[Id]) -> anyhow::Result<Vec<OsString>> {
+    let mut options: Vec<&(String, Value)> = self.defaults.iter().collect();
+    if let Some(name) = profile {
+      let Some((_, profile)) = self.profiles.iter().find(|(profile, _)| profile == name) else {
+        bail!("Unknown profile {name}");
+      };
+      options.retain(|(key, _)| !profile.iter().any(|(own, _)| own == key));
+      options.extend(profile);
+    }
+
+    let mut command = Cli::command();
+    command.build();
+    let mut args = Vec::new();
+    for (key, value) in options {
+      let arg = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(key.as_str()))
+        .with_context(|| format!("Unknown option {key}"))?;
+      if matches!(key.as_str(), "config" | "profile") || arg.is_positional() {
+        bail!("Option {key} can't be set in the config file");
+      }
+      let id = arg.get_id();
+      let overridden = explicit.contains(id) && !matches!(arg.get_action(), ArgAction::Append);
+      let conflicting = command
+        .get_arg_conflicts_with(arg)
+        .iter()
+        .any(|other| explicit.contains(other.get_id()))
+        || command.get_groups().any(|group| {
+          !group.clone().is_multiple()
+            && group.get_args().any(|member| member == id)
+            && group
+              .get_args()
+              .any(|member| member != id && explicit.contains(member))
+        });
+      if overridden || conflicting {
+        continue;
+      }
+      let flag = OsString::from(format!("--{key}"));
+      let num_args = arg.get_num_args().unwrap_or_default();
+      let takes_value = num_args.takes_values();
+      match value {
+        // `key = true` also passes options with an optional value without one
+        Value::Bool(true) if !takes_value || num_args.min_values() == 0 => args.push(flag),
+        Value::Bool(false) if !takes_value => {},
+        Value::Array(items) => {
+          for item in items {
+            args.push(with_value(&flag, item).with_context(|| format!("Invalid value of {key}"))?);
+          }
+        },
+        value => {
+          args.push(with_value(&flag, value).with_context(|| format!("Invalid value of {key}"))?);
+        },
+      }
+    }
+    Ok(args)
+  }
+}
+
+/// `--key=value`, which unlike two arguments also works for optional values.
+fn with_value(flag: &OsString, value: &Value) -> anyhow::Result<OsString> {
+  let mut arg = flag.clone();
+  arg.push("=");
+  arg.push(value.to_arg()?);
+  Ok(arg)
+}
+
+impl Value {
+  fn to_arg(&self) -> anyhow::Result<OsString> {
+    Ok(match self {
+      Value::String(s) => s.into(),
+      Value::Integer(num) => num.to_string().into(),
+      Value::Float(num) => num.to_string().into(),
+      Value::Bool(b) => b.to_string().into(),
+      Value::Array(_) => bail!("nested arrays are not supported"),
+    })
+  }
+}
+
+/// Command line arguments with those from the config file in front, leaving
+/// out the ones that those given explicitly override or conflict with.
+pub fn args() -> anyhow::Result<Vec<OsString>> {
+  let mut args: Vec<OsString> = std::env::args_os().collect();
+  let explicit = |name: &str| {
+    let flag = format!("--{name}");
+    let prefix = format!("--{name}=");
+    args.iter().enumerate().find_map(|(idx, arg)| {
+      let arg = arg.to_str()?;
+      if arg == flag {
+        args.get(idx + 1).cloned()
+      } else {
+        arg.strip_prefix(&prefix).map(OsString::from)
+      }
+    })
+  };
+  let profile = explicit("profile");
+  let path = match explicit("config") {
+    Some(path) => Some(PathBuf::from(path)),
+    None => Config::default_path().filter(|path| path.exists()),
+  };
+  let Some(path) = path else {
+    if let Some(profile) = profile {
+      bail!(
+        "Profile {} requested without a config file",
+        profile.to_string_lossy()
+      );
+    }
+    return Ok(args);
+  };
+
+  let config = Config::from_path(&path)?;
+  let profile = profile.map(|profile| profile.to_string_lossy().into_owned());
+  let mut turned_off = Vec::new();
+  // `--no-FLAG` turns off a flag the config file sets
+  args.retain(|arg| {
+    let negated = arg
+      .to_str()
+      .and_then(|arg| arg.strip_prefix("--no-"))
+      .and_then(|name| {
+        Cli::command()
+          .get_arguments()
+          .find(|arg| {
+            arg.get_long() == Some(name) && matches!(arg.get_action(), ArgAction::SetTrue)
+          })
+          .map(|arg| arg.get_id().clone())
+      });
+    match negated {
+      Some(id) => {
+        turned_off.push(id);
+        false
+      },
+      None => true,
+    }
+  });
+  let mut explicit = explicit_ids(&args);
+  explicit.extend(turned_off);
+  let from_config = config
+    .args(profile.as_deref(), &explicit)
+    .with_context(|| format!("Invalid config file {}", path.display()))?;
+  args.splice(1..1, from_config);
+  Ok(args)
+}
+
+/// Ids of the arguments given on the command line, before the subcommand.
+fn explicit_ids(args: &[OsString]) -> Vec<Id> {
+  // Errors like missing URLs are reported once the config is applied too
+  let Ok(matches) = Cli::command()
+    .ignore_errors(true)
+    .try_get_matches_from(args)
+  else {
+    return Vec::new();
+  };
+  matches
+    .ids()
+    .filter(|id| {
+      matches!(
+        matches.value_source(id.as_str()),
+        Some(ValueSource::CommandLine)
+      )
+    })
+    .cloned()
+    .collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+  let mut quote = None;
+  let mut escaped = false;
+  for (idx, ch) in line.char_indices() {
+    match (quote, ch) {
+      (Some('"'), '\\') if !escaped => {
+        escaped = true;
+        continue;
+      },
+      (Some(q), ch) if ch == q && !escaped => quote = None,
+      (None, '"' | '\'') => quote = Some(ch),
+      (None, '#') => return &line[..idx],
+      _ => {},
+    }
+    escaped = false;
+  }
+  line
+}
+
+/// Parses a value at the start of `s`, returning it and the rest.
+fn parse_value(s: &str) -> anyhow::Result<(Value, &str)> {
+  if let Some(rest) = s.strip_prefix('"') {
+    let mut value = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((idx, ch)) = chars.next() {
+      match ch {
+        '"' => return Ok((Value::String(value), &rest[idx + 1..])),
+        '\\' => match chars.next().map(|(_, ch)| ch) {
+          Some('n') => value.push('\n'),
+          Some('t') => value.push('\t'),
+          Some('r') => value.push('\r'),
+          Some('"') => value.push('"'),
+          Some('\\') => value.push('\\'),
+          Some(ch) => bail!("unsupported escape \\{ch}"),
+          None => break,
+        },
+        ch => value.push(ch),
+      }
+    }
+    bail!("unterminated string");
+  }
+  if let Some(rest) = s.strip_prefix('\'') {
+    let (value, rest) = rest.split_once('\'').context("unterminated string")?;
+    return Ok((Value::String(value.to_string()), rest));
+  }
+  if let Some(mut rest) = s.strip_prefix('[') {
+    let mut items = Vec::new();
+    loop {
+      rest = rest.trim_start();
+      if let Some(rest) = rest.strip_prefix(']') {
+        return Ok((Value::Array(items), rest));
+      }
+      let (item, after) = parse_value(rest)?;
+      items.push(item);
+      rest = after.trim_start();
+      match rest.strip_prefix(',') {
+        Some(after) => rest = after,
+        None if rest.starts_with(']') => {},
+        None => bail!("expected , or ] in array"),
+      }
+    }
+  }
+
+  let end = s.find([',', ']']).unwrap_or(s.len());
+  let (token, rest) = (s[..end].trim(), &s[end..]);
+  let value = match token {
+    "true" => Value::Bool(true),
+    "false" => Value::Bool(false),
+    token => {
+      let number = token.replace('_', "");
+      if let Ok(num) = number.parse() {
+        Value::Integer(num)
+      } else if let Ok(num) = number.parse() {
+        Value::Float(num)
+      } else {
+        bail!("invalid value {token}, strings must be quoted");
+      }
+    },
+  };
+  Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(config: &str, explicit: &[&'static str]) -> Vec<OsString> {
+    let explicit: Vec<Id> = explicit.iter().map(|id| Id::from(*id)).collect();
+    Config::parse(config)
+      .unwrap()
+      .args(None, &explicit)
+      .unwrap()
+  }
+
+  #[test]
+  fn parses_values() {
+    let config = Config::parse(
+      r#"
+      # comment
+      concurrency = 4
+      max_bytes = 1_000 # trailing comment
+      abort-below = 1.5
+      insecure = true
+      header = ["A: 1", 'B: #2']
+      proxy = "http://a\"b\\c"
+
+      [profiles."work"]
+      ipv4 = false
+      "#,
+    )
+    .unwrap();
+    assert_eq!(
+      config.defaults,
+      [
+        ("concurrency".to_string(), Value::Integer(4)),
+        ("max-bytes".to_string(), Value::Integer(1000)),
+        ("abort-below".to_string(), Value::Float(1.5)),
+        ("insecure".to_string(), Value::Bool(true)),
+        (
+          "header".to_string(),
+          Value::Array(vec![
+            Value::String("A: 1".to_string()),
+            Value::String("B: #2".to_string()),
+          ])
+        ),
+        (
+          "proxy".to_string(),
+          Value::String(r#"http://a"b\c"#.to_string())
+        ),
+      ]
+    );
+    assert_eq!(
+      config.profiles,
+      [(
+        "work".to_string(),
+        vec![("ipv4".to_string(), Value::Bool(false))]
+      )]
+    );
+  }
+
+  #[test]
+  fn rejects_invalid() {
+    for config in [
+      "concurrency",
+      "proxy = http://a",
+      "proxy = \"open",
+      "header = [\"a\" \"b\"]",
+      "[other]",
+      "[profiles.a",
+      "concurrency = 4 5",
+    ] {
+      assert!(Config::parse(config).is_err(), "{config}");
+    }
+  }
+
+  #[test]
+  fn profiles_replace_defaults() {
+    let config = Config::parse("concurrency = 4\n[profiles.a]\nconcurrency = 8\n").unwrap();
+    assert_eq!(config.args(Some("a"), &[]).unwrap(), ["--concurrency=8"]);
+    assert!(config.args(Some("b"), &[]).is_err());
+  }
+
+  #[test]
+  fn finds_explicit_args() {
+    let args: Vec<OsString> = ["spt", "-6", "-j", "2", "http://a/x"]
+      .iter()
+      .map(OsString::from)
+      .collect();
+    let explicit = explicit_ids(&args);
+    for id in ["ipv6", "concurrency", "urls"] {
+      assert!(explicit.contains(&Id::from(id)), "{id}");
+    }
+    assert!(!explicit.contains(&Id::from("ipv4")));
+  }
+
+  #[test]
+  fn command_line_wins() {
+    assert_eq!(args("ipv4 = true", &[]), ["--ipv4"]);
+    assert!(args("ipv4 = true", &["ipv6"]).is_empty());
+    assert!(args("ipv4 = true", &["ipv4"]).is_empty());
+    assert!(args("file = \"urls.txt\"", &["urls"]).is_empty());
+    assert_eq!(
+      args(
+        "concurrency = 4\nheader = [\"A: 1\"]",
+        &["concurrency", "header"]
+      ),
+      ["--header=A: 1"]
+    );
+  }
+}
@@ -0,0 +1,182 @@
+use std::{
+  fmt,
+  future::Future,
+  io,
+  pin::Pin,
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+
+use hyper::{
+  rt::{Read, ReadBufCursor, Write},
+  Uri,
+};
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use rustls::{CipherSuite, ProtocolVersion};
+use tower_service::Service;
+
+use crate::proxy::{ProxyConnector, ProxyStream};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Negotiated TLS parameters of a connection, available in response extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsInfo {
+  pub version: Option<ProtocolVersion>,
+  pub cipher: Option<CipherSuite>,
+}
+
+impl fmt::Display for TlsInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.version {
+      Some(ProtocolVersion::TLSv1_2) => f.write_str("TLS 1.2")?,
+      Some(ProtocolVersion::TLSv1_3) => f.write_str("TLS 1.3")?,
+      Some(version) => write!(f, "{version:?}")?,
+      None => f.write_str("TLS")?,
+    }
+    if let Some(cipher) = self.cipher {
+      write!(f, " {cipher:?}")?;
+    }
+    Ok(())
+  }
+}
+
+/// Durations of the phases of establishing a connection, available in response extensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectTimings {
+  /// When the connection was ready, telling new connections from reused ones
+  pub established: Instant,
+  pub dns: Option<Duration>,
+  pub connect: Duration,
+  pub tls: Option<Duration>,
+}
+
+/// An HTTPS connector which records details about each established connection.
+#[derive(Clone)]
+pub struct Connector {
+  inner: HttpsConnector<ProxyConnector>,
+  /// Fail TLS connections on which the server didn't pick HTTP/2
+  require_http2: bool,
+}
+
+impl Connector {
+  pub fn new(inner: HttpsConnector<ProxyConnector>) -> Connector {
+    Connector {
+      inner,
+      require_http2: false,
+    }
+  }
+
+  /// Fails TLS connections whose server didn't agree to HTTP/2 during the
+  /// handshake, instead of falling back to HTTP/1.1 on them.
+  pub fn require_http2(self) -> Connector {
+    Connector {
+      require_http2: true,
+      ..self
+    }
+  }
+}
+
+impl Service<Uri> for Connector {
+  type Response = Stream;
+  type Error = BoxError;
+  type Future = Pin<Box<dyn Future<Output = Result<Stream, BoxError>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, dst: Uri) -> Self::Future {
+    let host = dst.host().unwrap_or_default().to_string();
+    let require_http2 = self.require_http2;
+    let connecting = self.inner.call(dst);
+    let start = Instant::now();
+    Box::pin(async move {
+      let inner = connecting.await?;
+      let elapsed = start.elapsed();
+      let (tcp, tls) = match &inner {
+        MaybeHttpsStream::Https(stream) => {
+          let (tcp, conn) = stream.inner().get_ref();
+          if require_http2 && conn.alpn_protocol() != Some(b"h2") {
+            return Err(format!("{host} did not agree to HTTP/2 during the TLS handshake").into());
+          }
+          let tls = TlsInfo {
+            version: conn.protocol_version(),
+            cipher: conn.negotiated_cipher_suite().map(|suite| suite.suite()),
+          };
+          (tcp.inner(), Some(tls))
+        },
+        MaybeHttpsStream::Http(tcp) => (tcp, None),
+      };
+      let tcp_elapsed = tcp.dns.unwrap_or_default() + tcp.connect;
+      let timings = ConnectTimings {
+        established: Instant::now(),
+        dns: tcp.dns,
+        connect: tcp.connect,
+        tls: tls.map(|_| elapsed.saturating_sub(tcp_elapsed)),
+      };
+      Ok(Stream {
+        inner,
+        tls,
+        timings,
+      })
+    })
+  }
+}
+
+pub struct Stream {
+  inner: MaybeHttpsStream<ProxyStream>,
+  tls: Option<TlsInfo>,
+  timings: ConnectTimings,
+}
+
+impl Connection for Stream {
+  fn connected(&self) -> Connected {
+    let connected = self.inner.connected().extra(self.timings);
+    match self.tls {
+      Some(tls) => connected.extra(tls),
+      None => connected,
+    }
+  }
+}
+
+impl Read for Stream {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: ReadBufCursor<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl Write for Stream {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    self.inner.is_write_vectored()
+  }
+
+  fn poll_write_vectored(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    bufs: &[io::IoSlice<'_>],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+  }
+}
@@ -0,0 +1,228 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::{parse_seconds, parse_size, TestResult};
+
+/// Conditions a result has to meet to pass, like `status=2xx,speed>=1M` or
+/// `latency<=200ms`.
+#[derive(Debug, Clone, Default)]
+pub struct Criteria(Vec<Criterion>);
+
+#[derive(Debug, Clone)]
+enum Criterion {
+  /// Status codes, `2xx` style classes or exact codes, separated by `|`
+  Status(Vec<StatusPattern>),
+  Speed(Comparison, u64),
+  /// Time to the response headers, see [`TestResult::response_time`]
+  Latency(Comparison, Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StatusPattern {
+  Class(u16),
+  Exact(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+  Eq,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+impl Comparison {
+  fn holds<T: PartialOrd>(self, actual: T, expected: T) -> bool {
+    match self {
+      Comparison::Eq => actual == expected,
+      Comparison::Lt => actual < expected,
+      Comparison::Le => actual <= expected,
+      Comparison::Gt => actual > expected,
+      Comparison::Ge => actual >= expected,
+    }
+  }
+}
+
+impl Criteria {
+  /// Whether the result meets every criterion, a failed test never does.
+  pub fn passes(&self, data: &TestResult) -> bool {
+    self.0.iter().all(|criterion| match criterion {
+      Criterion::Status(patterns) => data.status.is_some_and(|status| {
+        let status = status.as_u16();
+        patterns.iter().any(|pattern| match *pattern {
+          StatusPattern::Class(class) => status / 100 == class,
+          StatusPattern::Exact(code) => status == code,
+        })
+      }),
+      Criterion::Speed(comparison, expected) => data
+        .speed
+        .is_some_and(|speed| comparison.holds(speed, *expected)),
+      Criterion::Latency(comparison, expected) => data
+        .response_time()
+        .is_some_and(|latency| comparison.holds(latency, *expected)),
+    })
+  }
+
+  /// Adds `speed>=SPEED`.
+  pub fn min_speed(mut self, speed: u64) -> Criteria {
+    self.0.push(Criterion::Speed(Comparison::Ge, speed));
+    self
+  }
+
+  /// Adds `latency<=LATENCY`.
+  pub fn max_latency(mut self, latency: Duration) -> Criteria {
+    self.0.push(Criterion::Latency(Comparison::Le, latency));
+    self
+  }
+}
+
+impl FromStr for Criteria {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    s.split(',')
+      .map(str::trim)
+      .filter(|criterion| !criterion.is_empty())
+      .map(parse_criterion)
+      .collect::<Result<Vec<_>, _>>()
+      .map(Criteria)
+  }
+}
+
+fn parse_criterion(s: &str) -> Result<Criterion, String> {
+  let split = s
+    .find(['=', '<', '>'])
+    .ok_or_else(|| format!("expected <KEY><OP><VALUE>, got {s}"))?;
+  let (key, rest) = s.split_at(split);
+  let (comparison, value) = [
+    (">=", Comparison::Ge),
+    ("<=", Comparison::Le),
+    ("=", Comparison::Eq),
+    (">", Comparison::Gt),
+    ("<", Comparison::Lt),
+  ]
+  .into_iter()
+  .find_map(|(op, comparison)| Some((comparison, rest.strip_prefix(op)?)))
+  .ok_or_else(|| format!("invalid comparison in {s}"))?;
+
+  match key.trim() {
+    "status" => {
+      if !matches!(comparison, Comparison::Eq) {
+        return Err(format!("status only supports `=`, got {s}"));
+      }
+      value
+        .split('|')
+        .map(parse_status)
+        .collect::<Result<_, _>>()
+        .map(Criterion::Status)
+    },
+    "speed" => Ok(Criterion::Speed(
+      comparison,
+      parse_size(value.strip_suffix("/s").unwrap_or(value))?,
+    )),
+    "latency" => Ok(Criterion::Latency(comparison, parse_seconds(value.trim())?)),
+    key => Err(format!(
+      "unknown criterion `{key}`, expected status, speed or latency"
+    )),
+  }
+}
+
+fn parse_status(s: &str) -> Result<StatusPattern, String> {
+  let s = s.trim();
+  let invalid = || format!("invalid status {s}, expected e.g. 200 or 2xx");
+  match s.to_ascii_lowercase().strip_suffix("xx") {
+    Some(class) => match class.parse() {
+      Ok(class @ 1..=5) => Ok(StatusPattern::Class(class)),
+      _ => Err(invalid()),
+    },
+    None => match s.parse() {
+      Ok(code @ 100..=599) => Ok(StatusPattern::Exact(code)),
+      _ => Err(invalid()),
+    },
+  }
+}
+
+/// Pass or fail of a result against [`Criteria`].
+pub struct Verdict(pub bool);
+
+impl fmt::Display for Verdict {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(if self.0 { "PASS" } else { "FAIL" })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::StatusCode;
+
+  use super::*;
+
+  fn result(status: u16, speed: Option<u64>, latency: Option<Duration>) -> TestResult {
+    TestResult {
+      status: Some(StatusCode::from_u16(status).unwrap()),
+      speed,
+      latency,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn parses_criteria() {
+    let criteria: Criteria = "status=2xx|304, speed>=1M/s,latency<200ms,"
+      .parse()
+      .unwrap();
+    assert!(criteria.passes(&result(
+      200,
+      Some(2 << 20),
+      Some(Duration::from_millis(100))
+    )));
+    assert!(criteria.passes(&result(
+      304,
+      Some(1 << 20),
+      Some(Duration::from_millis(100))
+    )));
+    assert!(!criteria.passes(&result(
+      404,
+      Some(2 << 20),
+      Some(Duration::from_millis(100))
+    )));
+    assert!(!criteria.passes(&result(
+      200,
+      Some(1 << 19),
+      Some(Duration::from_millis(100))
+    )));
+    assert!(!criteria.passes(&result(
+      200,
+      Some(2 << 20),
+      Some(Duration::from_millis(200))
+    )));
+    // A failed test passes no criterion
+    assert!(!criteria.passes(&result(200, None, Some(Duration::from_millis(100)))));
+    assert!(Criteria::default().passes(&TestResult::default()));
+  }
+
+  #[test]
+  fn builds_criteria() {
+    let criteria = Criteria::default()
+      .min_speed(1000)
+      .max_latency(Duration::from_secs(1));
+    assert!(criteria.passes(&result(200, Some(1000), Some(Duration::from_secs(1)))));
+    assert!(!criteria.passes(&result(200, Some(999), Some(Duration::from_secs(1)))));
+  }
+
+  #[test]
+  fn rejects_invalid_criteria() {
+    for criteria in [
+      "status",
+      "status>=200",
+      "status=6xx",
+      "status=99",
+      "speed=>1M",
+      "speed>=fast",
+      "latency<=soon",
+      "size>=1M",
+    ] {
+      assert!(criteria.parse::<Criteria>().is_err(), "{criteria}");
+    }
+  }
+}
@@ -0,0 +1,586 @@
+use std::{
+  future::Future,
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  pin::Pin,
+  str::FromStr,
+  sync::Arc,
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, header, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+  client::legacy::{
+    connect::{
+      dns::{GaiResolver, Name},
+      HttpConnector,
+    },
+    Client,
+  },
+  rt::TokioExecutor,
+};
+use parking_lot::Mutex;
+use rustls::ClientConfig;
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpStream, UdpSocket},
+};
+use tower_service::Service;
+
+const RECORD_A: u16 = 1;
+const RECORD_AAAA: u16 = 28;
+/// Largest DNS message over UDP without EDNS.
+const MAX_UDP_MESSAGE: usize = 512;
+/// Time to wait for a UDP answer before asking again.
+const UDP_RETRY: Duration = Duration::from_secs(2);
+const UDP_ATTEMPTS: u32 = 3;
+/// Time to wait for an answer over TCP, asked for when the UDP one was truncated.
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default)]
+pub struct ResolverOptions {
+  /// Fail the resolution if it takes longer than this
+  pub timeout: Option<Duration>,
+  /// Only use addresses of this family
+  pub family: Option<AddressFamily>,
+  /// Ask this server instead of the system resolver
+  pub nameserver: Option<Nameserver>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+  V4,
+  V6,
+}
+
+impl AddressFamily {
+  fn matches(self, addr: &SocketAddr) -> bool {
+    match self {
+      AddressFamily::V4 => addr.is_ipv4(),
+      AddressFamily::V6 => addr.is_ipv6(),
+    }
+  }
+}
+
+/// A DNS server queried directly, bypassing the system resolver.
+#[derive(Debug, Clone)]
+pub enum Nameserver {
+  /// Plain DNS over UDP, falling back to TCP for truncated answers
+  Udp(SocketAddr),
+  /// DNS over HTTPS (RFC 8484), the host of the URL being resolved by the system
+  Https(Uri),
+}
+
+impl Nameserver {
+  /// Parses a UDP server like `1.1.1.1`, `9.9.9.9:53` or `[2606:4700::1111]:53`.
+  pub fn parse_udp(s: &str) -> Result<Nameserver, String> {
+    if let Ok(ip) = IpAddr::from_str(s) {
+      return Ok(Nameserver::Udp(SocketAddr::new(ip, 53)));
+    }
+    SocketAddr::from_str(s)
+      .map(Nameserver::Udp)
+      .map_err(|_| format!("Invalid DNS server {s}, expected an IP address and optional port"))
+  }
+
+  /// Parses a DNS over HTTPS URL like `https://cloudflare-dns.com/dns-query`.
+  pub fn parse_https(s: &str) -> Result<Nameserver, String> {
+    let uri = Uri::from_str(s).map_err(|err| format!("Invalid DoH URL {s}: {err}"))?;
+    if uri.scheme_str() != Some("https") || uri.host().is_none() {
+      return Err(format!("Invalid DoH URL {s}, expected an https:// URL"));
+    }
+    Ok(Nameserver::Https(uri))
+  }
+}
+
+type DohClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// Addresses to use for a host and port instead of resolving it, like curl's `--resolve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolveOverride {
+  pub host: String,
+  /// `None` for every port
+  pub port: Option<u16>,
+  pub addrs: Vec<IpAddr>,
+}
+
+impl ResolveOverride {
+  /// Parses `host:port:addr[,addr]...`, where the port may be `*` and IPv6
+  /// addresses may be in brackets.
+  pub fn parse(s: &str) -> Result<ResolveOverride, String> {
+    let invalid = || format!("Invalid --resolve {s}, expected HOST:PORT:ADDR[,ADDR]...");
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next()) else {
+      return Err(invalid());
+    };
+    let port = match port {
+      "*" => None,
+      port => Some(port.parse().map_err(|_| invalid())?),
+    };
+    let addrs = addrs
+      .split(',')
+      .map(|addr| {
+        let addr = addr.trim().trim_start_matches('[').trim_end_matches(']');
+        IpAddr::from_str(addr).map_err(|_| format!("Invalid address {addr} in --resolve {s}"))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    if host.is_empty() {
+      return Err(invalid());
+    }
+    Ok(ResolveOverride {
+      host: host.to_ascii_lowercase(),
+      port,
+      addrs,
+    })
+  }
+
+  pub fn matches(&self, host: &str, port: u16) -> bool {
+    self.host.eq_ignore_ascii_case(host) && self.port.is_none_or(|own| own == port)
+  }
+}
+
+#[derive(Clone)]
+enum Backend {
+  System(GaiResolver),
+  Nameserver(Transport),
+  /// Fixed addresses from a [`ResolveOverride`]
+  Pinned(Vec<IpAddr>),
+}
+
+/// How queries reach a [`Nameserver`].
+#[derive(Clone)]
+enum Transport {
+  Udp(SocketAddr),
+  Https { uri: Uri, client: Arc<DohClient> },
+}
+
+/// Resolves host names for the connector, with the system resolver or a
+/// [`Nameserver`].
+#[derive(Clone)]
+pub struct Resolver {
+  backend: Backend,
+  options: ResolverOptions,
+  /// How long the last resolution took, shared between clones
+  elapsed: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Resolver {
+  /// Creates a resolver, `tls_config` being used to connect to DoH servers.
+  pub fn new(options: ResolverOptions, tls_config: ClientConfig) -> Resolver {
+    let backend = match &options.nameserver {
+      None => Backend::System(GaiResolver::new()),
+      Some(Nameserver::Udp(addr)) => Backend::Nameserver(Transport::Udp(*addr)),
+      Some(Nameserver::Https(uri)) => {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+          .with_tls_config(tls_config)
+          .https_only()
+          .enable_http1()
+          .enable_http2()
+          .build();
+        Backend::Nameserver(Transport::Https {
+          uri: uri.clone(),
+          client: Arc::new(Client::builder(TokioExecutor::new()).build(https)),
+        })
+      },
+    };
+    Resolver {
+      backend,
+      options,
+      elapsed: Default::default(),
+    }
+  }
+
+  /// A copy of this resolver keeping track of its own resolution time, for
+  /// timing a single connection.
+  pub fn recording(&self) -> Resolver {
+    Resolver {
+      backend: self.backend.clone(),
+      options: self.options.clone(),
+      elapsed: Default::default(),
+    }
+  }
+
+  /// A copy of this resolver returning `addrs` for every host name.
+  pub fn pinned(&self, addrs: Vec<IpAddr>) -> Resolver {
+    Resolver {
+      backend: Backend::Pinned(addrs),
+      options: self.options.clone(),
+      elapsed: Default::default(),
+    }
+  }
+
+  /// Time the last successful resolution took, `None` if nothing was resolved.
+  pub fn elapsed(&self) -> Option<Duration> {
+    *self.elapsed.lock()
+  }
+}
+
+impl Service<Name> for Resolver {
+  type Response = std::vec::IntoIter<SocketAddr>;
+  type Error = io::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, io::Error>> + Send>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, name: Name) -> Self::Future {
+    let backend = self.backend.clone();
+    let options = self.options.clone();
+    let elapsed = self.elapsed.clone();
+    Box::pin(async move {
+      let host = name.to_string();
+      let start = Instant::now();
+      let resolving = lookup(backend, name, options.family);
+      let addrs = match options.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, resolving)
+          .await
+          .map_err(|_| {
+            io::Error::new(
+              io::ErrorKind::TimedOut,
+              format!("resolving {host} timed out after {timeout:?}"),
+            )
+          })??,
+        None => resolving.await?,
+      };
+      *elapsed.lock() = Some(start.elapsed());
+      let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| options.family.is_none_or(|family| family.matches(addr)))
+        .collect();
+      if addrs.is_empty() {
+        let family = match options.family {
+          Some(AddressFamily::V4) => "IPv4 ",
+          Some(AddressFamily::V6) => "IPv6 ",
+          None => "",
+        };
+        return Err(io::Error::new(
+          io::ErrorKind::NotFound,
+          format!("{host} has no {family}addresses"),
+        ));
+      }
+      Ok(addrs.into_iter())
+    })
+  }
+}
+
+async fn lookup(
+  backend: Backend,
+  name: Name,
+  family: Option<AddressFamily>,
+) -> io::Result<Vec<SocketAddr>> {
+  let transport = match backend {
+    Backend::System(mut gai) => return Ok(gai.call(name).await?.collect()),
+    Backend::Pinned(addrs) => {
+      return Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect())
+    },
+    Backend::Nameserver(transport) => transport,
+  };
+  let record_types: &[u16] = match family {
+    Some(AddressFamily::V4) => &[RECORD_A],
+    Some(AddressFamily::V6) => &[RECORD_AAAA],
+    None => &[RECORD_A, RECORD_AAAA],
+  };
+  let mut addrs = Vec::new();
+  for record_type in record_types {
+    let query = encode_query(name.as_str(), *record_type)?;
+    let answer = match &transport {
+      Transport::Udp(server) => {
+        let answer = query_udp(*server, &query).await?;
+        // Answers too large for UDP are truncated, asking over TCP gets all of it
+        match is_truncated(&answer) {
+          true => query_tcp(*server, &query).await?,
+          false => answer,
+        }
+      },
+      Transport::Https { uri, client } => query_https(client, uri, &query).await?,
+    };
+    addrs.extend(
+      decode_answer(&answer, query_id(&query))?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, 0)),
+    );
+  }
+  Ok(addrs)
+}
+
+async fn query_udp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+  let bind: SocketAddr = match server {
+    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+  };
+  let socket = UdpSocket::bind(bind).await?;
+  socket.connect(server).await?;
+  let mut buf = [0; MAX_UDP_MESSAGE];
+  for _ in 0..UDP_ATTEMPTS {
+    socket.send(query).await?;
+    // Answers to earlier attempts may still arrive, so look for a matching ID
+    let answer = tokio::time::timeout(UDP_RETRY, async {
+      loop {
+        let len = socket.recv(&mut buf).await?;
+        if len >= 2 && buf[..2] == query[..2] {
+          return io::Result::Ok(buf[..len].to_vec());
+        }
+      }
+    });
+    if let Ok(answer) = answer.await {
+      return answer;
+    }
+  }
+  Err(io::Error::new(
+    io::ErrorKind::TimedOut,
+    format!("DNS server {server} did not answer"),
+  ))
+}
+
+/// Asks over TCP, where messages are prefixed with their length (RFC 1035 section 4.2.2).
+async fn query_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+  let exchange = async {
+    let mut stream = TcpStream::connect(server).await?;
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0; usize::from(len)];
+    stream.read_exact(&mut answer).await?;
+    io::Result::Ok(answer)
+  };
+  tokio::time::timeout(TCP_TIMEOUT, exchange)
+    .await
+    .map_err(|_| {
+      io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("DNS server {server} did not answer over TCP"),
+      )
+    })?
+}
+
+async fn query_https(client: &DohClient, uri: &Uri, query: &[u8]) -> io::Result<Vec<u8>> {
+  let other = |err: String| io::Error::other(err);
+  let request = Request::builder()
+    .method(Method::POST)
+    .uri(uri.clone())
+    .header(header::CONTENT_TYPE, "application/dns-message")
+    .header(header::ACCEPT, "application/dns-message")
+    .body(Full::new(Bytes::copy_from_slice(query)))
+    .map_err(|err| other(format!("Invalid DoH request: {err}")))?;
+  let resp = client
+    .request(request)
+    .await
+    .map_err(|err| other(format!("DoH request to {uri} failed: {err}")))?;
+  if !resp.status().is_success() {
+    return Err(other(format!(
+      "DoH server {uri} responded with {}",
+      resp.status()
+    )));
+  }
+  let body = resp
+    .into_body()
+    .collect()
+    .await
+    .map_err(|err| other(format!("Failed to read DoH response from {uri}: {err}")))?;
+  Ok(body.to_bytes().to_vec())
+}
+
+fn query_id(query: &[u8]) -> u16 {
+  u16::from_be_bytes([query[0], query[1]])
+}
+
+/// Whether the TC flag of a response is set.
+fn is_truncated(message: &[u8]) -> bool {
+  message.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+/// Builds a recursive query for records of `record_type` (RFC 1035 section 4).
+fn encode_query(host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+  let id = crate::random_u64() as u16;
+  let mut query = Vec::with_capacity(host.len() + 18);
+  query.extend_from_slice(&id.to_be_bytes());
+  // Recursion desired, one question
+  query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+  for label in host.trim_end_matches('.').split('.') {
+    if label.is_empty() || label.len() > 63 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid host name {host}"),
+      ));
+    }
+    query.push(label.len() as u8);
+    query.extend_from_slice(label.as_bytes());
+  }
+  query.push(0);
+  query.extend_from_slice(&record_type.to_be_bytes());
+  // Class IN
+  query.extend_from_slice(&[0, 1]);
+  Ok(query)
+}
+
+/// Collects the A and AAAA records from the answer section of a response.
+fn decode_answer(message: &[u8], id: u16) -> io::Result<Vec<IpAddr>> {
+  let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid DNS response");
+  let u16_at = |pos: usize| -> io::Result<u16> {
+    let bytes = message.get(pos..pos + 2).ok_or_else(invalid)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+  };
+  if u16_at(0)? != id {
+    return Err(invalid());
+  }
+  let flags = u16_at(2)?;
+  if flags & 0x0200 != 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "DNS response was truncated",
+    ));
+  }
+  match flags & 0x000f {
+    0 => {},
+    3 => return Ok(Vec::new()),
+    rcode => {
+      return Err(io::Error::other(format!(
+        "DNS server failed with response code {rcode}"
+      )))
+    },
+  }
+  let questions = u16_at(4)?;
+  let answers = u16_at(6)?;
+
+  let mut pos = 12;
+  for _ in 0..questions {
+    pos = skip_name(message, pos).ok_or_else(invalid)? + 4;
+  }
+  let mut addrs = Vec::new();
+  for _ in 0..answers {
+    pos = skip_name(message, pos).ok_or_else(invalid)?;
+    let record_type = u16_at(pos)?;
+    let len = usize::from(u16_at(pos + 8)?);
+    let data = message.get(pos + 10..pos + 10 + len).ok_or_else(invalid)?;
+    match (record_type, data.len()) {
+      (RECORD_A, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+      (RECORD_AAAA, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+      // CNAMEs are followed by the server, which returns their records as well
+      _ => {},
+    }
+    pos += 10 + len;
+  }
+  Ok(addrs)
+}
+
+/// Position after the possibly compressed name at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+  loop {
+    let len = *message.get(pos)?;
+    match len {
+      0 => return Some(pos + 1),
+      // A pointer ends the name, and isn't followed so it can't loop
+      len if len & 0xc0 == 0xc0 => {
+        message.get(pos + 1)?;
+        return Some(pos + 2);
+      },
+      // The other label types are reserved
+      len if len & 0xc0 != 0 => return None,
+      len => pos += 1 + usize::from(len),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  /// A response to `query` with its question and an A record for each of `ips`.
+  fn response(query: &[u8], flags: u16, ips: &[[u8; 4]]) -> Vec<u8> {
+    let mut message = query[..2].to_vec();
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&[0, 1]);
+    message.extend_from_slice(&(ips.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    message.extend_from_slice(&query[12..]);
+    for ip in ips {
+      // Pointer to the name of the question, type A, class IN, TTL 60
+      message.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+      message.extend_from_slice(ip);
+    }
+    message
+  }
+
+  #[test]
+  fn encodes_queries() {
+    let query = encode_query("example.com.", RECORD_AAAA).unwrap();
+    assert_eq!(&query[2..12], &[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x1c\x00\x01");
+    assert!(encode_query("a..b", RECORD_A).is_err());
+    assert!(encode_query(&"a".repeat(64), RECORD_A).is_err());
+  }
+
+  #[test]
+  fn decodes_answers() {
+    let query = encode_query("example.com", RECORD_A).unwrap();
+    let id = query_id(&query);
+    let answer = response(&query, 0x8180, &[[192, 0, 2, 1], [192, 0, 2, 2]]);
+    assert_eq!(
+      decode_answer(&answer, id).unwrap(),
+      [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+    );
+    // NXDOMAIN has no addresses
+    assert!(decode_answer(&response(&query, 0x8183, &[]), id)
+      .unwrap()
+      .is_empty());
+    assert!(decode_answer(&response(&query, 0x8182, &[]), id).is_err());
+    assert!(decode_answer(&response(&query, 0x8380, &[]), id).is_err());
+    assert!(decode_answer(&answer, id.wrapping_add(1)).is_err());
+    // Records cut off anywhere are invalid rather than panicking
+    for len in 0..answer.len() {
+      assert!(decode_answer(&answer[..len], id).is_err(), "{len}");
+    }
+  }
+
+  #[test]
+  fn skips_names() {
+    let message = b"\x07example\x03com\x00\x03www\xc0\x00\xc0\x0d";
+    assert_eq!(skip_name(message, 0), Some(13));
+    assert_eq!(skip_name(message, 13), Some(19));
+    // A pointer to itself ends the name without being followed
+    assert_eq!(skip_name(message, 19), Some(21));
+    assert_eq!(skip_name(&message[..20], 19), None);
+    assert_eq!(skip_name(b"\x07exam", 0), None);
+    assert_eq!(skip_name(b"\x40abc", 0), None);
+  }
+
+  #[tokio::test]
+  async fn retries_truncated_answers_over_tcp() {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = udp.local_addr().unwrap();
+    let tcp = TcpListener::bind(server).await.unwrap();
+    tokio::spawn(async move {
+      let mut buf = [0; 512];
+      let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+      let truncated = response(&buf[..len], 0x8380, &[]);
+      udp.send_to(&truncated, peer).await.unwrap();
+    });
+    tokio::spawn(async move {
+      let (mut stream, _) = tcp.accept().await.unwrap();
+      let len = stream.read_u16().await.unwrap();
+      let mut query = vec![0; usize::from(len)];
+      stream.read_exact(&mut query).await.unwrap();
+      let answer = response(&query, 0x8180, &[[192, 0, 2, 1]]);
+      stream
+        .write_all(&(answer.len() as u16).to_be_bytes())
+        .await
+        .unwrap();
+      stream.write_all(&answer).await.unwrap();
+    });
+    let addrs = lookup(
+      Backend::Nameserver(Transport::Udp(server)),
+      Name::from_str("example.com").unwrap(),
+      Some(AddressFamily::V4),
+    )
+    .await
+    .unwrap();
+    assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 1], 0))]);
+  }
+}
@@ -0,0 +1,68 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::{fetch_text, json::Json, TlsHyper, FETCH_TIMEOUT};
+
+const FAST: &str = "https://fast.com";
+const API: &str = "https://api.fast.com/netflix/speedtest/v2";
+
+/// Servers to get from the API, as many as fast.com downloads from at once.
+pub const URL_COUNT: u16 = 5;
+
+/// Bytes downloaded from each server, what fast.com requests.
+const DOWNLOAD_SIZE: u64 = 25 * 1024 * 1024;
+
+/// URLs of Netflix Open Connect Appliances near this machine, with the token
+/// the fast.com app embeds to call the API.
+pub async fn urls(client: &TlsHyper) -> anyhow::Result<Vec<String>> {
+  let html = fetch_text(client, FAST, FETCH_TIMEOUT).await?;
+  let script = script_path(&html).context("No app script on fast.com")?;
+  let script = fetch_text(client, &format!("{FAST}{script}"), FETCH_TIMEOUT).await?;
+  let token = parse_token(&script).context("No API token in the fast.com app")?;
+  let api = format!("{API}?https=true&token={token}&urlCount={URL_COUNT}");
+  parse_targets(&fetch_text(client, &api, FETCH_TIMEOUT).await?)
+}
+
+/// Path of the app script in the page of fast.com, like `/app-ed402d.js`.
+fn script_path(html: &str) -> Option<&str> {
+  html.split("src=\"").skip(1).find_map(|rest| {
+    let (path, _) = rest.split_once('"')?;
+    (path.starts_with("/app-") && path.ends_with(".js")).then_some(path)
+  })
+}
+
+/// The token of a `token:"..."` property of the app script.
+fn parse_token(script: &str) -> Option<&str> {
+  let (_, rest) = script.split_once("token:\"")?;
+  let (token, _) = rest.split_once('"')?;
+  (!token.is_empty()).then_some(token)
+}
+
+/// Download URLs of the targets of an API response like
+/// `{"targets": [{"url": "https://ipv4-c001-...nflxvideo.net/speedtest?c=..."}]}`,
+/// asking for a range of the size fast.com downloads.
+fn parse_targets(content: &str) -> anyhow::Result<Vec<String>> {
+  let response = Json::from_str(content)
+    .map_err(anyhow::Error::msg)
+    .context("Invalid fast.com API response")?;
+  let targets = response
+    .get("targets")
+    .and_then(Json::as_array)
+    .context("Invalid fast.com API response: missing targets")?;
+  let urls: Vec<String> = targets
+    .iter()
+    .filter_map(|target| target.get("url")?.as_str())
+    .map(|url| {
+      url.replacen(
+        "/speedtest",
+        &format!("/speedtest/range/0-{DOWNLOAD_SIZE}"),
+        1,
+      )
+    })
+    .collect();
+  if urls.is_empty() {
+    bail!("fast.com API returned no servers");
+  }
+  Ok(urls)
+}
@@ -0,0 +1,313 @@
+use std::{
+  collections::HashSet,
+  fs,
+  net::IpAddr,
+  path::Path,
+  str::FromStr,
+  sync::Arc,
+  time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use hyper::{body::Bytes, HeaderMap, Method, Request, Response, Uri, Version};
+use parking_lot::Mutex;
+
+use crate::{body::Body, json::Json, output::rfc3339, Target};
+
+/// A request and response exchanged during the run, see the HAR 1.2 spec at
+/// <http://www.softwareishard.com/blog/har-12-spec/>.
+#[derive(Debug, Clone)]
+pub struct Entry {
+  pub started: SystemTime,
+  pub method: Method,
+  pub uri: Uri,
+  pub version: Version,
+  pub request_headers: HeaderMap,
+  pub request_body_size: u64,
+  pub status: u16,
+  pub response_headers: HeaderMap,
+  pub server_ip: Option<IpAddr>,
+  /// Time until the response headers arrived
+  pub wait: Duration,
+  /// Time spent reading the body
+  pub receive: Duration,
+  pub body_size: u64,
+}
+
+impl Entry {
+  pub fn new<B>(
+    started: SystemTime,
+    method: Method,
+    uri: Uri,
+    request_headers: HeaderMap,
+    resp: &Response<B>,
+    wait: Duration,
+  ) -> Entry {
+    Entry {
+      started,
+      method,
+      uri,
+      version: resp.version(),
+      request_headers,
+      request_body_size: 0,
+      status: resp.status().as_u16(),
+      response_headers: resp.headers().clone(),
+      server_ip: None,
+      wait,
+      receive: Duration::ZERO,
+      body_size: 0,
+    }
+  }
+
+  fn to_json(&self) -> Json {
+    let version = format!("{:?}", self.version);
+    let query = self
+      .uri
+      .query()
+      .unwrap_or_default()
+      .split('&')
+      .filter(|pair| !pair.is_empty())
+      .map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        name_value(name, value)
+      })
+      .collect();
+    let mime_type = self
+      .response_headers
+      .get(hyper::header::CONTENT_TYPE)
+      .and_then(|val| val.to_str().ok())
+      .unwrap_or_default();
+    let status_text = hyper::StatusCode::from_u16(self.status)
+      .ok()
+      .and_then(|status| status.canonical_reason())
+      .unwrap_or_default();
+
+    let mut entry = vec![
+      ("startedDateTime", rfc3339(self.started).into()),
+      ("time", millis(self.wait + self.receive)),
+      (
+        "request",
+        Json::object([
+          ("method", self.method.as_str().into()),
+          ("url", self.uri.to_string().into()),
+          ("httpVersion", version.as_str().into()),
+          ("cookies", Json::Array(Vec::new())),
+          ("headers", headers(&self.request_headers)),
+          ("queryString", Json::Array(query)),
+          ("headersSize", Json::Number(-1.0)),
+          ("bodySize", self.request_body_size.into()),
+        ]),
+      ),
+      (
+        "response",
+        Json::object([
+          ("status", u64::from(self.status).into()),
+          ("statusText", status_text.into()),
+          ("httpVersion", version.as_str().into()),
+          ("cookies", Json::Array(Vec::new())),
+          ("headers", headers(&self.response_headers)),
+          (
+            "content",
+            Json::object([
+              ("size", self.body_size.into()),
+              ("mimeType", mime_type.into()),
+            ]),
+          ),
+          ("redirectURL", "".into()),
+          ("headersSize", Json::Number(-1.0)),
+          ("bodySize", self.body_size.into()),
+        ]),
+      ),
+      ("cache", Json::object::<&str>([])),
+      (
+        "timings",
+        Json::object([
+          ("send", 0u64.into()),
+          ("wait", millis(self.wait)),
+          ("receive", millis(self.receive)),
+        ]),
+      ),
+    ];
+    if let Some(ip) = self.server_ip {
+      entry.push(("serverIPAddress", ip.to_string().into()));
+    }
+    Json::object(entry)
+  }
+}
+
+fn millis(duration: Duration) -> Json {
+  // Microsecond precision is plenty and keeps the numbers short
+  Json::Number((duration.as_secs_f64() * 1e6).round() / 1e3)
+}
+
+fn name_value(name: &str, value: &str) -> Json {
+  Json::object([("name", name.into()), ("value", value.into())])
+}
+
+fn headers(headers: &HeaderMap) -> Json {
+  Json::Array(
+    headers
+      .iter()
+      .map(|(name, value)| name_value(name.as_str(), &String::from_utf8_lossy(value.as_bytes())))
+      .collect(),
+  )
+}
+
+/// Collects entries of all tests, shared between concurrently running ones.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<Entry>>>);
+
+impl Recorder {
+  /// Starts recording `entry`, which is added once the returned guard is dropped.
+  pub fn start(&self, entry: Entry) -> Pending {
+    Pending {
+      recorder: self.clone(),
+      entry: Some(entry),
+    }
+  }
+
+  pub fn to_json(&self) -> Json {
+    let mut entries = self.0.lock().clone();
+    entries.sort_by_key(|entry| entry.started);
+    Json::object([(
+      "log",
+      Json::object([
+        ("version", "1.2".into()),
+        (
+          "creator",
+          Json::object([
+            ("name", env!("CARGO_PKG_NAME").into()),
+            ("version", env!("CARGO_PKG_VERSION").into()),
+          ]),
+        ),
+        (
+          "entries",
+          Json::Array(entries.iter().map(Entry::to_json).collect()),
+        ),
+      ]),
+    )])
+  }
+
+  pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+    fs::write(path, format!("{}\n", self.to_json()))
+      .with_context(|| format!("Failed to write HAR file {}", path.display()))
+  }
+}
+
+/// An entry that is recorded when dropped, so failures after the response
+/// arrived still show up in the archive.
+pub struct Pending {
+  recorder: Recorder,
+  entry: Option<Entry>,
+}
+
+impl Pending {
+  pub fn entry(&mut self) -> &mut Entry {
+    self.entry.as_mut().expect("entry is only taken on drop")
+  }
+}
+
+impl Drop for Pending {
+  fn drop(&mut self) {
+    if let Some(entry) = self.entry.take() {
+      self.recorder.0.lock().push(entry);
+    }
+  }
+}
+
+/// Headers a replayed request gets from the client or the new connection
+/// rather than the recording.
+const NOT_REPLAYED: &[&str] = &[
+  "host",
+  "connection",
+  "content-length",
+  "transfer-encoding",
+  "keep-alive",
+  "upgrade",
+];
+
+/// Requests of a HAR file, such as one exported by a browser, whose response
+/// bodies were at least `min_size` bytes, with their original headers. Each
+/// URL is only replayed once.
+pub fn replay(path: &Path, min_size: u64) -> anyhow::Result<Vec<Target>> {
+  let content = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read HAR file {}", path.display()))?;
+  let har = Json::from_str(&content)
+    .map_err(anyhow::Error::msg)
+    .with_context(|| format!("Invalid HAR file {}", path.display()))?;
+  let entries = har
+    .get("log")
+    .and_then(|log| log.get("entries"))
+    .and_then(Json::as_array)
+    .with_context(|| format!("No log.entries in HAR file {}", path.display()))?;
+
+  let mut seen = HashSet::new();
+  let mut targets = Vec::new();
+  for (idx, entry) in entries.iter().enumerate() {
+    let (Some(request), Some(response)) = (entry.get("request"), entry.get("response")) else {
+      continue;
+    };
+    // Browsers report -1 for unknown sizes, and bodySize is 0 for cached responses
+    let size = [
+      response
+        .get("content")
+        .and_then(|content| content.get("size")),
+      response.get("bodySize"),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(Json::as_f64)
+    .fold(0.0, f64::max);
+    let url = request
+      .get("url")
+      .and_then(Json::as_str)
+      .unwrap_or_default();
+    let method = request
+      .get("method")
+      .and_then(Json::as_str)
+      .unwrap_or("GET");
+    if size < min_size as f64 || !(url.starts_with("http://") || url.starts_with("https://")) {
+      continue;
+    }
+    if !seen.insert((method.to_string(), url.to_string())) {
+      continue;
+    }
+    targets.push(
+      replay_request(method, url, request)
+        .with_context(|| format!("Invalid entry #{} in HAR file {}", idx + 1, path.display()))?,
+    );
+  }
+  Ok(targets)
+}
+
+fn replay_request(method: &str, url: &str, request: &Json) -> anyhow::Result<Target> {
+  let mut builder = Request::builder()
+    .method(Method::from_str(method).context("Invalid method")?)
+    .uri(Uri::from_str(url).with_context(|| format!("Invalid URL: {url}"))?);
+  for header in request
+    .get("headers")
+    .and_then(Json::as_array)
+    .unwrap_or_default()
+  {
+    let (Some(name), Some(value)) = (
+      header.get("name").and_then(Json::as_str),
+      header.get("value").and_then(Json::as_str),
+    ) else {
+      continue;
+    };
+    // HTTP/2 pseudo headers like `:authority` are part of the request line
+    if name.starts_with(':') || NOT_REPLAYED.contains(&name.to_ascii_lowercase().as_str()) {
+      continue;
+    }
+    builder = builder.header(name, value);
+  }
+  let body = request
+    .get("postData")
+    .and_then(|data| data.get("text"))
+    .and_then(Json::as_str)
+    .map_or(Body::default(), |text| {
+      Body::new(Bytes::copy_from_slice(text.as_bytes()))
+    });
+  let request = builder.body(body).context("Failed to build request")?;
+  Ok(Target::new(request))
+}
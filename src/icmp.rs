@@ -0,0 +1,211 @@
+use std::{
+  io::ErrorKind,
+  net::{IpAddr, SocketAddr},
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::{latency, random_u64};
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+/// Time between echo requests, like `ping -i 0.2` which anyone may use.
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// A socket sending ICMP echo requests to one host.
+struct Pinger {
+  socket: UdpSocket,
+  ip: IpAddr,
+  /// Replies of raw IPv4 sockets start with the IP header
+  raw: bool,
+  /// Sent in every request, telling our replies apart from those of other pings
+  token: u64,
+}
+
+impl Pinger {
+  /// Opens a raw socket if permitted, as for root or with `CAP_NET_RAW`, else
+  /// an unprivileged datagram socket, which Linux allows for the groups of
+  /// `net.ipv4.ping_group_range` and macOS for everyone.
+  fn new(ip: IpAddr) -> anyhow::Result<Pinger> {
+    let (domain, protocol) = match ip {
+      IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+      IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+    let (socket, raw) = match Socket::new(domain, Type::RAW, Some(protocol)) {
+      Ok(socket) => (socket, true),
+      Err(err) if err.kind() == ErrorKind::PermissionDenied => (
+        Socket::new(domain, Type::DGRAM, Some(protocol)).context(
+          "Failed to open an ICMP socket, which needs root or a group of net.ipv4.ping_group_range",
+        )?,
+        false,
+      ),
+      Err(err) => return Err(err).context("Failed to open an ICMP socket"),
+    };
+    // Only receive the replies of this host
+    socket
+      .connect(&SockAddr::from(SocketAddr::new(ip, 0)))
+      .with_context(|| format!("Failed to connect to {ip}"))?;
+    socket.set_nonblocking(true)?;
+    Ok(Pinger {
+      socket: UdpSocket::from_std(std::net::UdpSocket::from(socket))?,
+      ip,
+      raw: raw && ip.is_ipv4(),
+      token: random_u64(),
+    })
+  }
+
+  /// Round-trip time of the echo request `seq`, `None` if no reply came within `timeout`.
+  async fn ping(&self, seq: u16, timeout: Duration) -> anyhow::Result<Option<Duration>> {
+    let request = match self.ip {
+      IpAddr::V4(_) => ECHO_REQUEST_V4,
+      IpAddr::V6(_) => ECHO_REQUEST_V6,
+    };
+    let mut packet = vec![request, 0, 0, 0];
+    // Datagram sockets replace the identifier with their port
+    packet.extend_from_slice(&(std::process::id() as u16).to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&self.token.to_be_bytes());
+    // The kernel computes the checksums of ICMPv6, which cover the IP addresses
+    if self.ip.is_ipv4() {
+      let checksum = checksum(&packet);
+      packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    let start = Instant::now();
+    self
+      .socket
+      .send(&packet)
+      .await
+      .with_context(|| format!("Failed to send an echo request to {}", self.ip))?;
+    let receive = async {
+      let mut buf = [0; 1500];
+      loop {
+        let len = self.socket.recv(&mut buf).await?;
+        if self.is_reply(&buf[..len], seq) {
+          return anyhow::Ok(start.elapsed());
+        }
+      }
+    };
+    match tokio::time::timeout(timeout, receive).await {
+      Ok(rtt) => rtt.map(Some),
+      Err(_) => Ok(None),
+    }
+  }
+
+  fn is_reply(&self, mut packet: &[u8], seq: u16) -> bool {
+    if self.raw {
+      let header = usize::from(packet.first().map_or(0, |byte| byte & 0x0f)) * 4;
+      let Some(icmp) = packet.get(header..) else {
+        return false;
+      };
+      packet = icmp;
+    }
+    let reply = match self.ip {
+      IpAddr::V4(_) => ECHO_REPLY_V4,
+      IpAddr::V6(_) => ECHO_REPLY_V6,
+    };
+    packet.len() >= 16
+      && packet[0] == reply
+      && packet[6..8] == seq.to_be_bytes()
+      && packet[8..16] == self.token.to_be_bytes()
+  }
+}
+
+/// The internet checksum of RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+  let mut sum = data
+    .chunks(2)
+    .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+    .sum::<u32>();
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// The median round-trip time of `count` ICMP echo requests to `ip`, each
+/// waiting `timeout` for its reply.
+pub async fn ping(ip: IpAddr, count: u32, timeout: Duration) -> anyhow::Result<Duration> {
+  let ip = ip.to_canonical();
+  let pinger = Pinger::new(ip)?;
+  let mut rtts = Vec::new();
+  for seq in 0..count {
+    if seq > 0 {
+      tokio::time::sleep(INTERVAL).await;
+    }
+    if let Some(rtt) = pinger.ping(seq as u16, timeout).await? {
+      rtts.push(rtt);
+    }
+  }
+  match latency::median(&mut rtts) {
+    Some(rtt) => Ok(rtt),
+    None => bail!("No reply to {count} echo requests to {ip} within {timeout:?}"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv6Addr;
+
+  use super::*;
+
+  #[test]
+  fn checksums() {
+    // The example of RFC 1071 section 3
+    assert_eq!(
+      checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+      !0xddf2
+    );
+    // Odd lengths are padded with zero
+    assert_eq!(checksum(&[0x01]), !0x0100);
+    let mut packet = vec![ECHO_REQUEST_V4, 0, 0, 0, 0x12, 0x34, 0, 1, 0xff];
+    let sum = checksum(&packet);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    assert_eq!(checksum(&packet), 0);
+  }
+
+  async fn pinger(ip: IpAddr, raw: bool) -> Pinger {
+    Pinger {
+      socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+      ip,
+      raw,
+      token: 0x0102030405060708,
+    }
+  }
+
+  fn reply(kind: u8, seq: u16, token: u64) -> Vec<u8> {
+    let mut packet = vec![kind, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&token.to_be_bytes());
+    packet
+  }
+
+  #[tokio::test]
+  async fn matches_replies() {
+    let pinger = pinger(Ipv6Addr::LOCALHOST.into(), false).await;
+    let token = pinger.token;
+    assert!(pinger.is_reply(&reply(ECHO_REPLY_V6, 7, token), 7));
+    assert!(!pinger.is_reply(&reply(ECHO_REPLY_V6, 8, token), 7));
+    assert!(!pinger.is_reply(&reply(ECHO_REPLY_V6, 7, token + 1), 7));
+    assert!(!pinger.is_reply(&reply(ECHO_REQUEST_V6, 7, token), 7));
+    assert!(!pinger.is_reply(&reply(ECHO_REPLY_V4, 7, token), 7));
+    assert!(!pinger.is_reply(&reply(ECHO_REPLY_V6, 7, token)[..15], 7));
+  }
+
+  #[tokio::test]
+  async fn skips_ip_headers_of_raw_sockets() {
+    let pinger = pinger([127, 0, 0, 1].into(), true).await;
+    // An IPv4 header of 24 bytes, with options
+    let mut packet = vec![0x46];
+    packet.resize(24, 0);
+    packet.extend(reply(ECHO_REPLY_V4, 1, pinger.token));
+    assert!(pinger.is_reply(&packet, 1));
+    assert!(!pinger.is_reply(&packet[4..], 1));
+    assert!(!pinger.is_reply(&[], 1));
+  }
+}
@@ -0,0 +1,100 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::Context;
+use console::style;
+
+use spt::{printer::Printer, rank, SpeedTester, Target, TestResult};
+
+use crate::{emit_results, Cli};
+
+const HELP: &str = "Select rows to re-test: numbers or ranges (e.g. `1 3-5`), `f` for failed, `a` for all, `q` to quit";
+
+/// Prompts for rows of `results` to re-test, until the user quits or stdin is closed.
+pub async fn run(
+  tester: &SpeedTester,
+  targets: &[Target],
+  results: &mut [TestResult],
+  cli: &Cli,
+) -> anyhow::Result<()> {
+  let render_options = cli.render_options();
+  let mut stdin = io::stdin().lock();
+  loop {
+    println!();
+    for (idx, data) in results.iter().enumerate() {
+      println!(
+        "{} {} {}",
+        style(format!("[{}]", idx + 1)).cyan(),
+        data.speed(),
+        render_options.display_uri(&data.uri)
+      );
+    }
+    println!("{}", style(HELP).dim());
+    print!("{} ", style(">").magenta());
+    io::stdout().flush().context("Failed to flush stdout")?;
+
+    let mut line = String::new();
+    if stdin
+      .read_line(&mut line)
+      .context("Failed to read selection")?
+      == 0
+    {
+      return Ok(());
+    }
+
+    let selection = match parse_selection(&line, results) {
+      Ok(Selection::Quit) => return Ok(()),
+      Ok(Selection::Rows(rows)) => rows,
+      Err(err) => {
+        println!("{}", style(err).red());
+        continue;
+      },
+    };
+    if selection.is_empty() {
+      continue;
+    }
+
+    println!();
+    for row in selection {
+      let id = results[row].id;
+      results[row] = tester.test_target(id, &targets[id], &Printer::Direct).await;
+    }
+
+    rank(results, cli.rank_by);
+    emit_results(cli, results, tester.options().har.as_ref())?;
+  }
+}
+
+enum Selection {
+  Quit,
+  /// Zero-based row indices into the results
+  Rows(Vec<usize>),
+}
+
+fn parse_selection(line: &str, results: &[TestResult]) -> Result<Selection, String> {
+  let mut rows = Vec::new();
+  for token in line.split(|c: char| c == ',' || c.is_whitespace()) {
+    match token {
+      "" => {},
+      "q" | "quit" => return Ok(Selection::Quit),
+      "a" | "all" => rows.extend(0..results.len()),
+      "f" | "failed" => rows.extend(
+        results
+          .iter()
+          .enumerate()
+          .filter(|(_, data)| !data.succeeded())
+          .map(|(idx, _)| idx),
+      ),
+      token => {
+        let (start, end) = token.split_once('-').unwrap_or((token, token));
+        let parse_row = |s: &str| match s.parse::<usize>() {
+          Ok(row) if (1..=results.len()).contains(&row) => Ok(row - 1),
+          _ => Err(format!("Invalid row: {token}")),
+        };
+        rows.extend(parse_row(start)?..=parse_row(end)?);
+      },
+    }
+  }
+  rows.sort_unstable();
+  rows.dedup();
+  Ok(Selection::Rows(rows))
+}
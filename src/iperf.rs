@@ -0,0 +1,45 @@
+use clap::Args;
+use hyper::Method;
+
+use spt::{iperf3, Measure, Target};
+
+use crate::request_targets;
+
+/// An iperf3 server, tested sending and receiving over raw TCP or UDP.
+#[derive(Args)]
+pub struct Iperf3Args {
+  /// Host of the server, with a port unless it listens on the default 5201
+  host: String,
+  /// Test UDP instead of TCP
+  #[clap(long)]
+  udp: bool,
+  /// Bits per second to send UDP at, e.g. `100M`, by default 1M like iperf3
+  #[clap(long, value_name = "RATE", value_parser = iperf3::parse_bitrate, requires = "udp")]
+  bitrate: Option<u64>,
+}
+
+impl Iperf3Args {
+  /// Targets sending to the server and receiving from it.
+  pub fn targets(&self) -> anyhow::Result<Vec<Target>> {
+    let mut query = Vec::new();
+    if self.udp {
+      query.push("udp".to_string());
+    }
+    if let Some(bitrate) = self.bitrate {
+      query.push(format!("bitrate={bitrate}"));
+    }
+    let query = match query.is_empty() {
+      true => String::new(),
+      false => format!("?{}", query.join("&")),
+    };
+    let mut targets = Vec::new();
+    for (path, measure) in [("upload", Measure::Upload), ("download", Measure::Download)] {
+      let url = format!("iperf3://{}/{path}{query}", self.host);
+      for mut target in request_targets(Method::GET, &url, &[])? {
+        target.measure = Some(measure);
+        targets.push(target);
+      }
+    }
+    Ok(targets)
+  }
+}
@@ -0,0 +1,510 @@
+use std::{
+  str::FromStr,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use console::style;
+use hyper::{Request, Uri};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpStream, UdpSocket},
+  task::JoinSet,
+};
+
+use crate::{json::Json, output, printer::Printer, random_u64, Body, TestOptions, TestResult};
+
+pub const DEFAULT_PORT: u16 = 5201;
+
+/// Time a test runs unless `sample_duration` says otherwise, as with iperf3.
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// Bits per second of UDP tests unless the URL says otherwise, as with iperf3.
+const DEFAULT_BITRATE: u64 = 1024 * 1024;
+const TCP_BLOCK_SIZE: usize = 128 * 1024;
+const UDP_BLOCK_SIZE: usize = 1460;
+/// Version the server is told the client has, one whose protocol this speaks.
+const CLIENT_VERSION: &str = "3.1.3";
+
+// States the server sends over the control connection
+const TEST_START: u8 = 1;
+const TEST_RUNNING: u8 = 2;
+const TEST_END: u8 = 4;
+const PARAM_EXCHANGE: u8 = 9;
+const CREATE_STREAMS: u8 = 10;
+const SERVER_TERMINATE: u8 = 11;
+const EXCHANGE_RESULTS: u8 = 13;
+const DISPLAY_RESULTS: u8 = 14;
+const IPERF_DONE: u8 = 16;
+const SERVER_ERROR: u8 = -2i8 as u8;
+const ACCESS_DENIED: u8 = -1i8 as u8;
+
+/// Datagram announcing a UDP stream, and the one the server replies with.
+const UDP_CONNECT_MSG: u32 = 0x36373839;
+const UDP_CONNECT_REPLY: u32 = 0x39383736;
+
+/// Whether `uri` is an `iperf3://host:port` URL of an iperf3 server. A `udp`
+/// query like `?udp&bitrate=10M` tests UDP at that many bits per second.
+pub fn is_iperf3(uri: &Uri) -> bool {
+  uri.scheme_str() == Some("iperf3")
+}
+
+/// Settings of a test from the query of its URL.
+struct Params {
+  udp: bool,
+  bitrate: u64,
+}
+
+impl Params {
+  fn from_uri(uri: &Uri) -> anyhow::Result<Params> {
+    let mut params = Params {
+      udp: false,
+      bitrate: DEFAULT_BITRATE,
+    };
+    for pair in uri.query().unwrap_or_default().split('&') {
+      match pair.split_once('=').unwrap_or((pair, "")) {
+        ("", _) => {},
+        ("udp", _) => params.udp = true,
+        ("bitrate", value) => params.bitrate = parse_bitrate(value).map_err(anyhow::Error::msg)?,
+        (key, _) => bail!("Unknown iperf3 parameter {key}"),
+      }
+    }
+    Ok(params)
+  }
+}
+
+/// Parses bit rates like `100M`, with decimal prefixes as iperf3 does.
+pub fn parse_bitrate(s: &str) -> Result<u64, String> {
+  let (num, scale) = match s.char_indices().last() {
+    Some((idx, 'K' | 'k')) => (&s[..idx], 1e3),
+    Some((idx, 'M' | 'm')) => (&s[..idx], 1e6),
+    Some((idx, 'G' | 'g')) => (&s[..idx], 1e9),
+    _ => (s, 1.0),
+  };
+  let num: f64 = num.parse().map_err(|_| format!("Invalid bitrate {s}"))?;
+  Ok((num * scale) as u64)
+}
+
+/// Tests the server of an `iperf3://` request, sending to it with `upload`
+/// and receiving from it otherwise, over `streams` connections.
+pub async fn test(
+  id: usize,
+  request: Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<TestResult> {
+  let uri = request.uri().clone();
+  let params = Params::from_uri(&uri)?;
+  let host = uri.host().context("URL without host")?.to_string();
+  let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+  let duration = options.sample_duration.unwrap_or(DEFAULT_DURATION);
+  let streams = options.streams.max(1);
+  let reverse = !options.upload;
+  printer.println(format_args!(
+    "{} {} {} for {}",
+    style("==>").magenta(),
+    style(match reverse {
+      true => "RECEIVE",
+      false => "SEND",
+    })
+    .green(),
+    uri,
+    output::format_millis(duration, 0),
+  ));
+
+  let cookie = cookie();
+  let mut control = tokio::time::timeout(
+    options.request_timeout,
+    TcpStream::connect((host.as_str(), port)),
+  )
+  .await
+  .with_context(|| format!("Timed out for {:?}", options.request_timeout))?
+  .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+  let ip = control.peer_addr().ok().map(|addr| addr.ip());
+  control.write_all(&cookie).await?;
+
+  expect_state(&mut control, PARAM_EXCHANGE).await?;
+  let block_size = match params.udp {
+    true => UDP_BLOCK_SIZE,
+    false => TCP_BLOCK_SIZE,
+  };
+  let mut settings = vec![
+    (if params.udp { "udp" } else { "tcp" }, Json::Bool(true)),
+    ("omit", 0u64.into()),
+    ("time", duration.as_secs().max(1).into()),
+    ("parallel", u64::from(streams).into()),
+    ("len", (block_size as u64).into()),
+    ("client_version", CLIENT_VERSION.into()),
+  ];
+  if reverse {
+    settings.push(("reverse", Json::Bool(true)));
+  }
+  if params.udp {
+    settings.push(("bandwidth", params.bitrate.into()));
+  }
+  send_json(&mut control, &Json::object(settings)).await?;
+
+  expect_state(&mut control, CREATE_STREAMS).await?;
+  let mut data = Vec::new();
+  for _ in 0..streams {
+    data.push(match params.udp {
+      true => DataStream::Udp(connect_udp(&host, port).await?),
+      false => {
+        let mut stream = TcpStream::connect((host.as_str(), port))
+          .await
+          .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+        stream.write_all(&cookie).await?;
+        DataStream::Tcp(stream)
+      },
+    });
+  }
+  expect_state(&mut control, TEST_START).await?;
+  expect_state(&mut control, TEST_RUNNING).await?;
+
+  let start = Instant::now();
+  let deadline = start + duration;
+  let bitrate = params.bitrate / u64::from(streams);
+  let mut transfers = JoinSet::new();
+  for stream in data {
+    transfers.spawn(stream.transfer(reverse, deadline, bitrate));
+  }
+  let mut counts = Vec::new();
+  while let Some(transfer) = transfers.join_next().await {
+    counts.push(transfer.context("Transfer panicked")??);
+  }
+  let elapsed = start.elapsed();
+  control.write_all(&[TEST_END]).await?;
+
+  expect_state(&mut control, EXCHANGE_RESULTS).await?;
+  let results = Json::object([
+    ("cpu_util_total", 0u64.into()),
+    ("cpu_util_user", 0u64.into()),
+    ("cpu_util_system", 0u64.into()),
+    ("sender_has_retransmits", 0u64.into()),
+    (
+      "streams",
+      Json::Array(
+        counts
+          .iter()
+          .enumerate()
+          .map(|(idx, &(bytes, packets))| {
+            Json::object([
+              // iperf3 numbers its streams 1, 3, 4, ...
+              ("id", (if idx == 0 { 1 } else { idx as u64 + 2 }).into()),
+              ("bytes", bytes.into()),
+              ("retransmits", 0u64.into()),
+              ("jitter", 0u64.into()),
+              ("errors", 0u64.into()),
+              ("packets", packets.into()),
+              ("start_time", 0u64.into()),
+              ("end_time", elapsed.as_secs_f64().into()),
+            ])
+          })
+          .collect(),
+      ),
+    ),
+  ]);
+  send_json(&mut control, &results).await?;
+  let server = read_json(&mut control).await?;
+  // A server which already closed is fine, the results are in
+  if expect_state(&mut control, DISPLAY_RESULTS).await.is_ok() {
+    let _ = control.write_all(&[IPERF_DONE]).await;
+  }
+
+  // What arrived counts, which for sending only the server knows
+  let bytes = match reverse {
+    true => counts.iter().map(|(bytes, _)| bytes).sum(),
+    false => server
+      .get("streams")
+      .and_then(Json::as_array)
+      .context("Server results without streams")?
+      .iter()
+      .filter_map(|stream| stream.get("bytes")?.as_f64())
+      .sum::<f64>() as u64,
+  };
+  let speed = (bytes * 1000).checked_div(elapsed.as_millis() as u64);
+  printer.println(format_args!(
+    "{} {} in {}",
+    if reverse {
+      "Received"
+    } else {
+      "Server received"
+    },
+    humansize::format_size(bytes, humansize::BINARY),
+    output::format_millis(elapsed, options.time_precision),
+  ));
+  printer.println("");
+
+  Ok(TestResult {
+    id,
+    uri,
+    speed,
+    bytes,
+    ip,
+    elapsed: Some(elapsed),
+    ..Default::default()
+  })
+}
+
+enum DataStream {
+  Tcp(TcpStream),
+  Udp(UdpSocket),
+}
+
+impl DataStream {
+  /// Sends or receives until `deadline`, UDP at `bitrate` bits per second,
+  /// returning the bytes and packets transferred.
+  async fn transfer(
+    self,
+    receive: bool,
+    deadline: Instant,
+    bitrate: u64,
+  ) -> anyhow::Result<(u64, u64)> {
+    let deadline = tokio::time::Instant::from_std(deadline);
+    let mut buf = vec![0; TCP_BLOCK_SIZE];
+    let (mut bytes, mut packets) = (0u64, 0u64);
+    let start = tokio::time::Instant::now();
+    loop {
+      let step = async {
+        match (&self, receive) {
+          (DataStream::Tcp(stream), true) => {
+            stream.readable().await?;
+            match stream.try_read(&mut buf) {
+              Ok(0) => Ok(None),
+              Ok(read) => Ok(Some(read)),
+              Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(Some(0)),
+              Err(err) => Err(err),
+            }
+          },
+          (DataStream::Tcp(stream), false) => {
+            stream.writable().await?;
+            match stream.try_write(&buf) {
+              Ok(written) => Ok(Some(written)),
+              Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(Some(0)),
+              Err(err) => Err(err),
+            }
+          },
+          (DataStream::Udp(socket), true) => socket.recv(&mut buf).await.map(Some),
+          (DataStream::Udp(socket), false) => {
+            // Pace the datagrams to the bitrate
+            let due = start + Duration::from_secs_f64((bytes * 8) as f64 / bitrate.max(1) as f64);
+            tokio::time::sleep_until(due).await;
+            let elapsed = start.elapsed();
+            buf[..4].copy_from_slice(&(elapsed.as_secs() as u32).to_be_bytes());
+            buf[4..8].copy_from_slice(&elapsed.subsec_micros().to_be_bytes());
+            buf[8..12].copy_from_slice(&((packets + 1) as u32).to_be_bytes());
+            socket.send(&buf[..UDP_BLOCK_SIZE]).await.map(Some)
+          },
+        }
+      };
+      match tokio::time::timeout_at(deadline, step).await {
+        Err(_) => break,
+        Ok(Ok(Some(0))) => {},
+        Ok(Ok(Some(len))) => {
+          bytes += len as u64;
+          packets += 1;
+        },
+        Ok(Ok(None)) => break,
+        Ok(Err(err)) => return Err(err).context("Data connection failed"),
+      }
+    }
+    Ok((bytes, packets))
+  }
+}
+
+async fn connect_udp(host: &str, port: u16) -> anyhow::Result<UdpSocket> {
+  let addr = tokio::net::lookup_host((host, port))
+    .await?
+    .next()
+    .with_context(|| format!("Failed to resolve {host}"))?;
+  let local = match addr {
+    std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+    std::net::SocketAddr::V6(_) => "[::]:0",
+  };
+  let socket = UdpSocket::bind(local).await?;
+  socket.connect(addr).await?;
+  // iperf3 compares these in host byte order on both ends
+  socket.send(&UDP_CONNECT_MSG.to_ne_bytes()).await?;
+  let mut reply = [0; 4];
+  tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut reply))
+    .await
+    .context("Server did not accept the UDP stream")??;
+  if u32::from_ne_bytes(reply) != UDP_CONNECT_REPLY {
+    bail!("Unexpected reply to the UDP stream");
+  }
+  Ok(socket)
+}
+
+/// Random 36 characters the server tells the streams of a test apart by,
+/// ended with NUL.
+fn cookie() -> [u8; 37] {
+  const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+  let mut cookie = [0; 37];
+  let mut state = random_u64();
+  for (idx, byte) in cookie.iter_mut().take(36).enumerate() {
+    if idx % 12 == 0 {
+      state = random_u64();
+    }
+    *byte = CHARS[(state & 31) as usize];
+    state >>= 5;
+  }
+  cookie
+}
+
+async fn expect_state(control: &mut TcpStream, expected: u8) -> anyhow::Result<()> {
+  let mut state = [0];
+  control
+    .read_exact(&mut state)
+    .await
+    .context("Server closed the control connection")?;
+  match state[0] {
+    state if state == expected => Ok(()),
+    ACCESS_DENIED => bail!("Server is busy running another test"),
+    SERVER_ERROR => bail!("Server failed the test"),
+    SERVER_TERMINATE => bail!("Server terminated the test"),
+    state => bail!("Unexpected state {state} from the server"),
+  }
+}
+
+/// Sends JSON with its length in 4 bytes before it.
+async fn send_json(control: &mut TcpStream, json: &Json) -> anyhow::Result<()> {
+  let json = json.to_string();
+  control
+    .write_all(&(json.len() as u32).to_be_bytes())
+    .await?;
+  control.write_all(json.as_bytes()).await?;
+  Ok(())
+}
+
+async fn read_json(control: &mut TcpStream) -> anyhow::Result<Json> {
+  let mut len = [0; 4];
+  control.read_exact(&mut len).await?;
+  let mut json = vec![0; u32::from_be_bytes(len) as usize];
+  control.read_exact(&mut json).await?;
+  let json = String::from_utf8(json).context("Server results are not UTF-8")?;
+  Json::from_str(&json)
+    .map_err(anyhow::Error::msg)
+    .context("Invalid server results")
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::net::TcpListener;
+
+  use super::*;
+
+  #[test]
+  fn parses_bitrates() {
+    assert_eq!(parse_bitrate("10"), Ok(10));
+    assert_eq!(parse_bitrate("1.5k"), Ok(1500));
+    assert_eq!(parse_bitrate("100M"), Ok(100_000_000));
+    assert_eq!(parse_bitrate("2g"), Ok(2_000_000_000));
+    assert!(parse_bitrate("M").is_err());
+    assert!(parse_bitrate("10Mbps").is_err());
+  }
+
+  #[test]
+  fn params_from_query() {
+    let params = |uri: &'static str| Params::from_uri(&Uri::from_static(uri));
+    let default = params("iperf3://example.com").unwrap();
+    assert!(!default.udp);
+    assert_eq!(default.bitrate, DEFAULT_BITRATE);
+    let udp = params("iperf3://example.com/?udp&bitrate=10M").unwrap();
+    assert!(udp.udp);
+    assert_eq!(udp.bitrate, 10_000_000);
+    assert!(params("iperf3://example.com/?bitrate=fast").is_err());
+    assert!(params("iperf3://example.com/?reverse").is_err());
+  }
+
+  #[test]
+  fn cookies_are_random_and_nul_terminated() {
+    let cookie = cookie();
+    assert_eq!(cookie[36], 0);
+    assert!(cookie[..36]
+      .iter()
+      .all(|byte| b"abcdefghijklmnopqrstuvwxyz234567".contains(byte)));
+    assert_ne!(cookie, self::cookie());
+  }
+
+  /// Reads the JSON the client sends, with its length before it.
+  async fn receive_json(stream: &mut TcpStream) -> Json {
+    let len = stream.read_u32().await.unwrap();
+    let mut json = vec![0; len as usize];
+    stream.read_exact(&mut json).await.unwrap();
+    Json::from_str(std::str::from_utf8(&json).unwrap()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn receives_from_a_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+      let (mut control, _) = listener.accept().await.unwrap();
+      let mut cookie = [0; 37];
+      control.read_exact(&mut cookie).await.unwrap();
+      control.write_all(&[PARAM_EXCHANGE]).await.unwrap();
+      let settings = receive_json(&mut control).await;
+      assert_eq!(settings.get("reverse"), Some(&Json::Bool(true)));
+      assert_eq!(settings.get("parallel").and_then(Json::as_f64), Some(1.0));
+
+      control.write_all(&[CREATE_STREAMS]).await.unwrap();
+      let (mut data, _) = listener.accept().await.unwrap();
+      let mut data_cookie = [0; 37];
+      data.read_exact(&mut data_cookie).await.unwrap();
+      assert_eq!(data_cookie, cookie);
+      control
+        .write_all(&[TEST_START, TEST_RUNNING])
+        .await
+        .unwrap();
+      data.write_all(&[0; 100_000]).await.unwrap();
+
+      // The client stops at the end of the test, not when the data ends
+      assert_eq!(control.read_u8().await.unwrap(), TEST_END);
+      drop(data);
+      control.write_all(&[EXCHANGE_RESULTS]).await.unwrap();
+      let results = receive_json(&mut control).await;
+      let streams = results.get("streams").and_then(Json::as_array).unwrap();
+      assert_eq!(
+        streams[0].get("bytes").and_then(Json::as_f64),
+        Some(100_000.0)
+      );
+      let json = r#"{"streams":[{"id":1,"bytes":100000}]}"#;
+      control.write_u32(json.len() as u32).await.unwrap();
+      control.write_all(json.as_bytes()).await.unwrap();
+      control.write_all(&[DISPLAY_RESULTS]).await.unwrap();
+      assert_eq!(control.read_u8().await.unwrap(), IPERF_DONE);
+    });
+
+    let options = TestOptions {
+      request_timeout: Duration::from_secs(5),
+      sample_duration: Some(Duration::from_millis(200)),
+      ..Default::default()
+    };
+    let request = Request::get(format!("iperf3://{addr}"))
+      .body(Body::default())
+      .unwrap();
+    let result = test(0, request, &options, &Printer::Silent).await.unwrap();
+    server.await.unwrap();
+    assert_eq!(result.bytes, 100_000);
+    assert!(result.elapsed.unwrap() >= Duration::from_millis(200));
+    assert!(result.speed.is_some());
+  }
+
+  #[tokio::test]
+  async fn busy_server_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (mut control, _) = listener.accept().await.unwrap();
+      let mut cookie = [0; 37];
+      control.read_exact(&mut cookie).await.unwrap();
+      control.write_all(&[ACCESS_DENIED]).await.unwrap();
+    });
+    let request = Request::get(format!("iperf3://{addr}"))
+      .body(Body::default())
+      .unwrap();
+    let error = test(0, request, &TestOptions::default(), &Printer::Silent)
+      .await
+      .unwrap_err();
+    assert_eq!(error.to_string(), "Server is busy running another test");
+  }
+}
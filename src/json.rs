@@ -0,0 +1,422 @@
+use std::{fmt, str::FromStr};
+
+/// A minimal JSON value, enough to emit structured output and read request
+/// specs without serde.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+  Null,
+  Bool(bool),
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  /// Object members, kept in insertion order
+  Object(Vec<(String, Json)>),
+}
+
+impl Json {
+  pub fn object<K: Into<String>>(members: impl IntoIterator<Item = (K, Json)>) -> Json {
+    Json::Object(
+      members
+        .into_iter()
+        .map(|(key, value)| (key.into(), value))
+        .collect(),
+    )
+  }
+
+  /// The value of member `key`, if this is an object that has it.
+  pub fn get(&self, key: &str) -> Option<&Json> {
+    match self {
+      Json::Object(members) => members
+        .iter()
+        .find_map(|(name, value)| (name == key).then_some(value)),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Json::String(s) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Json::Number(num) => Some(*num),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&[Json]> {
+    match self {
+      Json::Array(items) => Some(items),
+      _ => None,
+    }
+  }
+}
+
+impl FromStr for Json {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parser = Parser {
+      input: s,
+      pos: 0,
+      depth: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < s.len() {
+      return Err(parser.error("unexpected trailing characters"));
+    }
+    Ok(value)
+  }
+}
+
+/// Arrays and objects nested deeper than this are rejected, rather than
+/// overflowing the stack while parsing them.
+pub const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+  input: &'a str,
+  pos: usize,
+  /// Arrays and objects the parser is in
+  depth: usize,
+}
+
+impl Parser<'_> {
+  fn error(&self, message: &str) -> String {
+    format!("{message} at offset {}", self.pos)
+  }
+
+  fn skip_whitespace(&mut self) {
+    let rest = &self.input[self.pos..];
+    self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.input.as_bytes().get(self.pos).copied()
+  }
+
+  fn expect(&mut self, byte: u8) -> Result<(), String> {
+    self.skip_whitespace();
+    if self.peek() != Some(byte) {
+      return Err(self.error(&format!("expected {}", byte as char)));
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  fn value(&mut self) -> Result<Json, String> {
+    self.skip_whitespace();
+    match self.peek() {
+      Some(b'{' | b'[') => {
+        if self.depth >= MAX_DEPTH {
+          return Err(self.error(&format!("nested deeper than {MAX_DEPTH} levels")));
+        }
+        self.depth += 1;
+        let value = self.collection();
+        self.depth -= 1;
+        value
+      },
+      Some(b'"') => self.string().map(Json::String),
+      Some(_) => {
+        let rest = &self.input[self.pos..];
+        let len = rest
+          .find(|ch: char| !(ch.is_ascii_alphanumeric() || matches!(ch, '+' | '-' | '.')))
+          .unwrap_or(rest.len());
+        let value = match &rest[..len] {
+          "null" => Json::Null,
+          "true" => Json::Bool(true),
+          "false" => Json::Bool(false),
+          token => Json::Number(
+            token
+              .parse()
+              .map_err(|_| self.error(&format!("unexpected {token}")))?,
+          ),
+        };
+        self.pos += len;
+        Ok(value)
+      },
+      None => Err(self.error("unexpected end of input")),
+    }
+  }
+
+  /// The object or array starting at the current position.
+  fn collection(&mut self) -> Result<Json, String> {
+    match self.peek() {
+      Some(b'{') => {
+        self.pos += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+          self.pos += 1;
+          return Ok(Json::Object(members));
+        }
+        loop {
+          self.skip_whitespace();
+          let key = self.string()?;
+          self.expect(b':')?;
+          members.push((key, self.value()?));
+          self.skip_whitespace();
+          match self.peek() {
+            Some(b',') => self.pos += 1,
+            Some(b'}') => {
+              self.pos += 1;
+              return Ok(Json::Object(members));
+            },
+            _ => return Err(self.error("expected , or }")),
+          }
+        }
+      },
+      Some(b'[') => {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+          self.pos += 1;
+          return Ok(Json::Array(items));
+        }
+        loop {
+          items.push(self.value()?);
+          self.skip_whitespace();
+          match self.peek() {
+            Some(b',') => self.pos += 1,
+            Some(b']') => {
+              self.pos += 1;
+              return Ok(Json::Array(items));
+            },
+            _ => return Err(self.error("expected , or ]")),
+          }
+        }
+      },
+      _ => Err(self.error("expected { or [")),
+    }
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    if self.peek() != Some(b'"') {
+      return Err(self.error("expected a string"));
+    }
+    self.pos += 1;
+    let mut value = String::new();
+    loop {
+      let rest = &self.input[self.pos..];
+      let end = rest
+        .find(['"', '\\'])
+        .ok_or_else(|| self.error("unterminated string"))?;
+      value.push_str(&rest[..end]);
+      self.pos += end + 1;
+      if rest.as_bytes()[end] == b'"' {
+        return Ok(value);
+      }
+      let escape = self
+        .peek()
+        .ok_or_else(|| self.error("unterminated string"))?;
+      self.pos += 1;
+      match escape {
+        b'"' => value.push('"'),
+        b'\\' => value.push('\\'),
+        b'/' => value.push('/'),
+        b'b' => value.push('\u{8}'),
+        b'f' => value.push('\u{c}'),
+        b'n' => value.push('\n'),
+        b'r' => value.push('\r'),
+        b't' => value.push('\t'),
+        b'u' => {
+          let mut code = self.hex4()?;
+          // Characters outside the BMP come as a surrogate pair
+          if (0xd800..0xdc00).contains(&code) && self.input[self.pos..].starts_with("\\u") {
+            self.pos += 2;
+            let low = self.hex4()?;
+            code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+          }
+          value.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+        },
+        _ => return Err(self.error("invalid escape")),
+      }
+    }
+  }
+
+  fn hex4(&mut self) -> Result<u32, String> {
+    let hex = self
+      .input
+      .get(self.pos..self.pos + 4)
+      .ok_or_else(|| self.error("truncated \\u escape"))?;
+    let code = u32::from_str_radix(hex, 16).map_err(|_| self.error("invalid \\u escape"))?;
+    self.pos += 4;
+    Ok(code)
+  }
+}
+
+impl fmt::Display for Json {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Json::Null => f.write_str("null"),
+      Json::Bool(b) => write!(f, "{b}"),
+      Json::Number(num) if !num.is_finite() => f.write_str("null"),
+      Json::Number(num) if num.fract() == 0.0 && num.abs() < 2f64.powi(53) => {
+        write!(f, "{}", *num as i64)
+      },
+      Json::Number(num) => write!(f, "{num}"),
+      Json::String(s) => write_escaped(f, s),
+      Json::Array(items) => {
+        f.write_str("[")?;
+        for (idx, item) in items.iter().enumerate() {
+          if idx > 0 {
+            f.write_str(",")?;
+          }
+          write!(f, "{item}")?;
+        }
+        f.write_str("]")
+      },
+      Json::Object(members) => {
+        f.write_str("{")?;
+        for (idx, (key, value)) in members.iter().enumerate() {
+          if idx > 0 {
+            f.write_str(",")?;
+          }
+          write_escaped(f, key)?;
+          write!(f, ":{value}")?;
+        }
+        f.write_str("}")
+      },
+    }
+  }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+  f.write_str("\"")?;
+  for ch in s.chars() {
+    match ch {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      ch if u32::from(ch) < 0x20 => write!(f, "\\u{:04x}", u32::from(ch))?,
+      ch => write!(f, "{ch}")?,
+    }
+  }
+  f.write_str("\"")
+}
+
+impl From<bool> for Json {
+  fn from(b: bool) -> Json {
+    Json::Bool(b)
+  }
+}
+
+impl From<&str> for Json {
+  fn from(s: &str) -> Json {
+    Json::String(s.to_string())
+  }
+}
+
+impl From<String> for Json {
+  fn from(s: String) -> Json {
+    Json::String(s)
+  }
+}
+
+impl From<u64> for Json {
+  fn from(num: u64) -> Json {
+    Json::Number(num as f64)
+  }
+}
+
+impl From<f64> for Json {
+  fn from(num: f64) -> Json {
+    Json::Number(num)
+  }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+  fn from(value: Option<T>) -> Json {
+    value.map_or(Json::Null, Into::into)
+  }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+  fn from(items: Vec<T>) -> Json {
+    Json::Array(items.into_iter().map(Into::into).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(s: &str) -> Result<Json, String> {
+    s.parse()
+  }
+
+  #[test]
+  fn parses_values() {
+    assert_eq!(
+      parse(r#" {"a": [1, -2.5e3, true, false, null], "b": {}, "c": []} "#).unwrap(),
+      Json::object([
+        (
+          "a",
+          Json::Array(vec![
+            Json::Number(1.0),
+            Json::Number(-2500.0),
+            Json::Bool(true),
+            Json::Bool(false),
+            Json::Null,
+          ])
+        ),
+        ("b", Json::Object(Vec::new())),
+        ("c", Json::Array(Vec::new())),
+      ])
+    );
+  }
+
+  #[test]
+  fn parses_escapes() {
+    assert_eq!(
+      parse(r#""a\"\\\/\n\t\u00e9\ud83d\ude00""#).unwrap(),
+      Json::from("a\"\\/\n\té😀")
+    );
+  }
+
+  #[test]
+  fn rejects_invalid() {
+    for s in [
+      "",
+      "[1,",
+      "[1 2]",
+      "{\"a\" 1}",
+      "{a: 1}",
+      "\"open",
+      "\"\\x\"",
+      "nul",
+      "1 2",
+    ] {
+      assert!(parse(s).is_err(), "{s}");
+    }
+  }
+
+  #[test]
+  fn limits_depth() {
+    let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    assert!(parse(&nested(MAX_DEPTH)).is_ok());
+    assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+    assert!(parse(&"[".repeat(200_000)).is_err());
+  }
+
+  #[test]
+  fn round_trips() {
+    let value = Json::object([
+      ("s", Json::from("quote \" and \u{1}")),
+      ("n", Json::from(3u64)),
+      ("f", Json::from(0.5)),
+      ("a", Json::from(vec![Json::Null, Json::from(true)])),
+    ]);
+    assert_eq!(
+      value.to_string(),
+      r#"{"s":"quote \" and \u0001","n":3,"f":0.5,"a":[null,true]}"#
+    );
+    assert_eq!(parse(&value.to_string()).unwrap(), value);
+  }
+}
@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use console::style;
+use hyper::{Method, Request};
+use hyper_util::client::legacy::connect::HttpInfo;
+
+use crate::{
+  clone_request, connector::TlsInfo, output, printer::Printer, Body, TestOptions, TestResult,
+  TlsHyper,
+};
+
+/// Sends `samples` HEAD requests, each on a new connection so DNS resolution,
+/// connecting and the TLS handshake are part of every sample, and reports the
+/// median time until the response headers arrive, its jitter and the share of
+/// requests that timed out.
+pub async fn test(
+  client: &TlsHyper,
+  id: usize,
+  request: Request<Body>,
+  samples: u32,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<TestResult> {
+  let uri = request.uri().clone();
+  printer.println(format_args!(
+    "{} {} {}",
+    style("==>").magenta(),
+    style(Method::HEAD).green(),
+    uri,
+  ));
+
+  let mut latencies = Vec::new();
+  let mut ip = None;
+  let mut tls = None;
+  let mut status = None;
+  let mut lost = 0;
+  for _ in 0..samples {
+    let mut head = clone_request(&request);
+    *head.method_mut() = Method::HEAD;
+    *head.body_mut() = Body::default();
+
+    let start = Instant::now();
+    let Ok(resp) = tokio::time::timeout(options.request_timeout, client.request(head)).await else {
+      printer.println(style(format!("Timed out for {:?}", options.request_timeout)).yellow());
+      lost += 1;
+      continue;
+    };
+    let resp = resp.context("Failed to send request")?;
+    let latency = start.elapsed();
+    printer.println(format_args!(
+      "{:?} {} {}",
+      resp.version(),
+      resp.status(),
+      output::format_millis(latency, options.time_precision)
+    ));
+
+    ip = resp
+      .extensions()
+      .get::<HttpInfo>()
+      .map(|info| info.remote_addr().ip())
+      .or(ip);
+    tls = resp.extensions().get::<TlsInfo>().copied().or(tls);
+    status = Some(resp.status());
+    latencies.push(latency);
+  }
+  printer.println("");
+  if latencies.is_empty() {
+    bail!("All {samples} requests timed out");
+  }
+
+  Ok(TestResult {
+    id,
+    uri,
+    ip,
+    tls,
+    status,
+    jitter: jitter(&latencies),
+    loss: Some(f64::from(lost) / f64::from(samples)),
+    latency: median(&mut latencies),
+    ..Default::default()
+  })
+}
+
+/// Mean absolute deviation of the latencies from their mean, undefined for less
+/// than two.
+pub(crate) fn jitter(latencies: &[Duration]) -> Option<Duration> {
+  if latencies.len() < 2 {
+    return None;
+  }
+  let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+  let deviation: Duration = latencies.iter().map(|latency| latency.abs_diff(mean)).sum();
+  Some(deviation / latencies.len() as u32)
+}
+
+pub(crate) fn median(latencies: &mut [Duration]) -> Option<Duration> {
+  latencies.sort_unstable();
+  match latencies.len() {
+    0 => None,
+    len if len % 2 == 0 => Some((latencies[len / 2 - 1] + latencies[len / 2]) / 2),
+    len => Some(latencies[len / 2]),
+  }
+}
@@ -0,0 +1,2455 @@
+//! Measures how fast URLs download, or upload with [`TestOptions::upload`].
+//!
+//! [`SpeedTester`] is the entry point for embedding the measurements of the
+//! `spt` command line tool: build [`Clients`] once, then test requests with the
+//! [`TestOptions`] of your choice.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use spt::{body::Body, hyper::Request, Clients, SpeedTester, TestOptions};
+//!
+//! let tester = SpeedTester::new(Clients::with_defaults()?, TestOptions::default());
+//! let request = Request::get("https://example.com/100MB.bin").body(Body::default())?;
+//! let result = tester.test(request).await;
+//! println!("{} {}", result.uri, result.speed());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+  borrow::Cow,
+  collections::hash_map::{DefaultHasher, RandomState},
+  fs::{self, File, OpenOptions},
+  future::Future,
+  hash::{BuildHasher, Hasher},
+  io::Write,
+  net::IpAddr,
+  path::PathBuf,
+  pin::Pin,
+  str::FromStr,
+  sync::Arc,
+  task::{Context as TaskContext, Poll},
+  time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use console::style;
+use futures::StreamExt;
+use http_body_util::{BodyDataStream, BodyExt};
+pub use hyper;
+use hyper::{
+  body::{Bytes, Incoming},
+  Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::{
+  client::legacy::{connect::HttpInfo, Client as HyperClient},
+  rt::TokioExecutor,
+};
+use indicatif::ProgressStyle;
+use rustls::ClientConfig;
+use tokio::{
+  sync::{Semaphore, SemaphorePermit},
+  task::{JoinError, JoinHandle},
+};
+
+pub mod apt;
+pub mod body;
+pub mod bufferbloat;
+pub mod checksum;
+pub mod connector;
+pub mod criteria;
+pub mod dns;
+pub mod fast;
+pub mod har;
+mod icmp;
+pub mod iperf3;
+pub mod json;
+mod latency;
+pub mod netrc;
+pub mod ookla;
+pub mod output;
+pub mod pacman;
+pub mod printer;
+pub mod proxy;
+pub mod registry;
+pub mod summary;
+pub mod tls;
+pub mod uri;
+pub mod websocket;
+
+use body::Body;
+use bufferbloat::{Bufferbloat, Load};
+use checksum::Sha256;
+use connector::{ConnectTimings, Connector, TlsInfo};
+use dns::{Resolver, ResolverOptions};
+use printer::Printer;
+use proxy::{ConnectOptions, ProxyConnector};
+use tls::TlsVersion;
+
+/// The HTTP client tests are run with.
+pub type TlsHyper = HyperClient<Connector, Body>;
+
+pub struct Clients {
+  pub default: TlsHyper,
+  /// Client which never negotiates HTTP/2, as needed by protocol upgrades
+  pub http1: TlsHyper,
+  /// Client which opens a new connection for every request
+  pub fresh: TlsHyper,
+}
+
+impl Clients {
+  /// Builds the clients, resolving host names as `resolver` says, connecting
+  /// as `connect` says and negotiating TLS with `tls_config`.
+  pub fn new(
+    tls_config: ClientConfig,
+    resolver: ResolverOptions,
+    connect: ConnectOptions,
+    version: HttpVersion,
+  ) -> Clients {
+    let resolver = Resolver::new(resolver, tls_config.clone());
+    let http = ProxyConnector::new(resolver, connect);
+    let builder = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_tls_config(tls_config.clone())
+      .https_or_http();
+    let https = match version {
+      HttpVersion::Auto => builder
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(http.clone()),
+      HttpVersion::Http1 => builder.enable_http1().wrap_connector(http.clone()),
+      HttpVersion::Http2 | HttpVersion::Http2PriorKnowledge => {
+        builder.enable_http2().wrap_connector(http.clone())
+      },
+    };
+    let http1 = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_tls_config(tls_config)
+      .https_or_http()
+      .enable_http1()
+      .wrap_connector(http);
+    let builder = || {
+      let mut builder = HyperClient::builder(TokioExecutor::new());
+      builder.http2_only(version == HttpVersion::Http2PriorKnowledge);
+      builder
+    };
+    let connector = match version {
+      HttpVersion::Http2 => Connector::new(https).require_http2(),
+      _ => Connector::new(https),
+    };
+    Clients {
+      default: builder().build(connector.clone()),
+      fresh: builder().pool_max_idle_per_host(0).build(connector),
+      http1: HyperClient::builder(TokioExecutor::new()).build(Connector::new(http1)),
+    }
+  }
+
+  /// Clients with the system resolver and root certificates, connecting directly.
+  pub fn with_defaults() -> anyhow::Result<Clients> {
+    let tls_config = tls::client_config(&Default::default())?;
+    Ok(Clients::new(
+      tls_config,
+      Default::default(),
+      Default::default(),
+      HttpVersion::Auto,
+    ))
+  }
+}
+
+/// HTTP version the clients speak, except for protocol upgrades which always use HTTP/1.1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+  /// HTTP/2 if the server picks it during the TLS handshake, else HTTP/1.1
+  #[default]
+  Auto,
+  Http1,
+  /// Offer only HTTP/2 during the TLS handshake, failing on servers which
+  /// don't pick it, plain HTTP stays HTTP/1.1
+  Http2,
+  /// HTTP/2 without negotiation, also over plain HTTP
+  Http2PriorKnowledge,
+}
+
+/// Runs speed tests with shared clients and options.
+pub struct SpeedTester {
+  clients: Clients,
+  options: TestOptions,
+}
+
+impl SpeedTester {
+  pub fn new(clients: Clients, options: TestOptions) -> SpeedTester {
+    SpeedTester { clients, options }
+  }
+
+  pub fn clients(&self) -> &Clients {
+    &self.clients
+  }
+
+  pub fn options(&self) -> &TestOptions {
+    &self.options
+  }
+
+  /// Tests a single request without printing anything. Failures don't make
+  /// this fail, but are reported in [`TestResult::error`].
+  pub async fn test(&self, request: Request<Body>) -> TestResult {
+    self
+      .test_target(0, &Target::new(request), &Printer::Silent)
+      .await
+  }
+
+  /// Tests `target`, reporting progress to `printer`. The `id` is passed
+  /// through to [`TestResult::id`].
+  pub async fn test_target(&self, id: usize, target: &Target, printer: &Printer) -> TestResult {
+    printer::scope(
+      printer.clone(),
+      run_test(&self.clients, id, target, &self.options, printer),
+    )
+    .await
+  }
+}
+
+/// A request to test, along with its settings from the URL file.
+pub struct Target {
+  pub request: Request<Body>,
+  /// Weight of the URL in aggregate statistics
+  pub weight: f64,
+  /// Expected digest of the response body
+  pub sha256: Option<Sha256>,
+  /// Status the response must have, instead of any 2xx
+  pub expect_status: Option<StatusCode>,
+  /// Time to wait for the response headers instead of `TestOptions::request_timeout`
+  pub timeout: Option<Duration>,
+  /// Times to test the target, instead of as often as every other
+  pub runs: Option<u32>,
+  /// What to measure instead of what `TestOptions` says, for runs mixing
+  /// downloads, uploads and latency probes
+  pub measure: Option<Measure>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Measure {
+  Download,
+  /// How fast the request body is sent
+  Upload,
+  /// Latency of this many requests, see `latency::test`
+  Latency(u32),
+}
+
+impl Target {
+  pub fn new(request: Request<Body>) -> Target {
+    Target {
+      request,
+      weight: 1.0,
+      sha256: None,
+      expect_status: None,
+      timeout: None,
+      runs: None,
+      measure: None,
+    }
+  }
+}
+
+/// Tests a single target with retries, turning failures into a result without speed.
+async fn run_test(
+  clients: &Clients,
+  id: usize,
+  target: &Target,
+  options: &TestOptions,
+  printer: &Printer,
+) -> TestResult {
+  let request = &target.request;
+  let uri = request.uri().clone();
+  let method = request.method().clone();
+  let overridden;
+  let options = match (
+    target.sha256,
+    target.expect_status,
+    target.timeout,
+    target.measure,
+  ) {
+    (None, None, None, None) => options,
+    (sha256, expect_status, timeout, measure) => {
+      overridden = TestOptions {
+        sha256: sha256.or(options.sha256),
+        expect_status: expect_status.or(options.expect_status),
+        request_timeout: timeout.unwrap_or(options.request_timeout),
+        upload: match measure {
+          Some(measure) => measure == Measure::Upload,
+          None => options.upload,
+        },
+        latency_samples: match measure {
+          Some(Measure::Latency(samples)) => Some(samples),
+          Some(Measure::Download | Measure::Upload) => None,
+          None => options.latency_samples,
+        },
+        ..options.clone()
+      };
+      &overridden
+    },
+  };
+  let mut attempt = 0;
+  loop {
+    let started = Instant::now();
+    let test = if iperf3::is_iperf3(&uri) {
+      iperf3::test(id, clone_request(request), options, printer).await
+    } else if ookla::is_ookla(&uri) {
+      ookla::test(id, clone_request(request), options, printer).await
+    } else if websocket::is_websocket(&uri) {
+      websocket::test(&clients.http1, id, clone_request(request), options, printer).await
+    } else if let Some(samples) = options.latency_samples {
+      latency::test(
+        &clients.fresh,
+        id,
+        clone_request(request),
+        samples,
+        options,
+        printer,
+      )
+      .await
+    } else {
+      // Streams multiplexed onto one HTTP/2 connection would share its congestion window
+      let client = if options.streams > 1 {
+        &clients.http1
+      } else {
+        &clients.default
+      };
+      match options.bufferbloat {
+        true => {
+          let load = Load::new();
+          let loading = TestOptions {
+            load: Some(load.clone()),
+            ..options.clone()
+          };
+          let test = test_and_render(client, id, clone_request(request), &loading, printer);
+          bufferbloat::test(&clients.fresh, request, options, printer, &load, test).await
+        },
+        false => test_and_render(client, id, clone_request(request), options, printer).await,
+      }
+    };
+    let err = match test {
+      Ok(data) => {
+        let ping = match options.ping {
+          true => ping(&uri, data.ip, options, printer).await,
+          false => None,
+        };
+        return TestResult {
+          method,
+          weight: target.weight,
+          attempts: attempt + 1,
+          samples: data.speed.into_iter().collect(),
+          elapsed: data.elapsed.or(Some(started.elapsed())),
+          ping: ping.or(data.ping),
+          ..data
+        };
+      },
+      Err(err) => err.context(format!("Failed to {} {}", method, uri)),
+    };
+    let status = err
+      .downcast_ref::<UnexpectedStatus>()
+      .map(|status| status.0);
+    printer.println(style(format!("{:?}", err)).red());
+    printer.println("");
+
+    // Other client errors won't go away by asking again, nor will a slow mirror speed up
+    let transient = !err.is::<BelowThreshold>()
+      && status.is_none_or(|status| {
+        status.is_server_error()
+          || status == StatusCode::REQUEST_TIMEOUT
+          || status == StatusCode::TOO_MANY_REQUESTS
+      });
+    if !transient && options.retry.retries > 0 {
+      tracing::info!("Not retrying, the error won't go away by asking again");
+    }
+    if attempt >= options.retry.retries || !transient {
+      return TestResult {
+        id,
+        uri,
+        method,
+        weight: target.weight,
+        attempts: attempt + 1,
+        status,
+        error: Some(format!("{err:#}")),
+        elapsed: Some(started.elapsed()),
+        ..Default::default()
+      };
+    }
+    let delay = options.retry.delay(attempt);
+    attempt += 1;
+    printer.println(
+      style(format!(
+        "Retrying in {:?} ({}/{})...",
+        delay, attempt, options.retry.retries
+      ))
+      .yellow(),
+    );
+    tokio::time::sleep(delay).await;
+  }
+}
+
+/// Echo requests per `TestOptions::ping`.
+const PINGS: u32 = 5;
+/// Time to wait for each echo reply, shorter than most request timeouts as
+/// lost pings are common.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// ICMP round-trip time of the server of `uri`, at `ip` if the test connected
+/// to it. Failures only warn, as ICMP is often filtered or not permitted.
+async fn ping(
+  uri: &Uri,
+  ip: Option<IpAddr>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> Option<Duration> {
+  let ping = async {
+    let ip = match ip {
+      Some(ip) => ip,
+      None => {
+        let host = uri.host().context("URL without host")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        tokio::net::lookup_host((host, 0))
+          .await
+          .with_context(|| format!("Failed to resolve {host}"))?
+          .next()
+          .with_context(|| format!("No address for {host}"))?
+          .ip()
+      },
+    };
+    icmp::ping(ip, PINGS, PING_TIMEOUT).await
+  };
+  match ping.await {
+    Ok(rtt) => {
+      printer.println(format_args!(
+        "Ping {}",
+        output::format_millis(rtt, options.time_precision)
+      ));
+      printer.println("");
+      Some(rtt)
+    },
+    Err(err) => {
+      printer.println(style(format!("No ping: {err:#}")).yellow());
+      printer.println("");
+      None
+    },
+  }
+}
+
+/// Sorts results from fastest to slowest.
+pub fn rank(results: &mut [TestResult], by: Aggregate) {
+  results.sort_unstable_by(|a, b| b.aggregate(by).cmp(&a.aggregate(by)).then_with(|| b.cmp(a)));
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+  /// Position of the request in the input list
+  pub id: usize,
+  pub uri: Uri,
+  pub method: Method,
+  pub speed: Option<u64>,
+  /// Bytes received from the response bodies, or sent with `--upload`
+  pub bytes: u64,
+  pub ip: Option<IpAddr>,
+  pub tls: Option<TlsInfo>,
+  /// Status of the last response, if the server answered at all
+  pub status: Option<StatusCode>,
+  /// Time until the response, for tests that measure latency rather than speed
+  pub latency: Option<Duration>,
+  /// Mean deviation of the latencies of repeated requests from their mean
+  pub jitter: Option<Duration>,
+  /// Share of the latency requests that timed out, from 0 to 1
+  pub loss: Option<f64>,
+  /// Time the test took, until failure for failed tests
+  pub elapsed: Option<Duration>,
+  /// Why the last attempt failed
+  pub error: Option<String>,
+  pub weight: f64,
+  /// Number of times the URL was tested
+  pub runs: u32,
+  /// Requests made for the URL, including retries, over all runs
+  pub attempts: u32,
+  /// Speeds of the successful runs, `speed` being their mean
+  pub samples: Vec<u64>,
+  pub timings: Option<Timings>,
+  /// URL the redirects led to, `None` if the server didn't redirect
+  pub final_uri: Option<Uri>,
+  /// Redirects followed to get to `final_uri`
+  pub redirects: u32,
+  /// The download was stopped early, see `TestOptions::max_bytes` and
+  /// `TestOptions::sample_duration`
+  pub truncated: bool,
+  /// Bytes received in each second of the transfers, over all runs
+  pub intervals: Vec<u64>,
+  /// Speeds of the concurrent streams of the last run with `TestOptions::streams`
+  pub stream_speeds: Vec<u64>,
+  /// Latency before and during the transfer of the last run
+  pub bufferbloat: Option<Bufferbloat>,
+  /// Median round-trip time of ICMP echo requests to the server, see `TestOptions::ping`
+  pub ping: Option<Duration>,
+}
+
+/// Statistics of the per-second throughput of a transfer, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throughput {
+  pub min: u64,
+  pub max: u64,
+  pub mean: u64,
+  pub stddev: u64,
+}
+
+impl Throughput {
+  /// How steady the throughput was, from 0 to 1: one minus the coefficient of
+  /// variation, so a server which bursts then stalls scores low.
+  pub fn stability(&self) -> f64 {
+    if self.mean == 0 {
+      return 0.0;
+    }
+    (1.0 - self.stddev as f64 / self.mean as f64).clamp(0.0, 1.0)
+  }
+}
+
+/// Durations of the phases of a test, setup phases being `None` on reused connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+  pub dns: Option<Duration>,
+  pub connect: Option<Duration>,
+  pub tls: Option<Duration>,
+  /// Time to the response headers after the connection was set up
+  pub ttfb: Duration,
+  /// Time receiving the body, or sending it with `--upload`
+  pub transfer: Duration,
+}
+
+impl Default for TestResult {
+  fn default() -> Self {
+    TestResult {
+      id: 0,
+      uri: Uri::default(),
+      method: Method::GET,
+      speed: None,
+      bytes: 0,
+      ip: None,
+      tls: None,
+      status: None,
+      latency: None,
+      jitter: None,
+      loss: None,
+      elapsed: None,
+      error: None,
+      weight: 1.0,
+      runs: 1,
+      attempts: 1,
+      samples: Vec::new(),
+      timings: None,
+      final_uri: None,
+      redirects: 0,
+      truncated: false,
+      intervals: Vec::new(),
+      stream_speeds: Vec::new(),
+      bufferbloat: None,
+      ping: None,
+    }
+  }
+}
+
+impl PartialEq for TestResult {
+  fn eq(&self, other: &Self) -> bool {
+    self.speed == other.speed
+  }
+}
+
+impl Eq for TestResult {}
+
+impl PartialOrd for TestResult {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for TestResult {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    // Without speeds, as in latency tests, the lower latency ranks higher
+    self
+      .speed
+      .cmp(&other.speed)
+      .then_with(|| match (self.latency, other.latency) {
+        (Some(latency), Some(other)) => other.cmp(&latency),
+        (latency, other) => latency.is_some().cmp(&other.is_some()),
+      })
+  }
+}
+
+impl TestResult {
+  /// Folds the result of another run of the same URL into this one.
+  pub fn merge(&mut self, other: TestResult) {
+    self.runs += other.runs;
+    self.attempts += other.attempts;
+    self.bytes += other.bytes;
+    self.samples.extend(other.samples);
+    self.speed = (!self.samples.is_empty())
+      .then(|| self.samples.iter().sum::<u64>() / self.samples.len() as u64);
+    self.ip = other.ip.or(self.ip);
+    self.tls = other.tls.or(self.tls);
+    self.status = other.status.or(self.status);
+    self.latency = other.latency.or(self.latency);
+    self.jitter = other.jitter.or(self.jitter);
+    self.loss = other.loss.or(self.loss);
+    self.elapsed = other.elapsed.or(self.elapsed);
+    // Runs which failed still show in `samples`, the URL only failed if all did
+    self.error = match self.succeeded() {
+      true => None,
+      false => other.error.or(self.error.take()),
+    };
+    self.timings = other.timings.or(self.timings);
+    self.final_uri = other.final_uri.or(self.final_uri.take());
+    self.redirects = other.redirects.max(self.redirects);
+    self.truncated |= other.truncated;
+    self.intervals.extend(other.intervals);
+    if !other.stream_speeds.is_empty() {
+      self.stream_speeds = other.stream_speeds;
+    }
+    self.bufferbloat = other.bufferbloat.or(self.bufferbloat);
+    self.ping = other.ping.or(self.ping);
+  }
+
+  pub fn succeeded(&self) -> bool {
+    self.speed.is_some() || self.latency.is_some()
+  }
+
+  /// Time until the response headers: the measured latency in latency mode,
+  /// otherwise the connection setup and TTFB of the last run.
+  pub fn response_time(&self) -> Option<Duration> {
+    self.latency.or_else(|| {
+      let timings = self.timings?;
+      let setup = [timings.dns, timings.connect, timings.tls];
+      Some(setup.into_iter().flatten().sum::<Duration>() + timings.ttfb)
+    })
+  }
+
+  /// Speeds of the successful runs combined as `by` says.
+  pub fn aggregate(&self, by: Aggregate) -> Option<u64> {
+    let mut sorted = self.samples.clone();
+    sorted.sort_unstable();
+    match by {
+      Aggregate::Mean => self.speed,
+      Aggregate::Median => match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[len / 2 - 1] + sorted[len / 2]) / 2),
+        len => Some(sorted[len / 2]),
+      },
+      Aggregate::Min => sorted.first().copied(),
+      Aggregate::Max => sorted.last().copied(),
+    }
+  }
+
+  /// Population standard deviation of the speeds of the successful runs.
+  pub fn stddev(&self) -> Option<u64> {
+    if self.samples.is_empty() {
+      return None;
+    }
+    let len = self.samples.len() as f64;
+    let mean = self.samples.iter().sum::<u64>() as f64 / len;
+    let variance = self
+      .samples
+      .iter()
+      .map(|speed| (*speed as f64 - mean).powi(2))
+      .sum::<f64>()
+      / len;
+    Some(variance.sqrt() as u64)
+  }
+
+  /// Spread of the throughput measured each second, leaving out the last
+  /// second of the transfer as it is usually cut short.
+  pub fn throughput(&self) -> Option<Throughput> {
+    let (_, full) = self.intervals.split_last()?;
+    let min = *full.iter().min()?;
+    let max = *full.iter().max()?;
+    let len = full.len() as f64;
+    let mean = full.iter().sum::<u64>() as f64 / len;
+    let variance = full
+      .iter()
+      .map(|bytes| (*bytes as f64 - mean).powi(2))
+      .sum::<f64>()
+      / len;
+    Some(Throughput {
+      min,
+      max,
+      mean: mean as u64,
+      stddev: variance.sqrt() as u64,
+    })
+  }
+
+  /// Jain's fairness index `(sum x)^2 / (n * sum x^2)` of the speeds of the
+  /// streams, from `1 / n` when one stream got all the bandwidth to 1 when all
+  /// got the same.
+  pub fn jain_index(&self) -> Option<f64> {
+    let speeds = &self.stream_speeds;
+    let sum: f64 = speeds.iter().map(|speed| *speed as f64).sum();
+    let sum_squares: f64 = speeds.iter().map(|speed| (*speed as f64).powi(2)).sum();
+    (speeds.len() > 1 && sum_squares > 0.0).then(|| sum * sum / (speeds.len() as f64 * sum_squares))
+  }
+
+  pub fn samples(&self) -> String {
+    format!("{}/{}", self.samples.len(), self.runs)
+  }
+
+  pub fn speed(&self) -> Cow<'_, str> {
+    output::format_speed(self.speed).into()
+  }
+
+  pub fn ip(&self) -> Cow<'_, str> {
+    match self.ip {
+      Some(ip) => ip.to_string().into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn latency(&self, precision: usize) -> Cow<'_, str> {
+    match self.latency {
+      Some(latency) => output::format_millis(latency, precision).into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn ping(&self, precision: usize) -> Cow<'_, str> {
+    match self.ping {
+      Some(ping) => output::format_millis(ping, precision).into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn jitter(&self, precision: usize) -> Cow<'_, str> {
+    match self.jitter {
+      Some(jitter) => output::format_millis(jitter, precision).into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn loss(&self) -> Cow<'_, str> {
+    match self.loss {
+      Some(loss) => format!("{:.0}%", loss * 100.0).into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn fairness(&self) -> Cow<'_, str> {
+    match self.jain_index() {
+      Some(index) => format!("{index:.3}").into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn elapsed(&self, precision: usize) -> Cow<'_, str> {
+    match self.elapsed {
+      Some(elapsed) => output::format_millis(elapsed, precision).into(),
+      None => "N/A".into(),
+    }
+  }
+
+  pub fn tls(&self) -> Cow<'_, str> {
+    match self.tls {
+      Some(tls) => tls.to_string().into(),
+      None => "N/A".into(),
+    }
+  }
+}
+
+/// How the speeds of several runs of a URL are combined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Aggregate {
+  #[default]
+  Mean,
+  Median,
+  Min,
+  Max,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetryPolicy {
+  pub retries: u32,
+  /// Delay before the first retry, doubled for every further attempt
+  pub delay: Duration,
+  /// Use "full jitter", picking a random delay between zero and the backoff
+  pub jitter: bool,
+}
+
+impl RetryPolicy {
+  /// Backoff before retrying after the zero-based `attempt` failed.
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let backoff = self.delay.saturating_mul(1 << attempt.min(16));
+    if self.jitter {
+      backoff.mul_f64(random_u64() as f64 / u64::MAX as f64)
+    } else {
+      backoff
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TestOptions {
+  /// Repeat the download until the measured window reaches this duration
+  pub min_duration: Option<Duration>,
+  /// Expected media type of the response, parameters like `charset` are ignored
+  pub expect_content_type: Option<String>,
+  pub retry: RetryPolicy,
+  /// Download several small ranges spread through the file instead of the whole body
+  pub range_parts: Option<RangeParts>,
+  pub discard: DiscardStrategy,
+  /// Minimum TLS version the connector was configured with, used for error messages
+  pub min_tls: Option<TlsVersion>,
+  /// Send an OPTIONS request first and skip the test if the method is not allowed
+  pub preflight: bool,
+  /// Don't trust `Content-Length`, showing a spinner instead of a progress bar
+  /// and ending bodies cut short of it without failing
+  pub ignore_content_length: bool,
+  /// Decimals of the milliseconds in printed timings
+  pub time_precision: usize,
+  /// Count body bytes without the progress bar, see `drain`
+  pub fast: bool,
+  /// Records requests and responses for `--har`
+  pub har: Option<har::Recorder>,
+  pub in_flight: Option<InFlight>,
+  /// Warn when a response redirects elsewhere, hinting at a stale URL
+  pub warn_on_redirect: bool,
+  /// Follow up to this many redirects, `None` failing on them instead
+  pub max_redirects: Option<u32>,
+  /// Stop downloading a body after this many bytes
+  pub max_bytes: Option<u64>,
+  /// Stop downloading a body after this long, unlike `max_test_duration` without failing
+  pub sample_duration: Option<Duration>,
+  /// Fail downloads whose average speed is below this many bytes per second
+  /// once the duration passed, without retrying them
+  pub abort_below: Option<(u64, Duration)>,
+  /// Save bodies into this directory instead of discarding them
+  pub output_dir: Option<PathBuf>,
+  /// Receive bodies at most this many bytes per second
+  pub limit_rate: Option<u64>,
+  /// Download each body over this many connections at once, see `measure_streams`
+  pub streams: u32,
+  /// Throwaway downloads before the measured one, to warm up caches on the way
+  pub warmup: u32,
+  /// Leave the start of each transfer out of the speed, see [`SkipFirst`]
+  pub skip_first: Option<SkipFirst>,
+  /// Expected digest of response bodies, overridden by `Target::sha256`
+  pub sha256: Option<Sha256>,
+  /// Status responses must have instead of any 2xx, overridden by `Target::expect_status`
+  pub expect_status: Option<StatusCode>,
+  /// Add a random `_cb` query parameter to every request to get past caches
+  pub cache_bust: bool,
+  /// Time to wait for the response headers
+  pub request_timeout: Duration,
+  /// Time after which a download is stopped
+  pub max_test_duration: Duration,
+  /// Measure how fast request bodies are sent rather than responses received
+  pub upload: bool,
+  /// Only measure the latency of this many requests, see `latency::test`
+  pub latency_samples: Option<u32>,
+  /// Probe the latency before and during transfers, see `bufferbloat::test`
+  pub bufferbloat: bool,
+  /// Started once a body flows, see [`Load`]
+  pub load: Option<Load>,
+  /// Also ping the servers of successful tests over ICMP, see `icmp::ping`
+  pub ping: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DiscardStrategy {
+  /// Drop received chunks right away
+  #[default]
+  Drop,
+  /// Write received chunks to the null device
+  Sink,
+  /// Hash received chunks, simulating client-side processing
+  Hash,
+}
+
+/// Consumes received body chunks according to a [`DiscardStrategy`], or
+/// saves them for `TestOptions::output_dir`.
+enum Discarder {
+  Drop,
+  Sink(File),
+  Hash(DefaultHasher),
+  Save(PathBuf, File),
+}
+
+impl Discarder {
+  fn new(strategy: DiscardStrategy) -> anyhow::Result<Discarder> {
+    Ok(match strategy {
+      DiscardStrategy::Drop => Discarder::Drop,
+      DiscardStrategy::Sink => {
+        let null = if cfg!(windows) { "NUL" } else { "/dev/null" };
+        let file = OpenOptions::new()
+          .write(true)
+          .open(null)
+          .context("Failed to open the null device")?;
+        Discarder::Sink(file)
+      },
+      DiscardStrategy::Hash => Discarder::Hash(DefaultHasher::new()),
+    })
+  }
+
+  /// Saves chunks to `path`, replacing an earlier download.
+  fn save(path: PathBuf) -> anyhow::Result<Discarder> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let file =
+      File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(Discarder::Save(path, file))
+  }
+
+  fn consume(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+    match self {
+      Discarder::Drop => {},
+      Discarder::Sink(file) => file
+        .write_all(chunk)
+        .context("Failed to write to the null device")?,
+      Discarder::Hash(hasher) => {
+        hasher.write(chunk);
+        std::hint::black_box(hasher.finish());
+      },
+      Discarder::Save(path, file) => file
+        .write_all(chunk)
+        .with_context(|| format!("Failed to write {}", path.display()))?,
+    }
+    Ok(())
+  }
+}
+
+/// A [`Discarder`] which also hashes chunks for `TestOptions::sha256` and
+/// notes where `TestOptions::skip_first` ends.
+struct BodySink {
+  discarder: Discarder,
+  hasher: Option<checksum::Hasher>,
+  skip: Option<SkipFirst>,
+  started: Instant,
+  received: u64,
+  /// Bytes received and when, once the skipped part was over
+  skipped: Option<(u64, Instant)>,
+  /// Bytes received in each second since `started`
+  intervals: Vec<u64>,
+}
+
+impl BodySink {
+  fn new(discarder: Discarder, options: &TestOptions) -> BodySink {
+    BodySink {
+      discarder,
+      hasher: options.sha256.map(|_| checksum::Hasher::new()),
+      skip: options.skip_first,
+      started: Instant::now(),
+      received: 0,
+      skipped: None,
+      intervals: Vec::new(),
+    }
+  }
+
+  fn consume(&mut self, chunk: &[u8]) -> anyhow::Result<()> {
+    if let Some(hasher) = &mut self.hasher {
+      hasher.update(chunk);
+    }
+    self.received += chunk.len() as u64;
+    let second = self.started.elapsed().as_secs() as usize;
+    if self.intervals.len() <= second {
+      self.intervals.resize(second + 1, 0);
+    }
+    self.intervals[second] += chunk.len() as u64;
+    let over = match self.skip {
+      Some(SkipFirst::Bytes(bytes)) => self.received >= bytes,
+      Some(SkipFirst::Duration(duration)) => self.started.elapsed() >= duration,
+      None => false,
+    };
+    if over && self.skipped.is_none() {
+      self.skipped = Some((self.received, Instant::now()));
+    }
+    self.discarder.consume(chunk)
+  }
+
+  /// The bytes and time of a transfer without the skipped start.
+  fn measured(
+    &self,
+    bytes: u64,
+    elapsed: Duration,
+    options: &TestOptions,
+    printer: &Printer,
+  ) -> (u64, Duration) {
+    if self.skip.is_none() {
+      return (bytes, elapsed);
+    }
+    let Some((skipped, at)) = self.skipped.filter(|(skipped, _)| *skipped < bytes) else {
+      printer.println(
+        style("Warning: transfer too short to skip its start, counting all of it").yellow(),
+      );
+      return (bytes, elapsed);
+    };
+    let offset = at - self.started;
+    printer.println(format_args!(
+      "Skipped the first {} in {}",
+      humansize::format_size(skipped, humansize::BINARY),
+      output::format_millis(offset, options.time_precision)
+    ));
+    (bytes - skipped, elapsed.saturating_sub(offset))
+  }
+
+  /// The digest of the body, if hashed, and the bytes of each second.
+  fn finish(self) -> (Option<Sha256>, Vec<u64>) {
+    (self.hasher.map(checksum::Hasher::finish), self.intervals)
+  }
+}
+
+/// Fails if the digest of a complete body doesn't match the expected one.
+fn verify(
+  expected: Option<Sha256>,
+  actual: Option<Sha256>,
+  truncated: bool,
+  printer: &Printer,
+) -> anyhow::Result<()> {
+  let (Some(expected), Some(actual)) = (expected, actual) else {
+    return Ok(());
+  };
+  if truncated {
+    printer.println(style("Warning: body was cut off, skipping checksum verification").yellow());
+  } else if expected != actual {
+    bail!("SHA-256 mismatch, expected {expected}, got {actual}");
+  }
+  Ok(())
+}
+
+/// How much of the start of a transfer to leave out of the measurement, as
+/// TCP slow start keeps it from reaching full speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipFirst {
+  Bytes(u64),
+  Duration(Duration),
+}
+
+impl FromStr for SkipFirst {
+  type Err = String;
+
+  /// Durations need a unit, as in `500ms` or `2s`, anything else is a size.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim();
+    let duration = |num: &str, scale: f64| -> Result<SkipFirst, String> {
+      let secs: f64 = num.parse().map_err(|_| format!("invalid duration: {s}"))?;
+      let duration = Duration::try_from_secs_f64(secs * scale).map_err(|err| err.to_string())?;
+      Ok(SkipFirst::Duration(duration))
+    };
+    if let Some(num) = s.strip_suffix("ms") {
+      duration(num, 0.001)
+    } else if let Some(num) = s.strip_suffix('s') {
+      duration(num, 1.0)
+    } else {
+      parse_size(s).map(SkipFirst::Bytes)
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RangeParts {
+  pub count: u64,
+  pub size: u64,
+}
+
+impl FromStr for RangeParts {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (count, size) = s
+      .split_once(':')
+      .ok_or_else(|| format!("expected <N>:<SIZE>, got {s}"))?;
+    let count = count
+      .parse()
+      .ok()
+      .filter(|count| *count > 0)
+      .ok_or_else(|| format!("invalid number of parts: {count}"))?;
+    let size = parse_size(size)?;
+    if size == 0 {
+      return Err("range size must not be zero".to_string());
+    }
+    Ok(RangeParts { count, size })
+  }
+}
+
+struct Measurement {
+  bytes: u64,
+  elapsed: Duration,
+  ip: Option<IpAddr>,
+  tls: Option<TlsInfo>,
+  status: Option<StatusCode>,
+  /// Complete length of the resource from `Content-Range`, if any
+  complete_length: Option<u64>,
+  timings: Option<Timings>,
+  /// Where redirects led, if any were followed
+  final_uri: Option<Uri>,
+  redirects: u32,
+  /// The body was cut off by a [`Limit`]
+  truncated: bool,
+  /// Bytes received in each second of the transfer
+  intervals: Vec<u64>,
+  /// Speeds of the concurrent streams of `TestOptions::streams`, empty for one
+  stream_speeds: Vec<u64>,
+}
+
+async fn test_and_render(
+  client: &TlsHyper,
+  id: usize,
+  request: Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<TestResult> {
+  let uri = request.uri().clone();
+  printer.println(format_args!(
+    "{} {} {}",
+    style("==>").magenta(),
+    style(request.method()).green(),
+    request.uri(),
+  ));
+
+  if options.preflight {
+    preflight(client, &request, options, printer).await?;
+  }
+  for warmup in 1..=options.warmup {
+    printer.println(style(format!("Warmup {warmup}/{}", options.warmup)).cyan());
+    if let Err(err) = download(client, &request, options, printer).await {
+      printer.println(style(format!("Warning: warmup failed: {err:#}")).yellow());
+    }
+  }
+  // Warmups don't count towards the elapsed time
+  let started = Instant::now();
+
+  let mut bytes = 0;
+  let mut window = Duration::ZERO;
+  let mut ip = None;
+  let mut tls = None;
+  let mut status = None;
+  let mut timings = None;
+  let mut final_uri = None;
+  let mut redirects = 0;
+  let mut truncated = false;
+  let mut intervals = Vec::new();
+  let mut stream_speeds;
+  loop {
+    let measurement = download(client, &request, options, printer).await?;
+    bytes += measurement.bytes;
+    window += measurement.elapsed;
+    ip = measurement.ip.or(ip);
+    tls = measurement.tls.or(tls);
+    status = measurement.status.or(status);
+    timings = timings.or(measurement.timings);
+    final_uri = final_uri.or(measurement.final_uri);
+    redirects = redirects.max(measurement.redirects);
+    truncated |= measurement.truncated;
+    intervals.extend(measurement.intervals);
+    stream_speeds = measurement.stream_speeds;
+
+    match options.min_duration {
+      Some(min) if window < min => {
+        printer.println(
+          style(format!(
+            "Measured {} of {}, downloading again...",
+            output::format_millis(window, options.time_precision),
+            output::format_millis(min, options.time_precision)
+          ))
+          .yellow(),
+        );
+      },
+      _ => break,
+    }
+  }
+
+  if intervals.len() > 1 {
+    printer.println(format_args!(
+      "Throughput {}",
+      style(output::sparkline(&intervals)).cyan()
+    ));
+    printer.println("");
+  }
+
+  let speed = (bytes * 1000).checked_div(window.as_millis() as u64);
+
+  Ok(TestResult {
+    id,
+    uri,
+    speed,
+    bytes,
+    ip,
+    tls,
+    status,
+    // Setup phases are those of the first request, while the transfer spans all
+    timings: timings.map(|timings| Timings {
+      transfer: window,
+      ..timings
+    }),
+    final_uri,
+    redirects,
+    truncated,
+    intervals,
+    stream_speeds,
+    elapsed: Some(started.elapsed()),
+    ..Default::default()
+  })
+}
+
+/// Measures a single download of `request`, in parts if options say so.
+async fn download(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<Measurement> {
+  match options.range_parts {
+    Some(parts) => measure_ranges(client, request, options, parts, printer).await,
+    None if options.streams > 1 => measure_streams(client, request, options, printer).await,
+    None => measure(client, clone_request(request), options, printer).await,
+  }
+}
+
+/// Issues an OPTIONS request, failing if the allowed methods exclude the request's.
+async fn preflight(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<()> {
+  let method = request.method().clone();
+  let mut preflight = clone_request(request);
+  *preflight.method_mut() = Method::OPTIONS;
+  *preflight.body_mut() = Body::default();
+  preflight.headers_mut().insert(
+    hyper::header::ACCESS_CONTROL_REQUEST_METHOD,
+    method.as_str().parse().context("Invalid method")?,
+  );
+
+  let resp = tokio::time::timeout(options.request_timeout, client.request(preflight))
+    .await
+    .with_context(|| format!("Preflight timed out for {:?}", options.request_timeout))?
+    .context("Failed to send preflight request")?;
+  let status = resp.status();
+  let allowed: Option<Vec<String>> = [
+    hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
+    hyper::header::ALLOW,
+  ]
+  .iter()
+  .find_map(|name| resp.headers().get(name))
+  .and_then(|val| val.to_str().ok())
+  .map(|val| val.split(',').map(|m| m.trim().to_string()).collect());
+  // Drain the body so the connection can be reused for the actual request.
+  let _ = resp.into_body().collect().await;
+
+  printer.println(format_args!(
+    "{} {} allows {}",
+    style("OPTIONS").cyan(),
+    status,
+    allowed
+      .as_ref()
+      .map_or("N/A".to_string(), |allowed| allowed.join(", ")),
+  ));
+
+  if let Some(allowed) = allowed {
+    let permitted = allowed
+      .iter()
+      .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(method.as_str()));
+    if !permitted {
+      bail!("Preflight does not allow {method}, skipping download");
+    }
+  }
+  Ok(())
+}
+
+/// Measures `parts.count` range requests at offsets spread evenly through the resource.
+async fn measure_ranges(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+  parts: RangeParts,
+  printer: &Printer,
+) -> anyhow::Result<Measurement> {
+  let mut total = Measurement {
+    bytes: 0,
+    elapsed: Duration::ZERO,
+    ip: None,
+    tls: None,
+    status: None,
+    complete_length: None,
+    timings: None,
+    final_uri: None,
+    redirects: 0,
+    truncated: false,
+    intervals: Vec::new(),
+    stream_speeds: Vec::new(),
+  };
+  for part in 0..parts.count {
+    // The first range tells us the complete length, the remaining ones are spread out.
+    let offset = match total.complete_length {
+      Some(length) if parts.count > 1 => {
+        let spread = u128::from(length.saturating_sub(parts.size)) * u128::from(part)
+          / u128::from(parts.count - 1);
+        spread as u64
+      },
+      _ => 0,
+    };
+    // Nothing to request for empty parts, or past the end of the resource
+    let Some(size) = parts.size.checked_sub(1) else {
+      continue;
+    };
+    if total.complete_length.is_some_and(|length| offset >= length) {
+      continue;
+    }
+    // Servers cut ranges ending past the resource short
+    let last = offset.saturating_add(size);
+    let mut request = clone_request(request);
+    let range = format!("bytes={offset}-{last}");
+    request.headers_mut().insert(
+      hyper::header::RANGE,
+      range.parse().context("Failed to build Range header")?,
+    );
+
+    let measurement = measure(client, request, options, printer).await?;
+    total.bytes += measurement.bytes;
+    total.elapsed += measurement.elapsed;
+    total.ip = measurement.ip.or(total.ip);
+    total.tls = measurement.tls.or(total.tls);
+    total.status = measurement.status.or(total.status);
+    total.complete_length = measurement.complete_length.or(total.complete_length);
+    total.timings = total.timings.or(measurement.timings);
+    total.final_uri = total.final_uri.or(measurement.final_uri);
+    total.redirects = total.redirects.max(measurement.redirects);
+    total.truncated |= measurement.truncated;
+    total.intervals.extend(measurement.intervals);
+  }
+  Ok(total)
+}
+
+/// Downloads the body over `options.streams` concurrent requests, each
+/// getting a range of it if the server supports that or a whole copy if not,
+/// measuring their aggregate throughput.
+async fn measure_streams(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<Measurement> {
+  let length = range_length(client, request, options).await;
+  let (streams, ranges) = match length {
+    Some(length) if length >= u64::from(options.streams) => (u64::from(options.streams), true),
+    // Too short to give every stream a byte
+    Some(_) => (1, false),
+    None => {
+      printer.println(
+        style("Server does not support range requests, downloading whole copies").yellow(),
+      );
+      (u64::from(options.streams), false)
+    },
+  };
+  let mut requests = Vec::new();
+  for stream in 0..streams {
+    let mut request = clone_request(request);
+    if let Some(length) = length.filter(|_| ranges) {
+      let range = format!(
+        "bytes={}-{}",
+        length * stream / streams,
+        length * (stream + 1) / streams - 1
+      );
+      request.headers_mut().insert(
+        hyper::header::RANGE,
+        range.parse().context("Failed to build Range header")?,
+      );
+    }
+    requests.push(request);
+  }
+  let printers = printer.streams(requests.len());
+
+  let start = Instant::now();
+  let parts = requests
+    .into_iter()
+    .zip(&printers)
+    .map(|(request, printer)| measure(client, request, options, printer));
+  let measurements = futures::future::join_all(parts).await;
+  let elapsed = start.elapsed();
+  for stream in &printers {
+    stream.flush_to(printer);
+  }
+
+  let mut total = Measurement {
+    bytes: 0,
+    elapsed,
+    ip: None,
+    tls: None,
+    status: None,
+    complete_length: length,
+    timings: None,
+    final_uri: None,
+    redirects: 0,
+    truncated: false,
+    intervals: Vec::new(),
+    stream_speeds: Vec::new(),
+  };
+  for measurement in measurements {
+    let measurement = measurement?;
+    if streams > 1 {
+      let speed = (measurement.bytes * 1000).checked_div(measurement.elapsed.as_millis() as u64);
+      total.stream_speeds.push(speed.unwrap_or_default());
+    }
+    total.bytes += measurement.bytes;
+    total.ip = total.ip.or(measurement.ip);
+    total.tls = total.tls.or(measurement.tls);
+    total.status = total.status.or(measurement.status);
+    total.timings = total.timings.or(measurement.timings);
+    total.final_uri = total.final_uri.or(measurement.final_uri);
+    total.redirects = total.redirects.max(measurement.redirects);
+    total.truncated |= measurement.truncated;
+    // Streams run at once, so their seconds add up
+    if total.intervals.len() < measurement.intervals.len() {
+      total.intervals.resize(measurement.intervals.len(), 0);
+    }
+    for (total, bytes) in total.intervals.iter_mut().zip(measurement.intervals) {
+      *total += bytes;
+    }
+  }
+  printer.println(format_args!(
+    "Received {} over {streams} streams in {}",
+    humansize::format_size(total.bytes, humansize::BINARY),
+    output::format_millis(elapsed, options.time_precision)
+  ));
+  printer.println("");
+  if let Some(timings) = &mut total.timings {
+    timings.transfer = elapsed;
+  }
+  Ok(total)
+}
+
+/// Length of the resource if a HEAD request says the server supports ranges,
+/// following redirects as `measure` would.
+async fn range_length(
+  client: &TlsHyper,
+  request: &Request<Body>,
+  options: &TestOptions,
+) -> Option<u64> {
+  let mut head = clone_request(request);
+  *head.method_mut() = Method::HEAD;
+  *head.body_mut() = Body::default();
+  let mut redirects = 0;
+  let resp = loop {
+    let next = clone_request(&head);
+    let resp = send(client, head, options).await.ok()?;
+    let location = resp
+      .headers()
+      .get(hyper::header::LOCATION)
+      .and_then(|val| val.to_str().ok());
+    match (options.max_redirects, location) {
+      (Some(max), Some(location)) if is_followed(resp.status()) && redirects < max => {
+        head = follow(next, resp.status(), location).ok()?;
+        redirects += 1;
+      },
+      _ => break resp,
+    }
+  };
+  let headers = resp.headers();
+  let ranges = headers
+    .get(hyper::header::ACCEPT_RANGES)
+    .is_some_and(|val| val.as_bytes().eq_ignore_ascii_case(b"bytes"));
+  let length = headers
+    .get(hyper::header::CONTENT_LENGTH)
+    .and_then(|val| val.to_str().ok())
+    .and_then(|val| val.parse().ok());
+  (resp.status().is_success() && ranges)
+    .then_some(length)
+    .flatten()
+}
+
+async fn measure(
+  client: &TlsHyper,
+  mut request: Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<Measurement> {
+  if options.cache_bust {
+    let busted = uri::append_query(request.uri(), "_cb", &format!("{:016x}", random_u64()))?;
+    *request.uri_mut() = busted;
+  }
+  let request_range = request.headers().get(hyper::header::RANGE).cloned();
+  let mut redirects = 0;
+  let (uri, upload, har_request, req_start, elapsed, resp) = loop {
+    let uri = request.uri().clone();
+    let upload = hyper::body::Body::size_hint(request.body())
+      .exact()
+      .filter(|len| *len > 0);
+    let har_request = options.har.as_ref().map(|_| {
+      (
+        SystemTime::now(),
+        request.method().clone(),
+        request.uri().clone(),
+        request.headers().clone(),
+      )
+    });
+    let next = options.max_redirects.map(|_| clone_request(&request));
+    for (name, value) in request.headers() {
+      tracing::debug!("> {name}: {}", String::from_utf8_lossy(value.as_bytes()));
+    }
+    if let Some(load) = options.load.as_ref().filter(|_| upload.is_some()) {
+      // The client doesn't tell when the body starts being sent after connecting
+      load.start();
+    }
+    let req_start = Instant::now();
+    let resp = send(client, request, options).await?;
+    let elapsed = req_start.elapsed();
+
+    printer.println(format_args!(
+      "{:?} {} {}",
+      resp.version(),
+      resp.status(),
+      output::format_millis(elapsed, options.time_precision)
+    ));
+    for (name, value) in resp.headers() {
+      tracing::debug!("< {name}: {}", String::from_utf8_lossy(value.as_bytes()));
+    }
+
+    let location = resp
+      .headers()
+      .get(hyper::header::LOCATION)
+      .and_then(|val| val.to_str().ok());
+    match (options.max_redirects, next, location) {
+      (Some(max), Some(next), Some(location)) if is_followed(resp.status()) => {
+        if redirects >= max {
+          printer.println(style(format!("Warning: stopped after {max} redirects")).yellow());
+          break (uri, upload, har_request, req_start, elapsed, resp);
+        }
+        request = follow(next, resp.status(), location)?;
+        redirects += 1;
+        // Drain the body so the connection can be reused for the next hop.
+        let _ = resp.into_body().collect().await;
+        printer.println(format_args!(
+          "{} {} {}",
+          style("-->").magenta(),
+          style(request.method()).green(),
+          request.uri(),
+        ));
+      },
+      _ => break (uri, upload, har_request, req_start, elapsed, resp),
+    }
+  };
+  let final_uri = (redirects > 0).then(|| uri.clone());
+
+  let ip = resp
+    .extensions()
+    .get::<HttpInfo>()
+    .map(|info| info.remote_addr().ip());
+  let tls = resp.extensions().get::<TlsInfo>().copied();
+  // Reused connections were set up before, for an earlier request
+  let connection = resp
+    .extensions()
+    .get::<ConnectTimings>()
+    .filter(|connection| connection.established >= req_start);
+  let setup = connection.map_or(Duration::ZERO, |connection| {
+    connection.dns.unwrap_or_default() + connection.connect + connection.tls.unwrap_or_default()
+  });
+  let remote = ip.map_or("N/A".to_string(), |ip| ip.to_string());
+  match connection {
+    Some(connection) => {
+      tracing::info!(
+        "Connected to {remote} in {}",
+        output::format_millis(setup, options.time_precision)
+      );
+      let millis = |phase: Option<Duration>| {
+        phase.map_or("N/A".to_string(), |phase| {
+          output::format_millis(phase, options.time_precision)
+        })
+      };
+      tracing::trace!(
+        "DNS {}, connect {}, TLS {}",
+        millis(connection.dns),
+        millis(Some(connection.connect)),
+        millis(connection.tls)
+      );
+      if let Some(tls) = tls {
+        tracing::trace!("Negotiated {tls}");
+      }
+    },
+    None => tracing::info!("Reused connection to {remote}"),
+  }
+  let timings = Timings {
+    dns: connection.and_then(|connection| connection.dns),
+    connect: connection.map(|connection| connection.connect),
+    tls: connection.and_then(|connection| connection.tls),
+    ttfb: elapsed.saturating_sub(setup),
+    transfer: Duration::ZERO,
+  };
+  if let Some(len) = upload {
+    // The body is sent by the time the response arrives, which is the best
+    // the client tells us about the end of the upload.
+    let speed = (len * 1000).checked_div(elapsed.as_millis() as u64);
+    printer.println(format_args!(
+      "Sent {} in {} ({})",
+      humansize::format_size(len, humansize::BINARY),
+      output::format_millis(elapsed, options.time_precision),
+      speed.map_or("N/A".to_string(), |speed| format!(
+        "{}/s",
+        humansize::format_size(speed, humansize::BINARY)
+      ))
+    ));
+  }
+  let mut har =
+    options
+      .har
+      .as_ref()
+      .zip(har_request)
+      .map(|(recorder, (started, method, uri, headers))| {
+        let mut entry = har::Entry::new(started, method, uri, headers, &resp, elapsed);
+        entry.server_ip = ip;
+        entry.request_body_size = upload.unwrap_or_default();
+        recorder.start(entry)
+      });
+
+  let status = resp.status();
+  if options.warn_on_redirect && status.is_redirection() {
+    let location = resp
+      .headers()
+      .get(hyper::header::LOCATION)
+      .and_then(|val| val.to_str().ok());
+    let target = match location.map(|location| uri::resolve(&uri, location)) {
+      Some(Ok(target)) => target.to_string(),
+      Some(Err(_)) => location.unwrap_or_default().to_string(),
+      None => "no Location".to_string(),
+    };
+    printer.println(style(format!("Warning: {uri} redirects ({status}) to {target}")).yellow());
+  }
+  match options.expect_status {
+    Some(expected) if status != expected => bail!(UnexpectedStatus(status, Some(expected))),
+    Some(_) => {},
+    None if !status.is_success() => bail!(UnexpectedStatus(status, None)),
+    None => {},
+  }
+  if options.upload {
+    // Only the reply to the upload is left, which isn't worth measuring
+    let _ = resp.into_body().collect().await;
+    return Ok(Measurement {
+      bytes: upload.unwrap_or_default(),
+      elapsed,
+      ip,
+      tls,
+      status: Some(status),
+      complete_length: None,
+      timings: Some(Timings {
+        transfer: elapsed,
+        ..timings
+      }),
+      final_uri,
+      redirects,
+      truncated: false,
+      intervals: Vec::new(),
+      stream_speeds: Vec::new(),
+    });
+  }
+
+  let is_range = request_range.is_some();
+  if is_range && resp.status() != StatusCode::PARTIAL_CONTENT {
+    bail!("Server does not support range requests")
+  }
+  let complete_length = resp
+    .headers()
+    .get(hyper::header::CONTENT_RANGE)
+    .and_then(|val| val.to_str().ok())
+    .and_then(|val| val.rsplit_once('/'))
+    .and_then(|(_, length)| length.parse().ok());
+
+  if let Some(expected) = &options.expect_content_type {
+    let actual = resp
+      .headers()
+      .get(hyper::header::CONTENT_TYPE)
+      .and_then(|val| val.to_str().ok());
+    let matches = actual.is_some_and(|actual| {
+      let media_type = actual.split(';').next().unwrap_or_default().trim();
+      media_type.eq_ignore_ascii_case(expected.trim())
+    });
+    if !matches {
+      bail!(
+        "Unexpected Content-Type, expected {}, got {}",
+        expected,
+        actual.unwrap_or("none")
+      );
+    }
+  }
+
+  let total: Option<u64> = resp
+    .headers()
+    .get(hyper::header::CONTENT_LENGTH)
+    .filter(|_| !options.ignore_content_length)
+    .and_then(|val| {
+      let str = std::str::from_utf8(val.as_bytes()).ok()?;
+      str.parse().ok()
+    });
+
+  let mut body = resp.into_body().into_data_stream();
+  let discarder = match &options.output_dir {
+    Some(dir) => {
+      // Mirrors tend to share file names, so each host gets a directory
+      let path = dir
+        .join(uri.host().unwrap_or("unknown"))
+        .join(uri::file_name(&uri));
+      printer.println(format_args!("Saving to {}", path.display()));
+      Discarder::save(path)?
+    },
+    None => Discarder::new(options.discard)?,
+  };
+  let mut sink = BodySink::new(discarder, options);
+  let limit = Limit::new(options);
+  // Stopping right at the end of the body doesn't cut anything off
+  let truncated = |stopped: bool, bytes: u64| stopped && total != Some(bytes);
+
+  if options.fast {
+    let (bytes, elapsed, stopped) = drain(
+      body,
+      &mut sink,
+      options.in_flight.as_ref(),
+      options.load.as_ref(),
+      options.max_test_duration,
+      limit,
+    )
+    .await?;
+    if let Some(har) = &mut har {
+      har.entry().receive = elapsed;
+      har.entry().body_size = bytes;
+    }
+    printer.println(format_args!(
+      "Received {} in {}",
+      humansize::format_size(bytes, humansize::BINARY),
+      output::format_millis(elapsed, options.time_precision)
+    ));
+    let truncated = truncated(stopped, bytes);
+    let (bytes, elapsed) = sink.measured(bytes, elapsed, options, printer);
+    printer.println("");
+    let (digest, intervals) = sink.finish();
+    verify(options.sha256, digest, truncated, printer)?;
+    return Ok(Measurement {
+      bytes,
+      elapsed,
+      ip,
+      tls,
+      status: Some(status),
+      complete_length,
+      timings: Some(Timings {
+        transfer: elapsed,
+        ..timings
+      }),
+      final_uri,
+      redirects,
+      truncated,
+      intervals,
+      stream_speeds: Vec::new(),
+    });
+  }
+
+  let (tx, mut rx) = tokio::sync::mpsc::channel::<usize>(1);
+  let in_flight = options.in_flight.clone();
+  let load = options.load.clone();
+  let download = AbortOnDrop(tokio::spawn(async move {
+    let tx = tx;
+    let mut received = 0;
+    loop {
+      let permit = match &in_flight {
+        Some(in_flight) => Some(in_flight.reserve().await?),
+        None => None,
+      };
+      let Some(body) = limit.next(&mut body, received).await? else {
+        break;
+      };
+      let body = limit.truncate(received, body);
+      if let Some(load) = &load {
+        load.start();
+      }
+      let _permit = match (&in_flight, permit) {
+        (Some(in_flight), Some(permit)) => Some(in_flight.fit(permit, body.len()).await?),
+        _ => None,
+      };
+      sink.consume(&body)?;
+      received += body.len() as u64;
+      limit.check_speed(received)?;
+      limit.throttle(received).await;
+      if tx.send(body.len()).await.is_err() {
+        break;
+      }
+      if limit.reached(received) {
+        return Ok((true, sink));
+      }
+    }
+    anyhow::Ok((limit.expired(), sink))
+  }));
+
+  let max_test_duration = options.max_test_duration;
+  let render = AbortOnDrop(tokio::spawn({
+    let printer = printer.clone();
+    async move {
+      let pb = printer
+        .progress_bar(total.map(|total| limit.max_bytes.map_or(total, |max| max.min(total))));
+      const STY_TEMP: &str = "{prefix}{spinner:.green} [{elapsed_precise}] [{bar:.cyan/blue}] {percent}% ({binary_bytes_per_sec}, {eta})";
+      const STY_TEMP_UNKNOWN_LEN: &str =
+        "{prefix}{spinner:.green} [{elapsed_precise}] {binary_bytes} ({binary_bytes_per_sec})";
+      let template = if total.is_some() {
+        STY_TEMP
+      } else {
+        STY_TEMP_UNKNOWN_LEN
+      };
+      pb.set_style(
+        ProgressStyle::with_template(template)
+          .unwrap()
+          .progress_chars("#>-"),
+      );
+      pb.enable_steady_tick(Duration::from_millis(200));
+
+      let update = |len: usize, _immediate: bool| {
+        pb.inc(len as u64);
+      };
+
+      // Also fails bodies which stall, and so send no chunk to check the time with
+      let receive = async {
+        while let Some(len) = rx.recv().await {
+          update(len, false);
+        }
+      };
+      if tokio::time::timeout(max_test_duration, receive)
+        .await
+        .is_err()
+      {
+        // Or the bar of a concurrent test would stay drawn among the others
+        printer.finish_progress(&pb);
+        bail!("Testing takes too long (> {max_test_duration:?}), stopping...");
+      }
+
+      update(0, true);
+      let received = (pb.position(), pb.elapsed());
+      printer.finish_progress(&pb);
+
+      printer.println("");
+      printer.println("");
+
+      Ok(received)
+    }
+  }));
+  // If rendering fails, dropping `download` aborts it so the body stops streaming.
+  let (bytes, elapsed) = render.await.context("Failed to wait render thread")??;
+  let (stopped, sink) = download.await.context("Error when downloading")??;
+  if let Some(har) = &mut har {
+    har.entry().receive = elapsed;
+    har.entry().body_size = bytes;
+  }
+  let truncated = truncated(stopped, bytes);
+  let (bytes, elapsed) = sink.measured(bytes, elapsed, options, printer);
+  let (digest, intervals) = sink.finish();
+  verify(options.sha256, digest, truncated, printer)?;
+
+  Ok(Measurement {
+    bytes,
+    elapsed,
+    ip,
+    tls,
+    status: Some(status),
+    complete_length,
+    timings: Some(Timings {
+      transfer: elapsed,
+      ..timings
+    }),
+    final_uri,
+    redirects,
+    truncated,
+    intervals,
+    stream_speeds: Vec::new(),
+  })
+}
+
+/// Bytes reserved for a chunk before reading it, the most hyper usually
+/// yields at once.
+const RESERVED_CHUNK: usize = 64 * 1024;
+
+/// Caps the bytes of received chunks being processed at once across all
+/// concurrent downloads, so reading stalls and TCP applies backpressure instead
+/// of chunks piling up in memory.
+#[derive(Debug, Clone)]
+pub struct InFlight {
+  semaphore: Arc<Semaphore>,
+  max: u32,
+}
+
+impl InFlight {
+  pub fn new(max: u64) -> InFlight {
+    let max = u32::try_from(max).unwrap_or(u32::MAX).max(1);
+    InFlight {
+      semaphore: Arc::new(Semaphore::new(max as usize)),
+      max,
+    }
+  }
+
+  /// Waits until `len` more bytes fit, chunks larger than the cap take all of it.
+  pub async fn acquire(&self, len: usize) -> anyhow::Result<SemaphorePermit<'_>> {
+    let permits = u32::try_from(len).unwrap_or(u32::MAX).min(self.max);
+    self
+      .semaphore
+      .acquire_many(permits)
+      .await
+      .context("In-flight bytes limiter closed")
+  }
+
+  /// Waits until a typical chunk fits before reading the next one, so a body
+  /// stays unread in the socket rather than buffered past the cap.
+  pub async fn reserve(&self) -> anyhow::Result<SemaphorePermit<'_>> {
+    self.acquire(RESERVED_CHUNK).await
+  }
+
+  /// Resizes `permit` of [`InFlight::reserve`] to the `len` bytes of the
+  /// chunk read with it, giving back the rest.
+  pub async fn fit<'a>(
+    &'a self,
+    mut permit: SemaphorePermit<'a>,
+    len: usize,
+  ) -> anyhow::Result<SemaphorePermit<'a>> {
+    let len = u32::try_from(len).unwrap_or(u32::MAX).min(self.max) as usize;
+    let held = permit.num_permits();
+    if len <= held {
+      drop(permit.split(held - len));
+      return Ok(permit);
+    }
+    // Waiting for more while holding some could deadlock with other downloads
+    drop(permit);
+    self.acquire(len).await
+  }
+}
+
+/// Reads the body in a tight loop without reporting progress, for links fast
+/// enough that passing every chunk to the progress bar would be the bottleneck.
+async fn drain(
+  mut body: BodyDataStream<Incoming>,
+  sink: &mut BodySink,
+  in_flight: Option<&InFlight>,
+  load: Option<&Load>,
+  max_test_duration: Duration,
+  limit: Limit,
+) -> anyhow::Result<(u64, Duration, bool)> {
+  let start = Instant::now();
+  let mut bytes = 0;
+  let mut stopped = false;
+  tokio::time::timeout(max_test_duration, async {
+    loop {
+      let permit = match in_flight {
+        Some(in_flight) => Some(in_flight.reserve().await?),
+        None => None,
+      };
+      let Some(chunk) = limit.next(&mut body, bytes).await? else {
+        break;
+      };
+      let chunk = limit.truncate(bytes, chunk);
+      if let Some(load) = load {
+        load.start();
+      }
+      let _permit = match (in_flight, permit) {
+        (Some(in_flight), Some(permit)) => Some(in_flight.fit(permit, chunk.len()).await?),
+        _ => None,
+      };
+      sink.consume(&chunk)?;
+      bytes += chunk.len() as u64;
+      limit.check_speed(bytes)?;
+      limit.throttle(bytes).await;
+      if limit.reached(bytes) {
+        stopped = true;
+        break;
+      }
+    }
+    stopped |= limit.expired();
+    anyhow::Ok(())
+  })
+  .await
+  .with_context(|| format!("Testing takes too long (> {max_test_duration:?}), stopping..."))??;
+  Ok((bytes, start.elapsed(), stopped))
+}
+
+/// How often a body waiting for its next chunk is checked against `abort_below`.
+const SPEED_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How much of a body to download and how fast, see [`TestOptions::max_bytes`],
+/// [`TestOptions::sample_duration`], [`TestOptions::limit_rate`] and
+/// [`TestOptions::abort_below`].
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+  max_bytes: Option<u64>,
+  deadline: Option<tokio::time::Instant>,
+  rate: Option<u64>,
+  abort_below: Option<(u64, Duration)>,
+  /// End the body where the connection does, see `TestOptions::ignore_content_length`
+  lenient: bool,
+  started: tokio::time::Instant,
+}
+
+impl Limit {
+  fn new(options: &TestOptions) -> Limit {
+    let started = tokio::time::Instant::now();
+    Limit {
+      max_bytes: options.max_bytes,
+      deadline: options.sample_duration.map(|duration| started + duration),
+      rate: options.limit_rate.filter(|rate| *rate > 0),
+      abort_below: options.abort_below,
+      lenient: options.ignore_content_length,
+      started,
+    }
+  }
+
+  /// Waits until receiving `bytes` so far keeps to the rate limit. Not reading
+  /// meanwhile makes TCP slow the sender down, as a slower link would.
+  async fn throttle(&self, bytes: u64) {
+    let Some(rate) = self.rate else {
+      return;
+    };
+    // Sleeping past the deadline keeps the rate, `next` stops after it
+    let due = self.started + Duration::from_secs_f64(bytes as f64 / rate as f64);
+    tokio::time::sleep_until(due).await;
+  }
+
+  /// The next chunk of `body`, or `None` once the deadline passed, even if
+  /// chunks are already buffered. A body cut short of its `Content-Length`
+  /// ends there when lenient. While waiting, the speed of the `bytes` received
+  /// so far is checked against `abort_below` as time passes, so a stalled body
+  /// fails too.
+  async fn next(
+    &self,
+    body: &mut BodyDataStream<Incoming>,
+    bytes: u64,
+  ) -> anyhow::Result<Option<Bytes>> {
+    if self.expired() {
+      return Ok(None);
+    }
+    let read = async {
+      match self.deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, body.next())
+          .await
+          .ok()
+          .flatten(),
+        None => body.next().await,
+      }
+    };
+    tokio::pin!(read);
+    let next = match self.abort_below {
+      Some((_, after)) => {
+        let mut check = tokio::time::interval_at(self.started + after, SPEED_CHECK_INTERVAL);
+        check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+          tokio::select! {
+            next = &mut read => break next,
+            _ = check.tick() => self.check_speed(bytes)?,
+          }
+        }
+      },
+      None => read.await,
+    };
+    match next {
+      Some(Ok(chunk)) => Ok(Some(chunk)),
+      Some(Err(err)) if self.lenient && is_cut_short(&err) => Ok(None),
+      Some(Err(err)) => Err(err).context("Failed to read response body"),
+      None => Ok(None),
+    }
+  }
+
+  /// Fails if receiving `bytes` so far is too slow for `abort_below`.
+  fn check_speed(&self, bytes: u64) -> anyhow::Result<()> {
+    let Some((min, after)) = self.abort_below else {
+      return Ok(());
+    };
+    let elapsed = self.started.elapsed();
+    if elapsed < after {
+      return Ok(());
+    }
+    let speed = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+    if speed < min {
+      bail!(BelowThreshold { speed, min, after });
+    }
+    Ok(())
+  }
+
+  /// The part of `chunk` within `max_bytes`, after `bytes` received before it.
+  fn truncate(&self, bytes: u64, chunk: Bytes) -> Bytes {
+    match self.max_bytes {
+      Some(max) if bytes + chunk.len() as u64 > max => {
+        chunk.slice(..max.saturating_sub(bytes) as usize)
+      },
+      _ => chunk,
+    }
+  }
+
+  fn reached(&self, bytes: u64) -> bool {
+    self.max_bytes.is_some_and(|max| bytes >= max)
+  }
+
+  fn expired(&self) -> bool {
+    self
+      .deadline
+      .is_some_and(|deadline| tokio::time::Instant::now() >= deadline)
+  }
+}
+
+/// Whether the connection closed before the body reached its `Content-Length`.
+fn is_cut_short(err: &hyper::Error) -> bool {
+  err.is_incomplete_message()
+    || std::error::Error::source(err)
+      .and_then(|source| source.downcast_ref::<std::io::Error>())
+      .is_some_and(|err| err.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+async fn send(
+  client: &TlsHyper,
+  request: Request<Body>,
+  options: &TestOptions,
+) -> anyhow::Result<Response<Incoming>> {
+  let resp = tokio::time::timeout(options.request_timeout, async move {
+    client.request(request).await
+  })
+  .await
+  .with_context(|| format!("Timed out for {:?}", options.request_timeout))?
+  .map_err(|err| {
+    let tls_error = tls::find_tls_error(&err)
+      .filter(|err| tls::is_version_mismatch(err))
+      .map(|err| err.to_string());
+    let err = anyhow::Error::new(err).context("Failed to send request");
+    match (tls_error, options.min_tls) {
+      (Some(tls_error), Some(min)) => err.context(format!(
+        "TLS handshake failed ({tls_error}), the server may not support {min} or later"
+      )),
+      _ => err,
+    }
+  })?;
+  Ok(resp)
+}
+
+/// Redirect statuses with a `Location` worth following, unlike 300 or 304.
+fn is_followed(status: StatusCode) -> bool {
+  matches!(
+    status,
+    StatusCode::MOVED_PERMANENTLY
+      | StatusCode::FOUND
+      | StatusCode::SEE_OTHER
+      | StatusCode::TEMPORARY_REDIRECT
+      | StatusCode::PERMANENT_REDIRECT
+  )
+}
+
+/// Turns `request` into the one following a redirect to `location`. Like
+/// browsers, 303 and a 301 or 302 after a POST switch to a GET without a body,
+/// and credentials aren't sent on to another host.
+fn follow(
+  mut request: Request<Body>,
+  status: StatusCode,
+  location: &str,
+) -> anyhow::Result<Request<Body>> {
+  let target = uri::resolve(request.uri(), location)
+    .with_context(|| format!("Invalid redirect location {location}"))?;
+  let method = request.method();
+  let to_get = (status == StatusCode::SEE_OTHER && method != Method::HEAD)
+    || (matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND)
+      && method == Method::POST);
+  if to_get {
+    *request.method_mut() = Method::GET;
+    *request.body_mut() = Body::default();
+    let headers = request.headers_mut();
+    headers.remove(hyper::header::CONTENT_TYPE);
+    headers.remove(hyper::header::CONTENT_LENGTH);
+  }
+  let same_origin =
+    target.scheme() == request.uri().scheme() && target.authority() == request.uri().authority();
+  if !same_origin {
+    let headers = request.headers_mut();
+    headers.remove(hyper::header::AUTHORIZATION);
+    headers.remove(hyper::header::COOKIE);
+  }
+  *request.uri_mut() = target;
+  Ok(request)
+}
+
+/// The server answered with a status other than 2xx, or than the expected one.
+#[derive(Debug)]
+struct UnexpectedStatus(StatusCode, Option<StatusCode>);
+
+impl std::fmt::Display for UnexpectedStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.1 {
+      Some(expected) => write!(f, "HTTP response status {} is not {expected}", self.0),
+      None => write!(f, "HTTP response status {} is not success", self.0),
+    }
+  }
+}
+
+impl std::error::Error for UnexpectedStatus {}
+
+/// A download was aborted for being slower than `TestOptions::abort_below`.
+#[derive(Debug)]
+struct BelowThreshold {
+  speed: u64,
+  min: u64,
+  after: Duration,
+}
+
+impl std::fmt::Display for BelowThreshold {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Below threshold: {}/s after {:?}, aborting below {}/s",
+      humansize::format_size(self.speed, humansize::BINARY),
+      self.after,
+      humansize::format_size(self.min, humansize::BINARY)
+    )
+  }
+}
+
+impl std::error::Error for BelowThreshold {}
+
+/// A [`JoinHandle`] that aborts its task when dropped, so a test that is
+/// cancelled or fails half-way does not leave background work running.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+  type Output = Result<T, JoinError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+    Pin::new(&mut self.0).poll(cx)
+  }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+fn clone_request(request: &Request<Body>) -> Request<Body> {
+  let mut cloned = Request::new(request.body().clone());
+  *cloned.method_mut() = request.method().clone();
+  *cloned.uri_mut() = request.uri().clone();
+  *cloned.version_mut() = request.version();
+  *cloned.headers_mut() = request.headers().clone();
+  cloned
+}
+
+/// Time for requests of [`fetch`] by default.
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends `request` and reads its whole body within `timeout`, for the API
+/// calls and server lists around tests rather than for measuring.
+pub async fn fetch(
+  client: &TlsHyper,
+  request: Request<Body>,
+  timeout: Duration,
+) -> anyhow::Result<Response<Bytes>> {
+  let method = request.method().clone();
+  let uri = request.uri().clone();
+  tokio::time::timeout(timeout, async {
+    let resp = client
+      .request(request)
+      .await
+      .context("Failed to send request")?;
+    let (parts, body) = resp.into_parts();
+    let body = body
+      .collect()
+      .await
+      .context("Failed to read response body")?;
+    anyhow::Ok(Response::from_parts(parts, body.to_bytes()))
+  })
+  .await
+  .with_context(|| format!("Timed out for {timeout:?}"))
+  .and_then(|resp| resp)
+  .with_context(|| format!("Failed to {method} {uri}"))
+}
+
+/// The body of `url` as text, failing unless it responds with a success.
+pub async fn fetch_text(client: &TlsHyper, url: &str, timeout: Duration) -> anyhow::Result<String> {
+  let request = Request::get(url)
+    .body(Body::default())
+    .context("Failed to build request")?;
+  let resp = fetch(client, request, timeout).await?;
+  if !resp.status().is_success() {
+    return Err(anyhow::anyhow!("Server responded with {}", resp.status()))
+      .with_context(|| format!("Failed to GET {url}"));
+  }
+  String::from_utf8(resp.into_body().to_vec())
+    .with_context(|| format!("Response of {url} is not UTF-8"))
+}
+
+/// A random number from the std hasher seeds, good enough for jitter and the like.
+fn random_u64() -> u64 {
+  RandomState::new().build_hasher().finish()
+}
+
+/// Parses seconds like `2.5`, optionally with a unit as in `15s`, `500ms` or `1m`.
+pub fn parse_seconds(s: &str) -> Result<Duration, String> {
+  let (num, scale) = if let Some(num) = s.strip_suffix("ms") {
+    (num, 0.001)
+  } else if let Some(num) = s.strip_suffix('s') {
+    (num, 1.0)
+  } else if let Some(num) = s.strip_suffix('m') {
+    (num, 60.0)
+  } else {
+    (s, 1.0)
+  };
+  let secs: f64 = num
+    .trim()
+    .parse()
+    .map_err(|_| format!("invalid number of seconds: {s}"))?;
+  Duration::try_from_secs_f64(secs * scale).map_err(|err| err.to_string())
+}
+
+/// Parses sizes like `512`, `64K`, `1.5MiB` or `2G`, all units being binary.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+  let s = s.trim();
+  let split = s
+    .find(|c: char| !c.is_ascii_digit() && c != '.')
+    .unwrap_or(s.len());
+  let (num, unit) = s.split_at(split);
+  let num: f64 = num.parse().map_err(|_| format!("invalid size: {s}"))?;
+  let unit = unit.trim().to_ascii_lowercase();
+  let multiplier: u64 = match unit.trim_end_matches('b').trim_end_matches('i') {
+    "" => 1,
+    "k" => 1 << 10,
+    "m" => 1 << 20,
+    "g" => 1 << 30,
+    "t" => 1 << 40,
+    _ => return Err(format!("invalid size unit: {unit}")),
+  };
+  Ok((num * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use super::*;
+
+  /// Serves one response announcing more body than it sends, then closes the
+  /// connection or with `stall` keeps it open.
+  async fn lying_server(stall: bool) -> Uri {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buf = [0; 1024];
+      let _ = stream.read(&mut buf).await;
+      let response = "HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n0123456789";
+      stream.write_all(response.as_bytes()).await.unwrap();
+      if stall {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+      }
+    });
+    Uri::from_str(&format!("http://{addr}/")).unwrap()
+  }
+
+  async fn test_lying_server(options: TestOptions, stall: bool) -> TestResult {
+    let tester = SpeedTester::new(Clients::with_defaults().unwrap(), options);
+    let request = Request::get(lying_server(stall).await)
+      .body(Body::default())
+      .unwrap();
+    tester.test(request).await
+  }
+
+  fn options() -> TestOptions {
+    TestOptions {
+      request_timeout: Duration::from_secs(5),
+      max_test_duration: Duration::from_secs(5),
+      fast: true,
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn short_body_fails() {
+    let result = test_lying_server(options(), false).await;
+    assert!(result.error.is_some());
+  }
+
+  #[tokio::test]
+  async fn short_body_ends_with_ignore_content_length() {
+    for fast in [true, false] {
+      let result = test_lying_server(
+        TestOptions {
+          ignore_content_length: true,
+          fast,
+          ..options()
+        },
+        false,
+      )
+      .await;
+      assert_eq!(result.error, None);
+      assert_eq!(result.bytes, 10);
+    }
+  }
+
+  #[tokio::test]
+  async fn stalled_body_aborts() {
+    for fast in [true, false] {
+      let started = Instant::now();
+      let result = test_lying_server(
+        TestOptions {
+          abort_below: Some((1024, Duration::from_millis(200))),
+          fast,
+          ..options()
+        },
+        true,
+      )
+      .await;
+      let error = result.error.unwrap();
+      assert!(error.contains("Below threshold"), "{error}");
+      assert!(started.elapsed() < Duration::from_secs(2));
+    }
+  }
+
+  #[test]
+  fn fairness_of_streams() {
+    let result = |stream_speeds: Vec<u64>| TestResult {
+      stream_speeds,
+      ..Default::default()
+    };
+    assert_eq!(result(vec![]).jain_index(), None);
+    assert_eq!(result(vec![100]).jain_index(), None);
+    assert_eq!(result(vec![100, 100, 100]).jain_index(), Some(1.0));
+    assert_eq!(result(vec![100, 0, 0, 0]).jain_index(), Some(0.25));
+  }
+
+  #[tokio::test]
+  async fn stalled_body_exceeds_max_test_duration() {
+    for fast in [true, false] {
+      let started = Instant::now();
+      let result = test_lying_server(
+        TestOptions {
+          max_test_duration: Duration::from_millis(300),
+          fast,
+          ..options()
+        },
+        true,
+      )
+      .await;
+      let error = result.error.unwrap();
+      assert!(error.contains("Testing takes too long"), "{error}");
+      assert!(started.elapsed() < Duration::from_secs(2));
+    }
+  }
+
+  #[test]
+  fn merge_keeps_errors_only_without_samples() {
+    let failed = || TestResult {
+      runs: 1,
+      error: Some("Failed".to_string()),
+      ..Default::default()
+    };
+    let succeeded = || TestResult {
+      runs: 1,
+      speed: Some(100),
+      samples: vec![100],
+      ..Default::default()
+    };
+
+    let mut result = failed();
+    result.merge(succeeded());
+    result.merge(succeeded());
+    assert_eq!(result.error, None);
+    assert_eq!(result.samples(), "2/3");
+
+    let mut result = succeeded();
+    result.merge(failed());
+    assert_eq!(result.error, None);
+
+    let mut result = failed();
+    result.merge(failed());
+    assert_eq!(result.error.as_deref(), Some("Failed"));
+    assert!(!result.succeeded());
+  }
+}
@@ -0,0 +1,82 @@
+use std::{str::FromStr, time::Duration};
+
+use clap::Args;
+use console::style;
+use hyper::{
+  header::{self, HeaderValue},
+  Method,
+};
+
+use spt::{
+  body::{Body, Payload, PayloadKind},
+  fetch_text,
+  json::Json,
+  parse_size, printer, Measure, Target, TlsHyper,
+};
+
+use crate::request_targets;
+
+/// A server speaking the LibreSpeed protocol, run as its web client does.
+#[derive(Args)]
+pub struct LibrespeedArgs {
+  /// URL of the directory of the server's `garbage.php`, `empty.php` and
+  /// `getIP.php`, e.g. `https://librespeed.example/backend`
+  server: String,
+  /// Mebibytes the server is asked to send for the download
+  #[clap(long, value_name = "MiB", default_value_t = 100, value_parser = clap::value_parser!(u32).range(1..=1024))]
+  download_size: u32,
+  /// Bytes to upload, e.g. `20M`
+  #[clap(long, value_name = "SIZE", value_parser = parse_size, default_value = "20M")]
+  upload_size: u64,
+  /// Number of requests to measure the ping with
+  #[clap(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+  pings: u32,
+}
+
+impl LibrespeedArgs {
+  /// Targets of the download, upload and ping phases, logging the client IP
+  /// the server sees first.
+  pub async fn targets(&self, client: &TlsHyper) -> anyhow::Result<Vec<Target>> {
+    let server = self.server.trim_end_matches('/');
+    match client_ip(client, server).await {
+      Ok(ip) => printer::log(format_args!("Testing {server} from {ip}")),
+      Err(err) => printer::log(style(format!("Failed to get client IP: {err:#}")).yellow()),
+    }
+
+    let mut targets = Vec::new();
+    let download = format!("{server}/garbage.php?ckSize={}", self.download_size);
+    for mut target in request_targets(Method::GET, &download, &[])? {
+      target.measure = Some(Measure::Download);
+      targets.push(target);
+    }
+    for mut target in request_targets(Method::POST, &format!("{server}/empty.php"), &[])? {
+      target.measure = Some(Measure::Upload);
+      *target.request.body_mut() =
+        Body::Generated(Payload::new(self.upload_size, PayloadKind::Random));
+      target.request.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+      );
+      targets.push(target);
+    }
+    for mut target in request_targets(Method::GET, &format!("{server}/empty.php"), &[])? {
+      target.measure = Some(Measure::Latency(self.pings));
+      targets.push(target);
+    }
+    Ok(targets)
+  }
+}
+
+/// IP address of this machine as the server sees it, with the ISP if the
+/// server knows it.
+async fn client_ip(client: &TlsHyper, server: &str) -> anyhow::Result<String> {
+  let url = format!("{server}/getIP.php?isp=true");
+  let body = fetch_text(client, &url, Duration::from_secs(10)).await?;
+  // Older servers answer with just the address
+  Ok(
+    Json::from_str(&body)
+      .ok()
+      .and_then(|json| Some(json.get("processedString")?.as_str()?.to_string()))
+      .unwrap_or_else(|| body.trim().to_string()),
+  )
+}
@@ -0,0 +1,190 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context};
+use clap::{ArgGroup, Args, Subcommand, ValueEnum};
+use hyper::Method;
+
+use console::style;
+use spt::{
+  apt, fetch_text,
+  output::OutputFormat,
+  pacman, printer,
+  registry::{self, ImageRef},
+  Target, TlsHyper, FETCH_TIMEOUT,
+};
+
+use crate::request_targets;
+
+const UBUNTU_MIRRORS: &str = "http://mirrors.ubuntu.com/mirrors.txt";
+const DEBIAN_MASTERLIST: &str =
+  "https://salsa.debian.org/mirror-team/masterlist/-/raw/master/Mirrors.masterlist";
+
+/// Package mirrors to rank, whose URLs are built from their base URLs.
+#[derive(Subcommand)]
+pub enum Provider {
+  /// Debian or Ubuntu APT mirrors, written out as a sources.list snippet
+  Apt(AptArgs),
+  /// Arch Linux mirrors from the mirror status of archlinux.org, written out
+  /// as a pacman mirrorlist
+  Arch(ArchArgs),
+  /// Docker or OCI registries and their pull-through caches, downloading
+  /// the largest layer of an image from each
+  Registry(RegistryArgs),
+}
+
+#[derive(Args)]
+#[clap(group = ArgGroup::new("mirror-list").args(&["mirrors", "list", "official"]).required(true))]
+pub struct AptArgs {
+  /// Base URLs of the mirrors, e.g. `http://deb.debian.org/debian`
+  mirrors: Vec<String>,
+  /// Read base URLs from a file, one per line
+  #[clap(long, value_name = "PATH")]
+  list: Option<PathBuf>,
+  /// Test the mirrors of the official mirror list of a distribution, for
+  /// Ubuntu those near this machine's location
+  #[clap(long, value_enum, value_name = "DISTRIBUTION")]
+  official: Option<Distribution>,
+  /// Suite of the sources.list lines, by default the codename of this machine
+  #[clap(long)]
+  suite: Option<String>,
+  /// Components of the sources.list lines
+  #[clap(long, value_delimiter = ',', default_value = "main")]
+  components: Vec<String>,
+  /// File below each base URL to download
+  #[clap(long, value_name = "PATH", default_value = apt::TEST_PATH)]
+  path: String,
+}
+
+#[derive(Args)]
+pub struct ArchArgs {
+  /// Only test mirrors in these countries, given as codes like `DE`
+  #[clap(long, value_name = "CODE", value_delimiter = ',')]
+  country: Vec<String>,
+  /// Only test mirrors serving these protocols, by default http and https
+  #[clap(long, value_name = "PROTOCOL", value_delimiter = ',')]
+  protocol: Vec<String>,
+  /// Only test the N mirrors archlinux.org scored best
+  #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+  limit: Option<u32>,
+  /// Where to get the mirror status JSON from
+  #[clap(long, value_name = "URL", default_value = pacman::MIRROR_STATUS)]
+  status_url: String,
+}
+
+#[derive(Args)]
+pub struct RegistryArgs {
+  /// Image to pull, e.g. `ubuntu:24.04` or `ghcr.io/owner/app:latest`
+  image: ImageRef,
+  /// Registries to compare like `mirror.gcr.io` or `http://localhost:5000`,
+  /// by default the one of the image
+  hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Distribution {
+  Debian,
+  Ubuntu,
+}
+
+impl Provider {
+  /// Format the results are written in unless `--format` says otherwise.
+  pub fn output_format(&self) -> OutputFormat {
+    match self {
+      Provider::Apt(_) => OutputFormat::SourcesList,
+      Provider::Arch(_) => OutputFormat::PacmanMirrorlist,
+      Provider::Registry(_) => OutputFormat::Table,
+    }
+  }
+
+  /// Redirects to follow unless `--follow-redirects` says otherwise.
+  pub fn max_redirects(&self) -> Option<u32> {
+    match self {
+      // Registries tend to redirect blob downloads to a CDN
+      Provider::Registry(_) => Some(5),
+      Provider::Apt(_) | Provider::Arch(_) => None,
+    }
+  }
+
+  pub fn apt_release(&self) -> Option<apt::Release> {
+    match self {
+      Provider::Apt(args) => {
+        let default = apt::Release::default();
+        Some(apt::Release {
+          suite: args.suite.clone().unwrap_or(default.suite),
+          components: args.components.clone(),
+          test_path: args.path.trim_start_matches('/').to_string(),
+        })
+      },
+      Provider::Arch(_) | Provider::Registry(_) => None,
+    }
+  }
+
+  /// Targets for the mirrors, fetching mirror lists with `client`.
+  pub async fn targets(&self, client: &TlsHyper) -> anyhow::Result<Vec<Target>> {
+    let urls: Vec<String> = match self {
+      Provider::Apt(args) => {
+        let mut bases = args.mirrors.clone();
+        if let Some(path) = &args.list {
+          let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mirror list {}", path.display()))?;
+          bases.extend(
+            content
+              .lines()
+              .map(str::trim)
+              .filter(|line| !line.is_empty() && !line.starts_with('#'))
+              .map(str::to_string),
+          );
+        }
+        match args.official {
+          Some(Distribution::Ubuntu) => bases.extend(apt::parse_ubuntu_mirrors(
+            &fetch_text(client, UBUNTU_MIRRORS, FETCH_TIMEOUT).await?,
+          )),
+          Some(Distribution::Debian) => bases.extend(apt::parse_debian_masterlist(
+            &fetch_text(client, DEBIAN_MASTERLIST, FETCH_TIMEOUT).await?,
+          )),
+          None => {},
+        }
+        let release = self.apt_release().unwrap_or_default();
+        bases.iter().map(|base| release.test_url(base)).collect()
+      },
+      Provider::Arch(args) => {
+        let filter = pacman::StatusFilter {
+          countries: args.country.clone(),
+          protocols: args.protocol.clone(),
+          limit: args.limit.map(|limit| limit as usize),
+        };
+        let status = fetch_text(client, &args.status_url, FETCH_TIMEOUT).await?;
+        let servers = pacman::parse_mirror_status(&status, &filter)?;
+        servers
+          .iter()
+          .map(|server| pacman::test_url(server))
+          .collect()
+      },
+      Provider::Registry(args) => {
+        let hosts = match args.hosts.is_empty() {
+          true => vec![args.image.default_registry().to_string()],
+          false => args.hosts.clone(),
+        };
+        let mut targets = Vec::new();
+        for host in hosts {
+          match registry::blob_target(client, &host, &args.image).await {
+            Ok(target) => targets.push(target),
+            Err(err) => printer::log(style(format!("Skipping {host}: {err:#}")).yellow()),
+          }
+        }
+        if targets.is_empty() {
+          bail!("No registry to test");
+        }
+        return Ok(targets);
+      },
+    };
+    if urls.is_empty() {
+      bail!("No mirrors to test");
+    }
+    let mut targets = Vec::new();
+    for url in urls {
+      targets.extend(request_targets(Method::GET, &url, &[])?);
+    }
+    Ok(targets)
+  }
+}
@@ -0,0 +1,169 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+  pub login: String,
+  pub password: String,
+}
+
+/// Credentials parsed from a `.netrc` file, in the format understood by curl and ftp.
+#[derive(Debug, Default)]
+pub struct Netrc {
+  machines: Vec<(String, Credentials)>,
+  default: Option<Credentials>,
+}
+
+impl Netrc {
+  pub fn from_path(path: &Path) -> anyhow::Result<Netrc> {
+    let content = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read netrc file {}", path.display()))?;
+    Netrc::parse(&content).with_context(|| format!("Unable to parse netrc file {}", path.display()))
+  }
+
+  /// The netrc file of the current user, `~/.netrc` (`~/_netrc` on Windows),
+  /// overridden by the `NETRC` environment variable.
+  pub fn default_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("NETRC") {
+      return Some(path.into());
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+    Some(Path::new(&home).join(name))
+  }
+
+  pub fn parse(content: &str) -> anyhow::Result<Netrc> {
+    let mut netrc = Netrc::default();
+    // The entry being read, `None` as machine name for `default`
+    let mut entry: Option<(Option<String>, Credentials)> = None;
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+      let mut tokens = line.split_whitespace();
+      while let Some(token) = tokens.next() {
+        if token.starts_with('#') {
+          break;
+        }
+        let mut value = |key: &str| {
+          tokens
+            .next()
+            .map(str::to_string)
+            .with_context(|| format!("Missing value for `{key}`"))
+        };
+        match token {
+          "machine" => {
+            netrc.push(entry.take());
+            entry = Some((Some(value(token)?), Credentials::default()));
+          },
+          "default" => {
+            netrc.push(entry.take());
+            entry = Some((None, Credentials::default()));
+          },
+          "login" | "password" | "account" => {
+            let value = value(token)?;
+            let Some((_, credentials)) = &mut entry else {
+              bail!("`{token}` outside of a machine entry");
+            };
+            match token {
+              "login" => credentials.login = value,
+              "password" => credentials.password = value,
+              _ => {},
+            }
+          },
+          "macdef" => {
+            // Macro definitions run until the next empty line
+            for line in lines.by_ref() {
+              if line.trim().is_empty() {
+                break;
+              }
+            }
+            break;
+          },
+          token => bail!("Unknown netrc token `{token}`"),
+        }
+      }
+    }
+    netrc.push(entry);
+    Ok(netrc)
+  }
+
+  fn push(&mut self, entry: Option<(Option<String>, Credentials)>) {
+    match entry {
+      Some((Some(machine), credentials)) => self.machines.push((machine, credentials)),
+      Some((None, credentials)) => self.default = Some(credentials),
+      None => {},
+    }
+  }
+
+  /// Credentials of the first entry matching `host`, falling back to `default`.
+  pub fn lookup(&self, host: &str) -> Option<&Credentials> {
+    self
+      .machines
+      .iter()
+      .find(|(machine, _)| machine.eq_ignore_ascii_case(host))
+      .map(|(_, credentials)| credentials)
+      .or(self.default.as_ref())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn credentials(login: &str, password: &str) -> Credentials {
+    Credentials {
+      login: login.to_string(),
+      password: password.to_string(),
+    }
+  }
+
+  #[test]
+  fn parses_entries() {
+    let netrc = Netrc::parse(
+      "# Comment\n\
+       machine example.com login alice password secret\n\
+       machine other.example\n  login bob # trailing comment\n  password hunter2 account x\n\
+       macdef init\ncd /pub\nmachine ignored.example login eve\n\n\
+       default login anonymous password guest\n",
+    )
+    .unwrap();
+    assert_eq!(
+      netrc.lookup("EXAMPLE.com"),
+      Some(&credentials("alice", "secret"))
+    );
+    assert_eq!(
+      netrc.lookup("other.example"),
+      Some(&credentials("bob", "hunter2"))
+    );
+    // Lines of a macro are not entries
+    assert_eq!(
+      netrc.lookup("ignored.example"),
+      Some(&credentials("anonymous", "guest"))
+    );
+  }
+
+  #[test]
+  fn first_entry_wins_without_default() {
+    let netrc = Netrc::parse(
+      "machine example.com login first password one\n\
+       machine example.com login second password two",
+    )
+    .unwrap();
+    assert_eq!(
+      netrc.lookup("example.com"),
+      Some(&credentials("first", "one"))
+    );
+    assert_eq!(netrc.lookup("other.example"), None);
+  }
+
+  #[test]
+  fn rejects_malformed_files() {
+    assert!(Netrc::parse("machine").is_err());
+    assert!(Netrc::parse("machine example.com login").is_err());
+    assert!(Netrc::parse("login alice").is_err());
+    assert!(Netrc::parse("machine example.com user alice").is_err());
+  }
+}
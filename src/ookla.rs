@@ -0,0 +1,375 @@
+use std::{
+  str::FromStr,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use console::style;
+use hyper::{Request, Uri};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+  net::TcpStream,
+};
+
+use crate::{json::Json, latency, output, printer::Printer, Body, TestOptions, TestResult};
+
+/// Servers near this machine, as the web client of speedtest.net lists them.
+pub const SERVERS: &str = "https://www.speedtest.net/api/js/servers?engine=js&limit=10";
+
+/// Bytes downloaded unless `max_bytes` says otherwise, so fast links take long
+/// enough to measure.
+const DOWNLOAD_SIZE: u64 = 100_000_000;
+/// Time after which a download stops unless `sample_duration` says otherwise.
+const DOWNLOAD_DURATION: Duration = Duration::from_secs(10);
+/// Bytes uploaded unless the request has a generated body of another size.
+const UPLOAD_SIZE: u64 = 25_000_000;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Whether `uri` is a `speedtest://host:port` URL of a server speaking the
+/// Speedtest.net TCP protocol.
+pub fn is_ookla(uri: &Uri) -> bool {
+  uri.scheme_str() == Some("speedtest")
+}
+
+/// A server of the [`SERVERS`] list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+  pub id: String,
+  /// Host and port of the TCP protocol, like `speedtest.example.net:8080`
+  pub host: String,
+  /// City of the server
+  pub name: String,
+  pub sponsor: String,
+  pub country: String,
+}
+
+impl Server {
+  /// URL of the server for `path`, like `/download`, which only labels what
+  /// the test measures.
+  pub fn url(&self, path: &str) -> String {
+    format!("speedtest://{}{path}", self.host)
+  }
+}
+
+/// Servers of a [`SERVERS`] response.
+pub fn parse_servers(content: &str) -> anyhow::Result<Vec<Server>> {
+  let servers = Json::from_str(content)
+    .map_err(anyhow::Error::msg)
+    .context("Invalid server list")?;
+  let servers = servers
+    .as_array()
+    .context("Invalid server list: not an array")?;
+  Ok(
+    servers
+      .iter()
+      .filter_map(|server| {
+        let field = |name| Some(server.get(name)?.as_str()?.to_string());
+        Some(Server {
+          // Older responses have numeric IDs
+          id: field("id").or_else(|| Some(server.get("id")?.as_f64()?.to_string()))?,
+          host: field("host")?,
+          name: field("name").unwrap_or_default(),
+          sponsor: field("sponsor").unwrap_or_default(),
+          country: field("country").unwrap_or_default(),
+        })
+      })
+      .collect(),
+  )
+}
+
+/// A connection to a server, greeted with `HI`.
+struct Connection {
+  stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+  async fn open(uri: &Uri, timeout: Duration) -> anyhow::Result<Connection> {
+    let host = uri.host().context("URL without host")?;
+    let port = uri.port_u16().unwrap_or(8080);
+    tokio::time::timeout(timeout, async {
+      let stream = TcpStream::connect((host, port))
+        .await
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+      let mut connection = Connection {
+        stream: BufReader::new(stream),
+      };
+      let hello = connection.command("HI\n").await?;
+      if !hello.starts_with("HELLO") {
+        bail!("Unexpected greeting {hello:?}");
+      }
+      anyhow::Ok(connection)
+    })
+    .await
+    .with_context(|| format!("Timed out for {timeout:?}"))?
+  }
+
+  /// Sends `command` and reads the line the server answers with.
+  async fn command(&mut self, command: &str) -> anyhow::Result<String> {
+    self.stream.get_mut().write_all(command.as_bytes()).await?;
+    self.read_line().await
+  }
+
+  async fn read_line(&mut self) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if self.stream.read_line(&mut line).await? == 0 {
+      bail!("Server closed the connection");
+    }
+    Ok(line.trim_end().to_string())
+  }
+
+  /// Time until the server answers a `PING`.
+  async fn ping(&mut self) -> anyhow::Result<Duration> {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+    let start = Instant::now();
+    let pong = self.command(&format!("PING {now}\n")).await?;
+    let latency = start.elapsed();
+    if !pong.starts_with("PONG") {
+      bail!("Unexpected answer {pong:?} to PING");
+    }
+    Ok(latency)
+  }
+}
+
+/// The lowest of `samples` ping times of the server at `uri`, for picking the
+/// closest server.
+pub async fn ping(uri: &Uri, samples: u32, timeout: Duration) -> anyhow::Result<Duration> {
+  let mut connection = Connection::open(uri, timeout).await?;
+  let mut best = Duration::MAX;
+  for _ in 0..samples {
+    let latency = tokio::time::timeout(timeout, connection.ping())
+      .await
+      .with_context(|| format!("Timed out for {timeout:?}"))??;
+    best = best.min(latency);
+  }
+  Ok(best)
+}
+
+/// Tests the server of a `speedtest://` request over one connection: the
+/// median of `latency_samples` pings if given, an upload of the request body's
+/// size with `upload`, or else a download.
+pub async fn test(
+  id: usize,
+  request: Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<TestResult> {
+  let uri = request.uri().clone();
+  let command = match (options.latency_samples, options.upload) {
+    (Some(_), _) => "PING",
+    (None, true) => "UPLOAD",
+    (None, false) => "DOWNLOAD",
+  };
+  printer.println(format_args!(
+    "{} {} {}",
+    style("==>").magenta(),
+    style(command).green(),
+    uri,
+  ));
+  let mut connection = Connection::open(&uri, options.request_timeout).await?;
+  let ip = connection
+    .stream
+    .get_ref()
+    .peer_addr()
+    .ok()
+    .map(|addr| addr.ip());
+
+  if let Some(samples) = options.latency_samples {
+    let mut latencies = Vec::new();
+    let mut lost = 0;
+    for _ in 0..samples {
+      let Ok(latency) = tokio::time::timeout(options.request_timeout, connection.ping()).await
+      else {
+        printer.println(style(format!("Timed out for {:?}", options.request_timeout)).yellow());
+        lost += 1;
+        // A late PONG would answer the next PING
+        connection = Connection::open(&uri, options.request_timeout).await?;
+        continue;
+      };
+      let latency = latency?;
+      printer.println(format_args!(
+        "PONG {}",
+        output::format_millis(latency, options.time_precision)
+      ));
+      latencies.push(latency);
+    }
+    printer.println("");
+    if latencies.is_empty() {
+      bail!("All {samples} pings timed out");
+    }
+    return Ok(TestResult {
+      id,
+      uri,
+      ip,
+      jitter: latency::jitter(&latencies),
+      loss: Some(f64::from(lost) / f64::from(samples)),
+      latency: latency::median(&mut latencies),
+      ..Default::default()
+    });
+  }
+
+  let size = match options.upload {
+    true => hyper::body::Body::size_hint(request.body())
+      .exact()
+      .filter(|size| *size > 0)
+      .unwrap_or(UPLOAD_SIZE),
+    false => options.max_bytes.unwrap_or(DOWNLOAD_SIZE),
+  };
+  let start = Instant::now();
+  let transfer = async {
+    match options.upload {
+      true => upload(&mut connection, size)
+        .await
+        .map(|bytes| (bytes, false)),
+      false => {
+        download(
+          &mut connection,
+          size,
+          options.sample_duration.unwrap_or(DOWNLOAD_DURATION),
+        )
+        .await
+      },
+    }
+  };
+  let (bytes, truncated) = tokio::time::timeout(options.max_test_duration, transfer)
+    .await
+    .with_context(|| format!("Timed out for {:?}", options.max_test_duration))??;
+  let elapsed = start.elapsed();
+  let speed = (bytes * 1000).checked_div(elapsed.as_millis() as u64);
+  printer.println(format_args!(
+    "{} {} in {}",
+    if options.upload { "Sent" } else { "Received" },
+    humansize::format_size(bytes, humansize::BINARY),
+    output::format_millis(elapsed, options.time_precision),
+  ));
+  printer.println("");
+
+  Ok(TestResult {
+    id,
+    uri,
+    speed,
+    bytes,
+    ip,
+    elapsed: Some(elapsed),
+    truncated,
+    ..Default::default()
+  })
+}
+
+/// Downloads `size` bytes, which the server sends as a line of junk
+/// starting with `DOWNLOAD `, stopping early after `duration`.
+async fn download(
+  connection: &mut Connection,
+  size: u64,
+  duration: Duration,
+) -> anyhow::Result<(u64, bool)> {
+  let command = format!("DOWNLOAD {size}\n");
+  connection
+    .stream
+    .get_mut()
+    .write_all(command.as_bytes())
+    .await?;
+  let start = Instant::now();
+  let mut buf = vec![0; CHUNK_SIZE];
+  let mut bytes = 0;
+  while bytes < size {
+    if start.elapsed() >= duration {
+      return Ok((bytes, true));
+    }
+    let read = connection.stream.read(&mut buf).await?;
+    if read == 0 {
+      bail!("Server closed the connection after {bytes} bytes");
+    }
+    bytes += read as u64;
+  }
+  Ok((bytes, false))
+}
+
+/// Uploads `size` bytes, counting the `UPLOAD` line like the server does,
+/// which answers with `OK` once it received them.
+async fn upload(connection: &mut Connection, size: u64) -> anyhow::Result<u64> {
+  let command = format!("UPLOAD {size} 0\n");
+  let stream = connection.stream.get_mut();
+  stream.write_all(command.as_bytes()).await?;
+  let mut remaining = size.saturating_sub(command.len() as u64);
+  let chunk = vec![b'x'; CHUNK_SIZE];
+  while remaining > 0 {
+    let len = remaining.min(CHUNK_SIZE as u64) as usize;
+    // The payload is one line, ended where the announced size is reached
+    let end = if len as u64 == remaining {
+      len - 1
+    } else {
+      len
+    };
+    stream.write_all(&chunk[..end]).await?;
+    if end < len {
+      stream.write_all(b"\n").await?;
+    }
+    remaining -= len as u64;
+  }
+  let ok = connection.read_line().await?;
+  if !ok.starts_with("OK") {
+    bail!("Unexpected answer {ok:?} to UPLOAD");
+  }
+  Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  };
+
+  use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+  use super::*;
+
+  /// Greets every connection, but leaves the first `PING` unanswered.
+  async fn server() -> Uri {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let pings = Arc::new(AtomicUsize::new(0));
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let pings = pings.clone();
+        tokio::spawn(async move {
+          let mut stream = BufReader::new(stream);
+          let mut line = String::new();
+          while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+            if line.starts_with("HI") {
+              stream.get_mut().write_all(b"HELLO 2.9\n").await.unwrap();
+            } else if line.starts_with("PING") {
+              if pings.fetch_add(1, Ordering::Relaxed) == 0 {
+                // Answered once the client gave up on it
+                tokio::time::sleep(Duration::from_millis(300)).await;
+              }
+              let _ = stream.get_mut().write_all(b"PONG 0\n").await;
+            }
+            line.clear();
+          }
+        });
+      }
+    });
+    Uri::from_str(&format!("speedtest://{addr}/")).unwrap()
+  }
+
+  #[tokio::test]
+  async fn counts_timed_out_pings_as_lost() {
+    let request = Request::get(server().await).body(Body::default()).unwrap();
+    let options = TestOptions {
+      latency_samples: Some(4),
+      request_timeout: Duration::from_millis(100),
+      ..Default::default()
+    };
+    let result = test(0, request, &options, &Printer::observed())
+      .await
+      .unwrap();
+    assert_eq!(result.loss, Some(0.25));
+    assert!(result.latency.is_some());
+  }
+}
@@ -0,0 +1,684 @@
+use std::{
+  fmt::Write,
+  path::Path,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use comfy_table::{modifiers::*, presets::*, Table};
+use hyper::Uri;
+
+use crate::{
+  apt,
+  criteria::{Criteria, Verdict},
+  har,
+  json::Json,
+  pacman,
+  summary::Summary,
+  Aggregate, TestResult, Timings,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+  /// Human readable table
+  Table,
+  /// Prometheus text exposition format, e.g. for the node_exporter textfile collector
+  Prometheus,
+  /// JSON array with an object per result
+  Json,
+  /// Comma-separated values with a header row
+  Csv,
+  /// Markdown table
+  Markdown,
+  /// Standalone HTML page with a table
+  Html,
+  /// HAR 1.2 archive of the requests and responses, for browser devtools
+  Har,
+  /// Pacman mirrorlist of the tested mirrors, fastest first
+  PacmanMirrorlist,
+  /// APT sources.list lines of the tested mirrors, fastest first
+  SourcesList,
+}
+
+impl OutputFormat {
+  /// Whether runs can be appended to one file, unlike a HAR archive, which is
+  /// a single JSON object, and Prometheus metrics, whose series must be unique.
+  pub fn appendable(&self) -> bool {
+    !matches!(self, OutputFormat::Har | OutputFormat::Prometheus)
+  }
+
+  /// Line inserted before each run when appending to an existing results file.
+  pub fn run_separator(&self, started: SystemTime) -> String {
+    match self {
+      OutputFormat::Table => format!("==> spt run at {}\n", rfc3339(started)),
+      OutputFormat::PacmanMirrorlist | OutputFormat::SourcesList => {
+        format!("# spt run at {}\n", rfc3339(started))
+      },
+      // Appended runs are one array per line, which JSON has no comments for
+      OutputFormat::Json | OutputFormat::Csv => String::new(),
+      OutputFormat::Har | OutputFormat::Prometheus => String::new(),
+      OutputFormat::Markdown | OutputFormat::Html => {
+        format!("<!-- spt run at {} -->\n", rfc3339(started))
+      },
+    }
+  }
+
+  /// Guesses the format from the extension of an output file, if it's a known one.
+  pub fn from_extension(path: &Path) -> Option<OutputFormat> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+      "txt" => Some(OutputFormat::Table),
+      "prom" => Some(OutputFormat::Prometheus),
+      "json" => Some(OutputFormat::Json),
+      "csv" => Some(OutputFormat::Csv),
+      "md" | "markdown" => Some(OutputFormat::Markdown),
+      "html" | "htm" => Some(OutputFormat::Html),
+      "har" => Some(OutputFormat::Har),
+      _ => None,
+    }
+  }
+}
+
+pub struct RenderOptions {
+  pub show_ip: bool,
+  pub show_tls: bool,
+  /// Show the durations of the phases of each test
+  pub show_timings: bool,
+  pub strip_query: bool,
+  /// Show how many runs of each URL succeeded
+  pub show_samples: bool,
+  /// Show the minimum, maximum and standard deviation of the speeds of each URL
+  pub show_spread: bool,
+  /// Show how many requests each URL needed, retries included
+  pub show_attempts: bool,
+  /// Show where followed redirects led
+  pub show_redirects: bool,
+  /// Show which downloads were cut off by a byte limit
+  pub show_truncated: bool,
+  /// Show the spread of the per-second throughput of each URL
+  pub show_throughput: bool,
+  /// Show a sparkline of the per-second throughput of each URL
+  pub show_sparkline: bool,
+  /// Skip the table, printing only the summary
+  pub summary_only: bool,
+  /// Tests open several streams, so show how fairly each shared its
+  /// bandwidth between them
+  pub show_fairness: bool,
+  /// Criteria to show the verdict of
+  pub criteria: Option<Criteria>,
+  /// Decimals of the milliseconds in timing columns
+  pub time_precision: usize,
+  /// Requests and responses of the run, for [`OutputFormat::Har`]
+  pub har: Option<har::Recorder>,
+  /// Release the lines of [`OutputFormat::SourcesList`] are for, that of this
+  /// machine by default
+  pub apt: Option<apt::Release>,
+}
+
+impl RenderOptions {
+  pub fn display_uri(&self, uri: &Uri) -> String {
+    if self.strip_query {
+      crate::uri::strip_query(uri)
+    } else {
+      uri.to_string()
+    }
+  }
+}
+
+/// Renders results, which are expected to be sorted from fastest to slowest.
+/// The rendered string always ends with a newline.
+pub fn render(format: OutputFormat, results: &[TestResult], options: &RenderOptions) -> String {
+  match format {
+    OutputFormat::Table if options.summary_only => {
+      format!("{}\n", Summary::new(results, options))
+    },
+    OutputFormat::Table if results.len() > 1 => {
+      format!(
+        "{}\n{}\n",
+        table(results, options),
+        Summary::new(results, options)
+      )
+    },
+    OutputFormat::Table => format!("{}\n", table(results, options)),
+    OutputFormat::Prometheus => prometheus(results, options),
+    OutputFormat::Json => format!("{}\n", json(results, options)),
+    OutputFormat::Csv => csv(results, options),
+    OutputFormat::Markdown => markdown(results, options),
+    OutputFormat::Html => html(results, options),
+    OutputFormat::Har => {
+      let recorder = options.har.clone().unwrap_or_default();
+      format!("{}\n", recorder.to_json())
+    },
+    OutputFormat::PacmanMirrorlist => pacman::mirrorlist(results, options),
+    OutputFormat::SourcesList => options
+      .apt
+      .clone()
+      .unwrap_or_default()
+      .sources_list(results),
+  }
+}
+
+/// Header and rows shared by the tabular formats.
+fn columns(
+  results: &[TestResult],
+  options: &RenderOptions,
+) -> (Vec<&'static str>, Vec<Vec<String>>) {
+  let show_latency = results.iter().any(|data| data.latency.is_some());
+  // Tell apart requests to the same URL, like an upload and a probe
+  let show_method = results.iter().any(|data| data.method != results[0].method);
+  let show_ping = results.iter().any(|data| data.ping.is_some());
+  let mut header = vec!["URL", "Speed"];
+  if show_ping {
+    header.push("Ping");
+  }
+  if show_latency {
+    header.push("Latency");
+  }
+  let show_jitter = results
+    .iter()
+    .any(|data| data.jitter.is_some() || data.loss.is_some());
+  if show_jitter {
+    header.extend(["Jitter", "Loss"]);
+  }
+  let show_bufferbloat = results.iter().any(|data| data.bufferbloat.is_some());
+  if show_bufferbloat {
+    header.extend(["Idle", "Loaded", "Bloat"]);
+  }
+  header.push("Elapsed");
+  if options.show_samples {
+    header.push("Success");
+  }
+  if options.show_spread {
+    header.extend(["Min", "Max", "Stddev"]);
+  }
+  if options.show_attempts {
+    header.push("Attempts");
+  }
+  if options.show_redirects {
+    header.extend(["Final URL", "Redirects"]);
+  }
+  if options.show_truncated {
+    header.push("Truncated");
+  }
+  if options.show_throughput {
+    header.extend(["1s Min", "1s Max", "1s Mean", "1s Stddev", "Stability"]);
+  }
+  if options.show_sparkline {
+    header.push("Throughput");
+  }
+  if options.show_fairness {
+    header.push("Fairness");
+  }
+  if options.show_ip {
+    header.push("IP");
+  }
+  if options.show_tls {
+    header.push("TLS");
+  }
+  if options.show_timings {
+    header.extend(["DNS", "Connect", "TLS Handshake", "TTFB", "Transfer"]);
+  }
+  if options.criteria.is_some() {
+    header.push("Verdict");
+  }
+
+  let rows = results
+    .iter()
+    .map(|data| {
+      let uri = match show_method {
+        true => format!("{} {}", data.method, options.display_uri(&data.uri)),
+        false => options.display_uri(&data.uri),
+      };
+      let mut row = vec![uri, data.speed().into()];
+      if show_ping {
+        row.push(data.ping(options.time_precision).into());
+      }
+      if show_latency {
+        row.push(data.latency(options.time_precision).into());
+      }
+      if show_jitter {
+        row.extend([
+          data.jitter(options.time_precision).into(),
+          data.loss().into(),
+        ]);
+      }
+      if show_bufferbloat {
+        row.extend(match data.bufferbloat {
+          Some(bufferbloat) => [
+            format_millis(bufferbloat.idle, options.time_precision),
+            format_millis(bufferbloat.loaded, options.time_precision),
+            format!(
+              "+{} {}",
+              format_millis(bufferbloat.increase(), options.time_precision),
+              bufferbloat.grade()
+            ),
+          ],
+          None => ["N/A".to_string(), "N/A".to_string(), "N/A".to_string()],
+        });
+      }
+      row.push(data.elapsed(options.time_precision).into());
+      if options.show_samples {
+        row.push(data.samples());
+      }
+      if options.show_spread {
+        row.extend(
+          [
+            data.aggregate(Aggregate::Min),
+            data.aggregate(Aggregate::Max),
+            data.stddev(),
+          ]
+          .map(format_speed),
+        );
+      }
+      if options.show_attempts {
+        row.push(data.attempts.to_string());
+      }
+      if options.show_redirects {
+        let final_uri = data.final_uri.as_ref().unwrap_or(&data.uri);
+        row.extend([options.display_uri(final_uri), data.redirects.to_string()]);
+      }
+      if options.show_truncated {
+        row.push(if data.truncated { "yes" } else { "no" }.to_string());
+      }
+      if options.show_throughput {
+        let throughput = data.throughput();
+        row.extend(
+          [
+            throughput.map(|throughput| throughput.min),
+            throughput.map(|throughput| throughput.max),
+            throughput.map(|throughput| throughput.mean),
+            throughput.map(|throughput| throughput.stddev),
+          ]
+          .map(format_speed),
+        );
+        row.push(throughput.map_or("N/A".to_string(), |throughput| {
+          format!("{:.0}%", throughput.stability() * 100.0)
+        }));
+      }
+      if options.show_sparkline {
+        row.push(sparkline(&data.intervals));
+      }
+      if options.show_fairness {
+        row.push(data.fairness().into());
+      }
+      if options.show_ip {
+        row.push(data.ip().into());
+      }
+      if options.show_tls {
+        row.push(data.tls().into());
+      }
+      if options.show_timings {
+        let millis = |phase: Option<Duration>| match phase {
+          Some(phase) => format_millis(phase, options.time_precision),
+          None => "N/A".to_string(),
+        };
+        let timings = data.timings;
+        row.extend([
+          millis(timings.and_then(|timings| timings.dns)),
+          millis(timings.and_then(|timings| timings.connect)),
+          millis(timings.and_then(|timings| timings.tls)),
+          millis(timings.map(|timings| timings.ttfb)),
+          millis(timings.map(|timings| timings.transfer)),
+        ]);
+      }
+      if let Some(criteria) = &options.criteria {
+        row.push(Verdict(criteria.passes(data)).to_string());
+      }
+      row
+    })
+    .collect();
+
+  (header, rows)
+}
+
+pub fn table(results: &[TestResult], options: &RenderOptions) -> Table {
+  let (header, rows) = columns(results, options);
+  let mut table = Table::new();
+  table
+    .load_preset(UTF8_FULL)
+    .apply_modifier(UTF8_ROUND_CORNERS)
+    .apply_modifier(UTF8_SOLID_INNER_BORDERS)
+    .set_header(header)
+    .add_rows(rows);
+  table
+}
+
+pub fn json(results: &[TestResult], options: &RenderOptions) -> Json {
+  let show_latency = results.iter().any(|data| data.latency.is_some());
+  let show_ping = results.iter().any(|data| data.ping.is_some());
+  Json::Array(
+    results
+      .iter()
+      .map(|data| {
+        let mut object = Json::object([
+          ("url", options.display_uri(&data.uri).into()),
+          ("method", data.method.as_str().into()),
+          (
+            "status",
+            data.status.map(|status| u64::from(status.as_u16())).into(),
+          ),
+          ("attempts", u64::from(data.attempts).into()),
+          ("bytes", data.bytes.into()),
+          (
+            "elapsed_ms",
+            data
+              .elapsed
+              .map(|elapsed| elapsed.as_secs_f64() * 1000.0)
+              .into(),
+          ),
+          ("speed_bps", data.speed.into()),
+          ("error", data.error.clone().into()),
+        ]);
+        if let Json::Object(members) = &mut object {
+          let millis = |duration: Option<Duration>| {
+            Json::from(duration.map(|duration| duration.as_secs_f64() * 1000.0))
+          };
+          if show_ping {
+            members.push(("ping_ms".to_string(), millis(data.ping)));
+          }
+          if show_latency {
+            members.extend([
+              ("latency_ms".to_string(), millis(data.latency)),
+              ("jitter_ms".to_string(), millis(data.jitter)),
+              ("loss".to_string(), data.loss.into()),
+            ]);
+          }
+          if options.show_spread {
+            members.extend([
+              (
+                "speed_min_bps".to_string(),
+                data.aggregate(Aggregate::Min).into(),
+              ),
+              (
+                "speed_max_bps".to_string(),
+                data.aggregate(Aggregate::Max).into(),
+              ),
+              ("speed_stddev_bps".to_string(), data.stddev().into()),
+            ]);
+          }
+          if options.show_redirects {
+            let final_uri = data.final_uri.as_ref().unwrap_or(&data.uri);
+            members.extend([
+              (
+                "final_url".to_string(),
+                options.display_uri(final_uri).into(),
+              ),
+              ("redirects".to_string(), u64::from(data.redirects).into()),
+            ]);
+          }
+          if options.show_truncated {
+            members.push(("truncated".to_string(), data.truncated.into()));
+          }
+          if options.show_throughput {
+            let throughput = data.throughput().map_or(Json::Null, |throughput| {
+              Json::object([
+                ("min_bps", throughput.min.into()),
+                ("max_bps", throughput.max.into()),
+                ("mean_bps", throughput.mean.into()),
+                ("stddev_bps", throughput.stddev.into()),
+                ("stability", throughput.stability().into()),
+              ])
+            });
+            members.push(("throughput".to_string(), throughput));
+          }
+          if options.show_fairness {
+            members.push(("fairness".to_string(), data.jain_index().into()));
+          }
+          if options.show_timings {
+            members.push(("timings".to_string(), timings_json(data.timings)));
+          }
+          if let Some(bufferbloat) = data.bufferbloat {
+            let millis = |duration: Duration| Json::from(duration.as_secs_f64() * 1000.0);
+            let bufferbloat = Json::object([
+              ("idle_ms", millis(bufferbloat.idle)),
+              ("loaded_ms", millis(bufferbloat.loaded)),
+              ("increase_ms", millis(bufferbloat.increase())),
+              ("grade", bufferbloat.grade().into()),
+            ]);
+            members.push(("bufferbloat".to_string(), bufferbloat));
+          }
+        }
+        object
+      })
+      .collect(),
+  )
+}
+
+fn timings_json(timings: Option<Timings>) -> Json {
+  let Some(timings) = timings else {
+    return Json::Null;
+  };
+  let millis =
+    |phase: Option<Duration>| -> Json { phase.map(|phase| phase.as_secs_f64() * 1000.0).into() };
+  Json::object([
+    ("dns_ms", millis(timings.dns)),
+    ("connect_ms", millis(timings.connect)),
+    ("tls_ms", millis(timings.tls)),
+    ("ttfb_ms", millis(Some(timings.ttfb))),
+    ("transfer_ms", millis(Some(timings.transfer))),
+  ])
+}
+
+/// Quotes `field` if it contains a separator, a quote or a line break.
+fn csv_escape(field: &str) -> String {
+  if field.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    field.to_string()
+  }
+}
+
+/// Same fields as [`json`], quoted following RFC 4180.
+pub fn csv(results: &[TestResult], options: &RenderOptions) -> String {
+  let mut out = String::from("url,method,status,attempts,bytes,elapsed_ms,speed_bps,error\r\n");
+  for data in results {
+    let fields = [
+      options.display_uri(&data.uri),
+      data.method.to_string(),
+      data
+        .status
+        .map(|status| status.as_u16().to_string())
+        .unwrap_or_default(),
+      data.attempts.to_string(),
+      data.bytes.to_string(),
+      data
+        .elapsed
+        .map(|elapsed| (elapsed.as_secs_f64() * 1000.0).to_string())
+        .unwrap_or_default(),
+      data
+        .speed
+        .map(|speed| speed.to_string())
+        .unwrap_or_default(),
+      data.error.clone().unwrap_or_default(),
+    ];
+    let fields: Vec<String> = fields.iter().map(|field| csv_escape(field)).collect();
+    let _ = write!(out, "{}\r\n", fields.join(","));
+  }
+  out
+}
+
+/// The bytes each URL received in each second of its transfers, as CSV with a
+/// row per second, or as JSON with an array per URL.
+pub fn timeline(results: &[TestResult], options: &RenderOptions, json: bool) -> String {
+  if json {
+    let urls = results
+      .iter()
+      .map(|data| {
+        Json::object([
+          ("url", options.display_uri(&data.uri).into()),
+          ("interval_ms", 1000u64.into()),
+          (
+            "bytes",
+            Json::Array(data.intervals.iter().map(|bytes| (*bytes).into()).collect()),
+          ),
+        ])
+      })
+      .collect();
+    return format!("{}\n", Json::Array(urls));
+  }
+  let mut out = String::from("url,second,bytes\r\n");
+  for data in results {
+    let url = csv_escape(&options.display_uri(&data.uri));
+    for (second, bytes) in data.intervals.iter().enumerate() {
+      let _ = write!(out, "{url},{second},{bytes}\r\n");
+    }
+  }
+  out
+}
+
+pub fn markdown(results: &[TestResult], options: &RenderOptions) -> String {
+  let escape = |cell: &str| cell.replace('|', "\\|");
+  let (header, rows) = columns(results, options);
+  let mut out = String::new();
+  if !options.summary_only {
+    let _ = writeln!(out, "| {} |", header.join(" | "));
+    let _ = writeln!(out, "|{}", "---|".repeat(header.len()));
+    for row in rows {
+      let row: Vec<String> = row.iter().map(|cell| escape(cell)).collect();
+      let _ = writeln!(out, "| {} |", row.join(" | "));
+    }
+    out.push('\n');
+  }
+  let _ = writeln!(out, "{}", Summary::new(results, options));
+  out
+}
+
+pub fn html(results: &[TestResult], options: &RenderOptions) -> String {
+  let (header, rows) = columns(results, options);
+  let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>spt results</title>\n</head>\n<body>\n");
+  if !options.summary_only {
+    out.push_str("<table>\n<thead>\n<tr>");
+    for cell in header {
+      let _ = write!(out, "<th>{}</th>", escape_html(cell));
+    }
+    out.push_str("</tr>\n</thead>\n<tbody>\n");
+    for row in rows {
+      out.push_str("<tr>");
+      for cell in row {
+        let _ = write!(out, "<td>{}</td>", escape_html(&cell));
+      }
+      out.push_str("</tr>\n");
+    }
+    out.push_str("</tbody>\n</table>\n");
+  }
+  let _ = writeln!(
+    out,
+    "<p>{}</p>",
+    escape_html(&Summary::new(results, options).to_string())
+  );
+  out.push_str("</body>\n</html>\n");
+  out
+}
+
+fn escape_html(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for ch in s.chars() {
+    match ch {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      ch => escaped.push(ch),
+    }
+  }
+  escaped
+}
+
+pub fn prometheus(results: &[TestResult], options: &RenderOptions) -> String {
+  let mut out = String::new();
+
+  out.push_str("# HELP spt_up Whether the speed test of the URL succeeded.\n");
+  out.push_str("# TYPE spt_up gauge\n");
+  for data in results {
+    let up = u8::from(data.succeeded());
+    let _ = writeln!(out, "spt_up{} {up}", prometheus_labels(data, options));
+  }
+
+  out.push_str("# HELP spt_speed_bytes_per_second Measured download speed of the URL.\n");
+  out.push_str("# TYPE spt_speed_bytes_per_second gauge\n");
+  for data in results {
+    if let Some(speed) = data.speed {
+      let _ = writeln!(
+        out,
+        "spt_speed_bytes_per_second{} {speed}",
+        prometheus_labels(data, options)
+      );
+    }
+  }
+
+  out
+}
+
+/// Labels telling the series of results apart, by the position of the target
+/// too as the same URL may be tested with different methods or bodies.
+fn prometheus_labels(data: &TestResult, options: &RenderOptions) -> String {
+  let url = options.display_uri(&data.uri);
+  let mut escaped = String::with_capacity(url.len());
+  for ch in url.chars() {
+    match ch {
+      '\\' => escaped.push_str("\\\\"),
+      '"' => escaped.push_str("\\\""),
+      '\n' => escaped.push_str("\\n"),
+      ch => escaped.push(ch),
+    }
+  }
+  format!(
+    "{{url=\"{escaped}\",method=\"{}\",target=\"{}\"}}",
+    data.method, data.id
+  )
+}
+
+/// Formats bytes per second, `N/A` if unknown.
+pub fn format_speed(speed: Option<u64>) -> String {
+  match speed {
+    Some(speed) => format!("{}/s", humansize::format_size(speed, humansize::BINARY)),
+    None => "N/A".to_string(),
+  }
+}
+
+/// Draws bytes per interval as a row of block characters scaled to the
+/// busiest one, stalls being blank.
+pub fn sparkline(intervals: &[u64]) -> String {
+  const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+  let max = intervals.iter().copied().max().unwrap_or_default().max(1);
+  intervals
+    .iter()
+    .map(|bytes| match bytes {
+      0 => ' ',
+      bytes => BARS[(bytes * 7 / max) as usize],
+    })
+    .collect()
+}
+
+/// Formats a duration in milliseconds with a fixed number of decimals, so
+/// timings line up and sort the same way regardless of their magnitude.
+pub fn format_millis(duration: Duration, precision: usize) -> String {
+  format!("{:.*}ms", precision, duration.as_secs_f64() * 1000.0)
+}
+
+/// Formats a UTC timestamp like `2023-10-01T12:34:56Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+  let secs = time
+    .duration_since(UNIX_EPOCH)
+    .map(|dur| dur.as_secs())
+    .unwrap_or_default();
+  let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+  // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+  let z = days as i64 + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z.rem_euclid(146097);
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + i64::from(month <= 2);
+
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+    secs_of_day / 3600,
+    secs_of_day / 60 % 60,
+    secs_of_day % 60
+  )
+}
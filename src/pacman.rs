@@ -0,0 +1,142 @@
+use std::{str::FromStr, time::SystemTime};
+
+use anyhow::Context;
+use hyper::Uri;
+
+use crate::{
+  json::Json,
+  output::{self, RenderOptions},
+  TestResult,
+};
+
+/// Status of the Arch Linux mirrors, as checked by archlinux.org.
+pub const MIRROR_STATUS: &str = "https://archlinux.org/mirrors/status/json/";
+
+/// Repository whose database is downloaded to test a mirror, a file every
+/// mirror has that is large enough to measure.
+const TEST_REPO: &str = "extra";
+
+/// Whether `content` is a pacman mirrorlist, all of its lines being
+/// comments, blank or `Server = URL`.
+pub fn is_mirrorlist(content: &str) -> bool {
+  content.lines().any(|line| server(line).is_some())
+    && content.lines().all(|line| {
+      let line = line.trim();
+      line.is_empty() || line.starts_with('#') || server(line).is_some()
+    })
+}
+
+/// The `Server = URL` of a mirrorlist line, also if it is commented out like
+/// in the lists generated by archlinux.org.
+fn server(line: &str) -> Option<&str> {
+  let line = line.trim().trim_start_matches('#').trim_start();
+  let (key, url) = line.split_once('=')?;
+  (key.trim() == "Server").then(|| url.trim())
+}
+
+/// URLs to test the servers of a mirrorlist with, see [`test_url`].
+pub fn test_urls(content: &str) -> Vec<String> {
+  content.lines().filter_map(server).map(test_url).collect()
+}
+
+/// The URL of a repository database of `server`, replacing its `$repo` and
+/// `$arch`.
+pub fn test_url(server: &str) -> String {
+  let server = server
+    .replace("$repo", TEST_REPO)
+    .replace("$arch", std::env::consts::ARCH);
+  format!("{}/{TEST_REPO}.db", server.trim_end_matches('/'))
+}
+
+/// Which mirrors of the [`MIRROR_STATUS`] to test.
+#[derive(Debug, Clone, Default)]
+pub struct StatusFilter {
+  /// Country codes like `DE`, any country if empty
+  pub countries: Vec<String>,
+  /// Protocols like `https`, any protocol pacman supports if empty
+  pub protocols: Vec<String>,
+  /// Test only this many of the best scored mirrors
+  pub limit: Option<usize>,
+}
+
+/// Servers of the [`MIRROR_STATUS`] JSON which are active, completely synced
+/// and match `filter`, best scored first.
+pub fn parse_mirror_status(content: &str, filter: &StatusFilter) -> anyhow::Result<Vec<String>> {
+  let status = Json::from_str(content)
+    .map_err(anyhow::Error::msg)
+    .context("Invalid mirror status")?;
+  let mirrors = status
+    .get("urls")
+    .and_then(Json::as_array)
+    .context("Invalid mirror status: missing urls")?;
+  let matches = |values: &[String], value: Option<&str>| {
+    values.is_empty()
+      || value.is_some_and(|value| values.iter().any(|v| v.eq_ignore_ascii_case(value)))
+  };
+  let mut servers: Vec<(f64, String)> = mirrors
+    .iter()
+    .filter(|mirror| matches!(mirror.get("active"), Some(Json::Bool(true))))
+    .filter(|mirror| mirror.get("completion_pct").and_then(Json::as_f64) == Some(1.0))
+    .filter(|mirror| {
+      let protocol = mirror.get("protocol").and_then(Json::as_str);
+      match filter.protocols.is_empty() {
+        true => matches!(protocol, Some("http" | "https")),
+        false => matches(&filter.protocols, protocol),
+      }
+    })
+    .filter(|mirror| {
+      let country = mirror.get("country_code").and_then(Json::as_str);
+      matches(&filter.countries, country)
+    })
+    .filter_map(|mirror| {
+      let url = mirror.get("url")?.as_str()?;
+      // Lower scores are better, mirrors without one go last
+      let score = mirror
+        .get("score")
+        .and_then(Json::as_f64)
+        .unwrap_or(f64::MAX);
+      Some((score, format!("{url}$repo/os/$arch")))
+    })
+    .collect();
+  servers.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+  servers.truncate(filter.limit.unwrap_or(usize::MAX));
+  Ok(servers.into_iter().map(|(_, server)| server).collect())
+}
+
+/// The `Server =` URL of a tested mirror, undoing [`test_urls`] for
+/// repository database URLs and taking others as they are.
+pub fn server_url(uri: &Uri) -> String {
+  let url = uri.to_string();
+  let segments: Vec<&str> = url.rsplitn(5, '/').collect();
+  match segments[..] {
+    [db, _arch, "os", repo, base] if db == format!("{repo}.db") => {
+      format!("{base}/$repo/os/$arch")
+    },
+    _ => url,
+  }
+}
+
+/// A mirrorlist for `/etc/pacman.d/mirrorlist` with the mirrors in the order
+/// of `results`, failed ones commented out.
+pub fn mirrorlist(results: &[TestResult], options: &RenderOptions) -> String {
+  let mut out = format!(
+    "##\n## Arch Linux repository mirrorlist\n## Ranked by spt at {}\n##\n",
+    output::rfc3339(SystemTime::now())
+  );
+  for data in results {
+    let server = server_url(&data.uri);
+    out.push('\n');
+    if data.succeeded() {
+      let measured = match data.latency {
+        Some(latency) => output::format_millis(latency, options.time_precision),
+        None => output::format_speed(data.speed),
+      };
+      out.push_str(&format!("## {measured}\nServer = {server}\n"));
+    } else {
+      let error = data.error.as_deref().unwrap_or("failed");
+      let error = error.lines().next().unwrap_or_default();
+      out.push_str(&format!("## {error}\n#Server = {server}\n"));
+    }
+  }
+  out
+}
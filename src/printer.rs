@@ -0,0 +1,268 @@
+use std::{
+  fmt::{Debug, Display, Write as _},
+  future::Future,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+use console::style;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use parking_lot::Mutex;
+use tracing::{
+  field::{Field, Visit},
+  level_filters::LevelFilter,
+  span, Event, Level, Metadata, Subscriber,
+};
+
+static PROGRESS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns progress bars off everywhere. Without a terminal on stderr they are
+/// hidden anyway, so this is only needed to silence them on one.
+pub fn set_progress_enabled(enabled: bool) {
+  PROGRESS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints the `tracing` events of spt as dimmed diagnostics: connection reuse
+/// and retry decisions at 1, request and response headers at 2, connection
+/// setup details at 3, nothing at 0. Programs embedding the library may
+/// install a subscriber of their own instead.
+pub fn set_verbosity(level: u8) {
+  let level = match level {
+    0 => return,
+    1 => Level::INFO,
+    2 => Level::DEBUG,
+    _ => Level::TRACE,
+  };
+  let _ = tracing::subscriber::set_global_default(Diagnostics { level });
+}
+
+tokio::task_local! {
+  static CURRENT: Printer;
+}
+
+/// Runs `future` with its diagnostics going to `printer`, so they stay with
+/// the rest of the output of its test.
+pub async fn scope<F: Future>(printer: Printer, future: F) -> F::Output {
+  CURRENT.scope(printer, future).await
+}
+
+/// A subscriber printing events up to `level` as single lines, to the printer
+/// of the current test if there is one.
+struct Diagnostics {
+  level: Level,
+}
+
+impl Subscriber for Diagnostics {
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    // Not the events of hyper, h2 and rustls
+    metadata.target().starts_with("spt") && *metadata.level() <= self.level
+  }
+
+  fn max_level_hint(&self) -> Option<LevelFilter> {
+    Some(LevelFilter::from_level(self.level))
+  }
+
+  fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+    span::Id::from_u64(1)
+  }
+
+  fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+  fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+  fn event(&self, event: &Event<'_>) {
+    let mut line = Line::default();
+    event.record(&mut line);
+    let line = style(line.0).dim().to_string();
+    if CURRENT.try_with(|printer| printer.println(&line)).is_err() {
+      log(line);
+    }
+  }
+
+  fn enter(&self, _: &span::Id) {}
+
+  fn exit(&self, _: &span::Id) {}
+}
+
+/// The message of an event followed by its other fields as `name=value`.
+#[derive(Default)]
+struct Line(String);
+
+impl Visit for Line {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    match field.name() {
+      "message" => self.0.push_str(value),
+      name => {
+        let _ = write!(self.0, " {name}={value}");
+      },
+    }
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+    match field.name() {
+      "message" => {
+        let _ = write!(self.0, "{value:?}");
+      },
+      name => {
+        let _ = write!(self.0, " {name}={value:?}");
+      },
+    }
+  }
+}
+
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends log lines to stderr, keeping stdout clean for results meant for other programs.
+pub fn set_log_to_stderr(enabled: bool) {
+  LOG_TO_STDERR.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints a log line right away, to stdout unless [`set_log_to_stderr`] was enabled.
+pub fn log(line: impl Display) {
+  if LOG_TO_STDERR.load(Ordering::Relaxed) {
+    eprintln!("{line}");
+  } else {
+    println!("{line}");
+  }
+}
+
+/// Where the progress of a single test is reported.
+#[derive(Clone)]
+pub enum Printer {
+  /// Print lines and draw the progress bar right away
+  Direct,
+  /// Print nothing, as when embedding the library
+  Silent,
+  /// Collect lines until [`Printer::flush`] so tests running concurrently
+  /// don't interleave their output, drawing progress bars below each other
+  Buffered {
+    lines: Arc<Mutex<Vec<String>>>,
+    bars: MultiProgress,
+    /// Shown in front of the progress bar to tell the tests apart
+    label: String,
+  },
+  /// Draw nothing, keeping the last line and the hidden progress bars for a
+  /// dashboard to show
+  Observed {
+    last_line: Arc<Mutex<Option<String>>>,
+    bars: Arc<Mutex<Vec<ProgressBar>>>,
+  },
+}
+
+impl Printer {
+  pub fn buffered(bars: &MultiProgress, label: impl Into<String>) -> Printer {
+    Printer::Buffered {
+      lines: Default::default(),
+      bars: bars.clone(),
+      label: label.into(),
+    }
+  }
+
+  pub fn observed() -> Printer {
+    Printer::Observed {
+      last_line: Default::default(),
+      bars: Default::default(),
+    }
+  }
+
+  pub fn println(&self, line: impl Display) {
+    match self {
+      Printer::Direct => log(line),
+      Printer::Silent => {},
+      Printer::Buffered { lines, .. } => lines.lock().push(line.to_string()),
+      Printer::Observed { last_line, .. } => {
+        let line = line.to_string();
+        if !line.is_empty() {
+          *last_line.lock() = Some(line);
+        }
+      },
+    }
+  }
+
+  /// The last line printed to an observed printer.
+  pub fn last_line(&self) -> Option<String> {
+    match self {
+      Printer::Observed { last_line, .. } => last_line.lock().clone(),
+      _ => None,
+    }
+  }
+
+  /// Bytes counted by all progress bars of an observed printer so far.
+  pub fn position(&self) -> u64 {
+    match self {
+      Printer::Observed { bars, .. } => bars.lock().iter().map(ProgressBar::position).sum(),
+      _ => 0,
+    }
+  }
+
+  pub fn progress_bar(&self, len: Option<u64>) -> ProgressBar {
+    if !PROGRESS_ENABLED.load(Ordering::Relaxed) {
+      return ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden());
+    }
+    match self {
+      Printer::Direct => ProgressBar::with_draw_target(len, ProgressDrawTarget::stderr()),
+      Printer::Silent => ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden()),
+      Printer::Observed { bars, .. } => {
+        let pb = ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden());
+        bars.lock().push(pb.clone());
+        pb
+      },
+      Printer::Buffered { bars, label, .. } => bars
+        .add(ProgressBar::with_draw_target(
+          len,
+          ProgressDrawTarget::hidden(),
+        ))
+        .with_prefix(format!("{label} ")),
+    }
+  }
+
+  /// Finishes a bar from [`Printer::progress_bar`], clearing it if it shares
+  /// the screen with others so finished tests don't pile up.
+  pub fn finish_progress(&self, pb: &ProgressBar) {
+    match self {
+      // Unlike `finish`, leaves bars of downloads stopped early where they were
+      Printer::Direct | Printer::Silent | Printer::Observed { .. } => pb.abandon(),
+      Printer::Buffered { bars, .. } => {
+        pb.finish_and_clear();
+        bars.remove(pb);
+      },
+    }
+  }
+
+  /// Printers for `count` parts of a test running concurrently, their bars
+  /// drawn below each other. Lines are kept until [`Printer::flush_to`].
+  pub fn streams(&self, count: usize) -> Vec<Printer> {
+    let (bars, label) = match self {
+      Printer::Silent => return vec![Printer::Silent; count],
+      // Summing up the bars of all streams
+      Printer::Observed { .. } => return vec![self.clone(); count],
+      Printer::Direct => (MultiProgress::new(), String::new()),
+      Printer::Buffered { bars, label, .. } => (bars.clone(), format!("{label} ")),
+    };
+    (1..=count)
+      .map(|stream| Printer::buffered(&bars, format!("{label}#{stream}")))
+      .collect()
+  }
+
+  /// Moves lines buffered by a printer from [`Printer::streams`] to this one.
+  pub fn flush_to(&self, parent: &Printer) {
+    if let Printer::Buffered { lines, .. } = self {
+      for line in lines.lock().drain(..) {
+        parent.println(line);
+      }
+    }
+  }
+
+  /// Prints buffered lines, if any.
+  pub fn flush(&self) {
+    if let Printer::Buffered { lines, bars, .. } = self {
+      bars.suspend(|| {
+        for line in lines.lock().drain(..) {
+          log(line);
+        }
+      });
+    }
+  }
+}
@@ -0,0 +1,244 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use hyper::{
+  body::Bytes,
+  header::{self, HeaderMap, HeaderValue},
+  Method, Request, StatusCode, Uri,
+};
+
+use crate::{body::Body, fetch, json::Json, Target, TlsHyper, FETCH_TIMEOUT};
+
+/// Manifest types to accept, indexes first so multi-platform images resolve
+/// to the manifest of this machine's platform.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+  application/vnd.docker.distribution.manifest.list.v2+json, \
+  application/vnd.oci.image.manifest.v1+json, \
+  application/vnd.docker.distribution.manifest.v2+json";
+
+/// Host of Docker Hub's registry API, for images not naming a registry.
+pub const DOCKER_HUB: &str = "registry-1.docker.io";
+
+/// An image like `ubuntu:24.04`, `ghcr.io/owner/app@sha256:...` or
+/// `library/alpine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+  /// Registry named in the reference, if any
+  pub registry: Option<String>,
+  /// Repository, with `library/` for official images of Docker Hub
+  pub repository: String,
+  /// Tag or digest
+  pub reference: String,
+}
+
+impl ImageRef {
+  /// The registry to pull the image from unless told otherwise.
+  pub fn default_registry(&self) -> &str {
+    match self.registry.as_deref() {
+      None | Some("docker.io" | "index.docker.io") => DOCKER_HUB,
+      Some(registry) => registry,
+    }
+  }
+}
+
+impl FromStr for ImageRef {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (name, reference) = match s.split_once('@') {
+      Some((name, digest)) => (name, digest.to_string()),
+      None => match s.rsplit_once(':') {
+        // A colon before the last slash belongs to a registry port
+        Some((name, tag)) if !tag.contains('/') => (name, tag.to_string()),
+        _ => (s, "latest".to_string()),
+      },
+    };
+    let (registry, repository) = match name.split_once('/') {
+      Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+        (Some(first.to_string()), rest.to_string())
+      },
+      _ => (None, name.to_string()),
+    };
+    if repository.is_empty() || reference.is_empty() {
+      return Err(format!("invalid image reference {s}"));
+    }
+    let docker_hub = registry
+      .as_deref()
+      .is_none_or(|registry| registry == "docker.io" || registry == "index.docker.io");
+    let repository = match docker_hub && !repository.contains('/') {
+      true => format!("library/{repository}"),
+      false => repository,
+    };
+    Ok(ImageRef {
+      registry,
+      repository,
+      reference,
+    })
+  }
+}
+
+/// A target downloading the largest layer of `image` from the registry at
+/// `host`, like `registry-1.docker.io` or `http://localhost:5000`, with a
+/// token from the registry's auth server if it asks for one.
+pub async fn blob_target(
+  client: &TlsHyper,
+  host: &str,
+  image: &ImageRef,
+) -> anyhow::Result<Target> {
+  let base = match host.contains("://") {
+    true => host.trim_end_matches('/').to_string(),
+    false => format!("https://{}", host.trim_end_matches('/')),
+  };
+  let mut registry = Registry {
+    client,
+    base,
+    repository: &image.repository,
+    authorization: None,
+  };
+  let mut manifest = registry.manifest(&image.reference).await?;
+  if let Some(manifests) = manifest.get("manifests").and_then(Json::as_array) {
+    let digest = platform_manifest(manifests).context("No manifest for this platform")?;
+    manifest = registry.manifest(&digest).await?;
+  }
+  let layers = manifest
+    .get("layers")
+    .and_then(Json::as_array)
+    .context("Manifest has no layers")?;
+  let (digest, _) = layers
+    .iter()
+    .filter_map(|layer| {
+      let digest = layer.get("digest")?.as_str()?;
+      Some((digest, layer.get("size")?.as_f64()?))
+    })
+    .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    .context("Manifest has no layers")?;
+
+  let mut request = Request::builder()
+    .uri(registry.url(&format!("blobs/{digest}"))?)
+    .body(Body::default())
+    .context("Failed to build request")?;
+  if let Some(authorization) = registry.authorization {
+    request
+      .headers_mut()
+      .insert(header::AUTHORIZATION, authorization);
+  }
+  Ok(Target::new(request))
+}
+
+/// Digest of the manifest of an index matching this machine's platform.
+fn platform_manifest(manifests: &[Json]) -> Option<String> {
+  let arch = match std::env::consts::ARCH {
+    "x86_64" => "amd64",
+    "aarch64" => "arm64",
+    "x86" => "386",
+    arch => arch,
+  };
+  manifests
+    .iter()
+    .find(|manifest| {
+      let platform = manifest.get("platform");
+      let field = |name| platform.and_then(|p| p.get(name)).and_then(Json::as_str);
+      field("os") == Some("linux") && field("architecture") == Some(arch)
+    })
+    .and_then(|manifest| Some(manifest.get("digest")?.as_str()?.to_string()))
+}
+
+struct Registry<'a> {
+  client: &'a TlsHyper,
+  /// Scheme and host of the registry
+  base: String,
+  repository: &'a str,
+  authorization: Option<HeaderValue>,
+}
+
+impl Registry<'_> {
+  fn url(&self, path: &str) -> anyhow::Result<Uri> {
+    let url = format!("{}/v2/{}/{path}", self.base, self.repository);
+    Uri::from_str(&url).with_context(|| format!("Invalid URL: {url}"))
+  }
+
+  /// The manifest of a tag or digest, getting a token first if needed.
+  async fn manifest(&mut self, reference: &str) -> anyhow::Result<Json> {
+    let uri = self.url(&format!("manifests/{reference}"))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static(MANIFEST_TYPES));
+    let (status, resp_headers, body) = self.get(&uri, &headers).await?;
+    let body = match status {
+      StatusCode::UNAUTHORIZED if self.authorization.is_none() => {
+        let challenge = resp_headers
+          .get(header::WWW_AUTHENTICATE)
+          .and_then(|value| value.to_str().ok())
+          .context("Registry asked for credentials without saying how to get them")?;
+        self.authorization = Some(self.token(challenge).await?);
+        let (status, _, body) = self.get(&uri, &headers).await?;
+        if !status.is_success() {
+          bail!("Registry responded with {status} to {uri}");
+        }
+        body
+      },
+      status if status.is_success() => body,
+      status => bail!("Registry responded with {status} to {uri}"),
+    };
+    let body = std::str::from_utf8(&body).context("Manifest is not UTF-8")?;
+    Json::from_str(body)
+      .map_err(anyhow::Error::msg)
+      .with_context(|| format!("Invalid manifest from {uri}"))
+  }
+
+  /// Gets an anonymous pull token as a `WWW-Authenticate` challenge like
+  /// `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`
+  /// says.
+  async fn token(&self, challenge: &str) -> anyhow::Result<HeaderValue> {
+    let params = challenge
+      .strip_prefix("Bearer ")
+      .with_context(|| format!("Unsupported authentication {challenge}"))?;
+    let param = |name: &str| {
+      params.split(',').find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        (key == name).then(|| value.trim_matches('"'))
+      })
+    };
+    let realm = param("realm").context("Authentication challenge without realm")?;
+    let mut url = format!("{realm}?scope=repository:{}:pull", self.repository);
+    if let Some(service) = param("service") {
+      url = format!("{url}&service={service}");
+    }
+    let uri = Uri::from_str(&url).with_context(|| format!("Invalid URL: {url}"))?;
+    let (status, _, body) = self.get(&uri, &HeaderMap::new()).await?;
+    if !status.is_success() {
+      bail!("Auth server responded with {status} to {uri}");
+    }
+    let body = std::str::from_utf8(&body).context("Token response is not UTF-8")?;
+    let response = Json::from_str(body)
+      .map_err(anyhow::Error::msg)
+      .context("Invalid token response")?;
+    let token = response
+      .get("token")
+      .or_else(|| response.get("access_token"))
+      .and_then(Json::as_str)
+      .context("Token response without token")?;
+    HeaderValue::from_str(&format!("Bearer {token}")).context("Invalid token")
+  }
+
+  async fn get(
+    &self,
+    uri: &Uri,
+    headers: &HeaderMap,
+  ) -> anyhow::Result<(StatusCode, HeaderMap, Bytes)> {
+    let mut request = Request::builder()
+      .method(Method::GET)
+      .uri(uri.clone())
+      .body(Body::default())
+      .context("Failed to build request")?;
+    request.headers_mut().extend(headers.clone());
+    if let Some(authorization) = &self.authorization {
+      request
+        .headers_mut()
+        .insert(header::AUTHORIZATION, authorization.clone());
+    }
+    let (parts, body) = fetch(self.client, request, FETCH_TIMEOUT)
+      .await?
+      .into_parts();
+    Ok((parts.status, parts.headers, body))
+  }
+}
@@ -0,0 +1,357 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, Context};
+use clap::Args;
+use console::style;
+use http_body_util::BodyExt;
+use tokio::{
+  io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+  net::{TcpListener, TcpStream},
+};
+
+use spt::{
+  body::{Body, Payload, PayloadKind},
+  parse_size, printer,
+};
+
+/// Bytes `/download` sends without a `bytes` query.
+const DOWNLOAD_SIZE: u64 = 100 * 1024 * 1024;
+/// Longest request line or header accepted.
+const MAX_LINE: usize = 8 * 1024;
+
+/// Serve endpoints for another spt to test the link to this machine with.
+#[derive(Args)]
+pub struct ServeArgs {
+  /// Port to listen on
+  #[clap(long, default_value_t = 8080)]
+  port: u16,
+  /// Address to listen on, by default all of IPv4
+  #[clap(long, value_name = "ADDRESS", default_value = "0.0.0.0")]
+  bind: IpAddr,
+  /// Bytes of the payloads sent
+  #[clap(long, value_enum, value_name = "KIND", default_value_t = PayloadKind::Random)]
+  data: PayloadKind,
+}
+
+/// Serves until interrupted, answering over HTTP/1.1:
+///
+/// - `GET /download?bytes=SIZE` with a generated body of SIZE, e.g. `1G`,
+///   honouring a single `Range`
+/// - `POST` or `PUT /upload` by discarding the body
+/// - `/ping` with an empty response, for `--latency`
+pub async fn run(args: &ServeArgs) -> anyhow::Result<()> {
+  let addr = SocketAddr::new(args.bind, args.port);
+  let listener = TcpListener::bind(addr)
+    .await
+    .with_context(|| format!("Failed to listen on {addr}"))?;
+  let url = format!("http://{}", listener.local_addr()?);
+  printer::log(format_args!("Listening on {}", style(&url).green()));
+  printer::log(format_args!("  Download  spt {url}/download?bytes=100M"));
+  printer::log(format_args!("  Upload    spt --upload 100M {url}/upload"));
+  printer::log(format_args!("  Latency   spt --latency {url}/ping"));
+  let data = args.data;
+  loop {
+    let (stream, peer) = listener.accept().await.context("Failed to accept")?;
+    tokio::spawn(async move {
+      if let Err(err) = serve_connection(stream, peer, data).await {
+        printer::log(style(format!("{peer}: {err:#}")).yellow());
+      }
+    });
+  }
+}
+
+struct Request {
+  method: String,
+  path: String,
+  query: String,
+  headers: Vec<(String, String)>,
+}
+
+impl Request {
+  fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  fn param(&self, name: &str) -> Option<&str> {
+    self.query.split('&').find_map(|pair| {
+      let (key, value) = pair.split_once('=')?;
+      (key == name).then_some(value)
+    })
+  }
+}
+
+async fn serve_connection(
+  stream: TcpStream,
+  peer: SocketAddr,
+  data: PayloadKind,
+) -> anyhow::Result<()> {
+  let mut stream = BufReader::new(stream);
+  while let Some(request) = read_request(&mut stream).await? {
+    printer::log(format_args!(
+      "{} {} {}{}",
+      style(peer.ip()).dim(),
+      style(&request.method).green(),
+      request.path,
+      match request.query.is_empty() {
+        true => String::new(),
+        false => format!("?{}", request.query),
+      }
+    ));
+    let received = read_body(&mut stream, &request).await?;
+    let close = request
+      .header("connection")
+      .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+    let writer = stream.get_mut();
+    match (request.method.as_str(), request.path.as_str()) {
+      ("GET" | "HEAD", "/download") => {
+        let size = match request.param("bytes").map(parse_size) {
+          Some(Ok(size)) => size,
+          Some(Err(err)) => {
+            respond(writer, "400 Bad Request", "text/plain", &format!("{err}\n")).await?;
+            continue;
+          },
+          None => DOWNLOAD_SIZE,
+        };
+        let (status, start, len) = match request
+          .header("range")
+          .and_then(|range| parse_range(range, size))
+        {
+          Some((start, end)) => ("206 Partial Content", start, end - start + 1),
+          None => ("200 OK", 0, size),
+        };
+        let mut head = format!(
+          "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nContent-Length: {len}\r\nAccept-Ranges: bytes\r\nCache-Control: no-store\r\n"
+        );
+        if status.starts_with("206") {
+          head.push_str(&format!(
+            "Content-Range: bytes {start}-{}/{size}\r\n",
+            start + len - 1
+          ));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes()).await?;
+        if request.method == "GET" {
+          let mut body = Body::Generated(Payload::new(len, data));
+          while let Some(Ok(frame)) = body.frame().await {
+            if let Ok(chunk) = frame.into_data() {
+              writer.write_all(&chunk).await?;
+            }
+          }
+        }
+      },
+      ("POST" | "PUT", "/upload") => {
+        let body = format!("{{\"bytes\":{received}}}\n");
+        respond(writer, "200 OK", "application/json", &body).await?;
+      },
+      (_, "/ping") => respond(writer, "204 No Content", "text/plain", "").await?,
+      (_, "/download" | "/upload") => {
+        respond(
+          writer,
+          "405 Method Not Allowed",
+          "text/plain",
+          "Method not allowed\n",
+        )
+        .await?
+      },
+      _ => respond(writer, "404 Not Found", "text/plain", "Not found\n").await?,
+    }
+    writer.flush().await?;
+    if close {
+      break;
+    }
+  }
+  Ok(())
+}
+
+async fn respond(
+  writer: &mut TcpStream,
+  status: &str,
+  content_type: &str,
+  body: &str,
+) -> anyhow::Result<()> {
+  let response = format!(
+    "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\r\n{body}",
+    body.len()
+  );
+  writer.write_all(response.as_bytes()).await?;
+  Ok(())
+}
+
+/// The next request of a connection, `None` once the client closed it.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> anyhow::Result<Option<Request>> {
+  let Some(line) = read_line(stream).await? else {
+    return Ok(None);
+  };
+  let mut parts = line.split(' ');
+  let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+  else {
+    bail!("Invalid request line {line:?}");
+  };
+  let (path, query) = target.split_once('?').unwrap_or((target, ""));
+  let mut headers = Vec::new();
+  loop {
+    let line = read_line(stream)
+      .await?
+      .context("Connection closed in the headers")?;
+    if line.is_empty() {
+      break;
+    }
+    let (name, value) = line
+      .split_once(':')
+      .with_context(|| format!("Invalid header {line:?}"))?;
+    headers.push((name.trim().to_string(), value.trim().to_string()));
+  }
+  Ok(Some(Request {
+    method: method.to_string(),
+    path: path.to_string(),
+    query: query.to_string(),
+    headers,
+  }))
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> anyhow::Result<Option<String>> {
+  let mut line = Vec::new();
+  let read = (&mut *stream)
+    .take(MAX_LINE as u64)
+    .read_until(b'\n', &mut line)
+    .await?;
+  if read == 0 {
+    return Ok(None);
+  }
+  if !line.ends_with(b"\n") {
+    bail!("Line too long");
+  }
+  let line = String::from_utf8(line).context("Invalid request")?;
+  Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Reads and discards the body of `request`, returning its length.
+async fn read_body(stream: &mut BufReader<TcpStream>, request: &Request) -> anyhow::Result<u64> {
+  let chunked = request
+    .header("transfer-encoding")
+    .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+  if !chunked {
+    let len = match request.header("content-length") {
+      Some(len) => len.parse().context("Invalid Content-Length")?,
+      None => 0,
+    };
+    let copied = tokio::io::copy(&mut (&mut *stream).take(len), &mut tokio::io::sink()).await?;
+    if copied < len {
+      bail!("Connection closed after {copied} of {len} bytes");
+    }
+    return Ok(len);
+  }
+  let mut total = 0;
+  loop {
+    let line = read_line(stream)
+      .await?
+      .context("Connection closed in a chunk")?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    let size =
+      u64::from_str_radix(size, 16).with_context(|| format!("Invalid chunk size {size:?}"))?;
+    if size == 0 {
+      // Trailers end with an empty line
+      while read_line(stream)
+        .await?
+        .is_some_and(|line| !line.is_empty())
+      {}
+      return Ok(total);
+    }
+    tokio::io::copy(&mut (&mut *stream).take(size), &mut tokio::io::sink()).await?;
+    read_line(stream).await?;
+    total += size;
+  }
+}
+
+/// The first and last byte of a `bytes=START-END` range of a body of `size`,
+/// `None` for ranges this server doesn't satisfy, which get the whole body.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+  let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+  let (start, end) = match (start.trim(), end.trim()) {
+    ("", suffix) => (
+      size.checked_sub(suffix.parse().ok()?)?,
+      size.checked_sub(1)?,
+    ),
+    (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+    (start, end) => (
+      start.parse().ok()?,
+      end.parse::<u64>().ok()?.min(size.checked_sub(1)?),
+    ),
+  };
+  (start <= end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_ranges() {
+    assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+    assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+    assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+    // The end is clamped to the body
+    assert_eq!(parse_range("bytes=500-5000", 1000), Some((500, 999)));
+    assert_eq!(parse_range("bytes=1000-", 1000), None);
+    assert_eq!(parse_range("bytes=-2000", 1000), None);
+    assert_eq!(parse_range("bytes=99-0", 1000), None);
+    assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+    assert_eq!(parse_range("items=0-9", 1000), None);
+    assert_eq!(parse_range("bytes=0-", 0), None);
+  }
+
+  /// Writes `raw` to a loopback connection and reads it as the body of a
+  /// request with `headers`, returning its length and what followed it.
+  async fn read_raw_body(raw: &[u8], headers: &[(&str, &str)]) -> anyhow::Result<(u64, String)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let mut client = TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+    client.write_all(raw).await?;
+    client.shutdown().await?;
+    let request = Request {
+      method: "POST".to_string(),
+      path: "/upload".to_string(),
+      query: String::new(),
+      headers: headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect(),
+    };
+    let mut stream = BufReader::new(server);
+    let len = read_body(&mut stream, &request).await?;
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).await?;
+    Ok((len, rest))
+  }
+
+  #[tokio::test]
+  async fn reads_bodies() {
+    let body = read_raw_body(b"helloGET", &[("Content-Length", "5")]).await;
+    assert_eq!(body.unwrap(), (5, "GET".to_string()));
+    let body = read_raw_body(b"GET", &[]).await;
+    assert_eq!(body.unwrap(), (0, "GET".to_string()));
+    assert!(read_raw_body(b"hel", &[("Content-Length", "5")])
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn reads_chunked_bodies() {
+    let chunked = [("Transfer-Encoding", "chunked")];
+    let body = read_raw_body(
+      b"5\r\nhello\r\nA;ext=1\r\n0123456789\r\n0\r\n\r\nGET",
+      &chunked,
+    )
+    .await;
+    assert_eq!(body.unwrap(), (15, "GET".to_string()));
+    // Trailers are skipped along with the last chunk
+    let body = read_raw_body(b"3\r\nabc\r\n0\r\nX-Trailer: 1\r\n\r\nGET", &chunked).await;
+    assert_eq!(body.unwrap(), (3, "GET".to_string()));
+    assert!(read_raw_body(b"zz\r\n", &chunked).await.is_err());
+    assert!(read_raw_body(b"5\r\nhel", &chunked).await.is_err());
+  }
+}
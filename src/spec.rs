@@ -0,0 +1,165 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::{bail, Context};
+use hyper::Method;
+
+use spt::{json::Json, Target};
+
+use crate::{request_targets, vars::Vars, yaml};
+
+/// Reads a request spec, a JSON file or a YAML one like
+///
+/// ```yaml
+/// requests:
+///   - url: https://example.com/file.bin
+///     repeat: 3
+///   - url: https://example.com/upload
+///     method: POST
+///     headers:
+///       Content-Type: application/octet-stream
+///     body_file: payload.bin
+///     timeout: 30
+///     expect:
+///       status: 201
+/// ```
+///
+/// The top level may also be the list of requests itself. Fields other than
+/// `url`, `method`, `headers`, `expect` and `repeat` are the options of URL
+/// file lines. `${NAME}` placeholders are expanded before parsing.
+pub fn parse_from_path(path: &Path, vars: &Vars) -> anyhow::Result<Vec<Target>> {
+  let content =
+    fs::read_to_string(path).with_context(|| format!("Failed to read spec {}", path.display()))?;
+  let content = vars
+    .expand(&content)
+    .with_context(|| format!("Invalid spec {}", path.display()))?;
+  let is_json = path
+    .extension()
+    .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+  let spec = if is_json {
+    Json::from_str(&content).map_err(anyhow::Error::msg)
+  } else {
+    yaml::parse(&content)
+  }
+  .with_context(|| format!("Invalid spec {}", path.display()))?;
+
+  let requests = spec.get("requests").unwrap_or(&spec);
+  let Some(requests) = requests.as_array() else {
+    bail!(
+      "Invalid spec {}: expected a list of requests",
+      path.display()
+    );
+  };
+  let mut targets = Vec::new();
+  for (idx, request) in requests.iter().enumerate() {
+    targets.extend(
+      parse_request(request)
+        .with_context(|| format!("Invalid request #{} in spec {}", idx + 1, path.display()))?,
+    );
+  }
+  Ok(targets)
+}
+
+fn parse_request(request: &Json) -> anyhow::Result<Vec<Target>> {
+  let Json::Object(fields) = request else {
+    bail!("expected an object");
+  };
+  let mut url = None;
+  let mut method = Method::GET;
+  let mut options: Vec<(&str, String)> = Vec::new();
+  for (key, value) in fields {
+    match key.as_str() {
+      "url" => url = Some(value.as_str().context("url must be a string")?),
+      "method" => {
+        let name = value.as_str().context("method must be a string")?;
+        method = Method::from_str(name).context("Invalid method")?;
+      },
+      "headers" => match value {
+        Json::Object(headers) => {
+          for (name, value) in headers {
+            options.push(("header", format!("{name}: {}", to_option(value)?)));
+          }
+        },
+        Json::Array(headers) => {
+          for header in headers {
+            options.push(("header", to_option(header)?));
+          }
+        },
+        _ => bail!("headers must be an object or a list"),
+      },
+      "expect" => {
+        let Json::Object(expectations) = value else {
+          bail!("expect must be an object");
+        };
+        for (key, value) in expectations {
+          match key.as_str() {
+            "status" | "sha256" => options.push((key, to_option(value)?)),
+            _ => bail!("Unknown expectation {key}"),
+          }
+        }
+      },
+      "repeat" => options.push(("runs", to_option(value)?)),
+      "name" => {},
+      "body_file" => options.push(("body-file", to_option(value)?)),
+      key => options.push((key, to_option(value)?)),
+    }
+  }
+  let url = url.context("Missing url")?;
+  let options: Vec<(&str, &str)> = options
+    .iter()
+    .map(|(key, value)| (*key, value.as_str()))
+    .collect();
+  request_targets(method, url, &options)
+}
+
+/// A scalar as the value of a URL file option.
+fn to_option(value: &Json) -> anyhow::Result<String> {
+  match value {
+    Json::String(s) => Ok(s.clone()),
+    Json::Number(_) | Json::Bool(_) => Ok(value.to_string()),
+    _ => bail!("expected a string or number, got {value}"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(yaml: &str) -> anyhow::Result<Vec<Target>> {
+    parse_request(&yaml::parse(yaml)?)
+  }
+
+  #[test]
+  fn parses_requests() {
+    let targets = parse(
+      "url: http://a/{1..2}\nmethod: PUT\nheaders: {X-A: 1}\nbody: data\nrepeat: 3\nexpect:\n  status: 201\n",
+    )
+    .unwrap();
+    assert_eq!(targets.len(), 2);
+    let target = &targets[1];
+    assert_eq!(target.request.uri(), "http://a/2");
+    assert_eq!(target.request.method(), Method::PUT);
+    assert_eq!(target.request.headers()["x-a"], "1");
+    assert_eq!(target.runs, Some(3));
+    assert_eq!(
+      target.expect_status.map(|status| status.as_u16()),
+      Some(201)
+    );
+
+    let targets = parse("{url: 'http://a/x', headers: ['X-B: 2']}").unwrap();
+    assert_eq!(targets[0].request.headers()["x-b"], "2");
+  }
+
+  #[test]
+  fn rejects_invalid_requests() {
+    for yaml in [
+      "method: GET",
+      "url: [http://a/x]",
+      "url: http://a/x\nheaders: 1",
+      "url: http://a/x\nexpect: {speed: 1}",
+      "url: http://a/x\nunknown: 1",
+      "- http://a/x",
+    ] {
+      assert!(parse(yaml).is_err(), "{yaml}");
+    }
+  }
+}
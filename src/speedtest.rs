@@ -0,0 +1,119 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+use clap::Args;
+use console::style;
+use hyper::{Method, Uri};
+
+use spt::{
+  body::{Body, Payload, PayloadKind},
+  fetch_text,
+  ookla::{self, Server},
+  output, parse_size, printer, Measure, Target, TlsHyper, FETCH_TIMEOUT,
+};
+
+use crate::request_targets;
+
+/// Pings to rate each candidate server with, the best counting.
+const SELECTION_PINGS: u32 = 3;
+const SELECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The closest Speedtest.net server, or the given ones, tested with its TCP
+/// protocol.
+#[derive(Args)]
+pub struct OoklaArgs {
+  /// Test these servers, given as HOST:PORT, instead of the one with the
+  /// lowest latency of those speedtest.net lists for this machine
+  #[clap(long, value_name = "HOST:PORT")]
+  server: Vec<String>,
+  /// Bytes to upload, e.g. `25M`
+  #[clap(long, value_name = "SIZE", value_parser = parse_size, default_value = "25M")]
+  upload_size: u64,
+  /// Number of pings to measure the latency with
+  #[clap(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+  pings: u32,
+}
+
+impl OoklaArgs {
+  /// Targets of the download, upload and ping tests of each server, getting
+  /// the server list with `client` if none were given.
+  pub async fn targets(&self, client: &TlsHyper) -> anyhow::Result<Vec<Target>> {
+    let servers = match self.server.is_empty() {
+      true => vec![
+        closest(&ookla::parse_servers(
+          &fetch_text(client, ookla::SERVERS, FETCH_TIMEOUT).await?,
+        )?)
+        .await?,
+      ],
+      false => self
+        .server
+        .iter()
+        .map(|host| Server {
+          id: String::new(),
+          host: host.clone(),
+          name: String::new(),
+          sponsor: String::new(),
+          country: String::new(),
+        })
+        .collect(),
+    };
+
+    let mut targets = Vec::new();
+    for server in &servers {
+      for mut target in request_targets(Method::GET, &server.url("/download"), &[])? {
+        target.measure = Some(Measure::Download);
+        targets.push(target);
+      }
+      for mut target in request_targets(Method::GET, &server.url("/upload"), &[])? {
+        target.measure = Some(Measure::Upload);
+        *target.request.body_mut() =
+          Body::Generated(Payload::new(self.upload_size, PayloadKind::Zero));
+        targets.push(target);
+      }
+      for mut target in request_targets(Method::GET, &server.url("/ping"), &[])? {
+        target.measure = Some(Measure::Latency(self.pings));
+        targets.push(target);
+      }
+    }
+    Ok(targets)
+  }
+}
+
+/// The server of `servers` answering pings fastest.
+async fn closest(servers: &[Server]) -> anyhow::Result<Server> {
+  let pings = servers.iter().map(|server| async move {
+    let uri = Uri::from_str(&server.url("/")).context("Invalid server host")?;
+    ookla::ping(&uri, SELECTION_PINGS, SELECTION_TIMEOUT).await
+  });
+  let mut best: Option<(Duration, &Server)> = None;
+  for (server, ping) in servers.iter().zip(futures::future::join_all(pings).await) {
+    match ping {
+      Ok(latency) => {
+        printer::log(format_args!(
+          "{} {} ({}, {}) {}",
+          output::format_millis(latency, 1),
+          server.sponsor,
+          server.name,
+          server.country,
+          style(&server.host).dim()
+        ));
+        if best.is_none_or(|(best, _)| latency < best) {
+          best = Some((latency, server));
+        }
+      },
+      Err(err) => printer::log(style(format!("Skipping {}: {err:#}", server.host)).yellow()),
+    }
+  }
+  let Some((latency, server)) = best else {
+    bail!("No Speedtest.net server answered");
+  };
+  printer::log(format_args!(
+    "Testing {} in {}, {} (id {}) at {}",
+    server.sponsor,
+    server.name,
+    server.country,
+    server.id,
+    output::format_millis(latency, 1)
+  ));
+  Ok(server.clone())
+}
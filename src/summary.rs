@@ -0,0 +1,96 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+  output::{format_millis, RenderOptions},
+  TestResult,
+};
+
+/// Aggregate statistics over all results of a run.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+  pub tested: usize,
+  pub succeeded: usize,
+  /// Mean speed of successful results, weighted by their URL weight
+  pub mean: Option<f64>,
+  pub median: Option<u64>,
+  /// 90th percentile of the speeds, by nearest rank
+  pub p90: Option<u64>,
+  /// Median of the latencies, for tests measuring latency rather than speed
+  pub median_latency: Option<Duration>,
+  /// Bytes received over all results
+  pub bytes: u64,
+  /// Time spent testing, summed over all results
+  pub elapsed: Duration,
+  /// Decimals of the milliseconds when displaying `elapsed`
+  pub time_precision: usize,
+}
+
+impl Summary {
+  pub fn new(results: &[TestResult], options: &RenderOptions) -> Summary {
+    let speeds: Vec<(u64, f64)> = results
+      .iter()
+      .filter_map(|data| Some((data.speed?, data.weight)))
+      .collect();
+    let total_weight: f64 = speeds.iter().map(|(_, weight)| weight).sum();
+    let mean = (total_weight > 0.0).then(|| {
+      speeds
+        .iter()
+        .map(|(speed, weight)| *speed as f64 * weight)
+        .sum::<f64>()
+        / total_weight
+    });
+
+    let mut sorted: Vec<u64> = speeds.iter().map(|(speed, _)| *speed).collect();
+    sorted.sort_unstable();
+
+    let mut latencies: Vec<Duration> = results.iter().filter_map(|data| data.latency).collect();
+    latencies.sort_unstable();
+
+    Summary {
+      tested: results.len(),
+      succeeded: results.iter().filter(|data| data.succeeded()).count(),
+      mean,
+      median: percentile(&sorted, 50),
+      p90: percentile(&sorted, 90),
+      median_latency: percentile(&latencies, 50),
+      bytes: results.iter().map(|data| data.bytes).sum(),
+      elapsed: results.iter().filter_map(|data| data.elapsed).sum(),
+      time_precision: options.time_precision,
+    }
+  }
+}
+
+/// Nearest-rank percentile of ascending `sorted` values.
+fn percentile<T: Copy>(sorted: &[T], percent: usize) -> Option<T> {
+  let rank = (sorted.len() * percent).div_ceil(100).max(1);
+  sorted.get(rank - 1).copied()
+}
+
+impl fmt::Display for Summary {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let speed = |speed: u64| format!("{}/s", humansize::format_size(speed, humansize::BINARY));
+    write!(f, "{}/{} succeeded", self.succeeded, self.tested)?;
+    if let Some(mean) = self.mean {
+      write!(f, ", mean {}", speed(mean as u64))?;
+    }
+    if let Some(median) = self.median {
+      write!(f, ", median {}", speed(median))?;
+    }
+    if let Some(p90) = self.p90 {
+      write!(f, ", p90 {}", speed(p90))?;
+    }
+    if let Some(latency) = self.median_latency {
+      write!(
+        f,
+        ", median latency {}",
+        format_millis(latency, self.time_precision)
+      )?;
+    }
+    write!(
+      f,
+      ", {} in {}",
+      humansize::format_size(self.bytes, humansize::BINARY),
+      format_millis(self.elapsed, self.time_precision)
+    )
+  }
+}
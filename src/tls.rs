@@ -0,0 +1,191 @@
+use std::{
+  fmt,
+  fs::File,
+  io::BufReader,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use rustls::{
+  client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+  crypto::{self, CryptoProvider},
+  pki_types::{CertificateDer, ServerName, UnixTime},
+  version, AlertDescription, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+  SupportedProtocolVersion,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum TlsVersion {
+  #[value(name = "1.2")]
+  Tls12,
+  #[value(name = "1.3")]
+  Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TlsVersion::Tls12 => f.write_str("TLS 1.2"),
+      TlsVersion::Tls13 => f.write_str("TLS 1.3"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+  pub min_version: Option<TlsVersion>,
+  /// PEM bundle of certificates to trust on top of the native roots
+  pub ca_cert: Option<PathBuf>,
+  /// Accept any server certificate
+  pub insecure: bool,
+  /// PEM certificate chain to authenticate with
+  pub client_cert: Option<PathBuf>,
+  /// PEM private key of `client_cert`, read from that file if not given
+  pub client_key: Option<PathBuf>,
+}
+
+pub fn client_config(options: &TlsOptions) -> anyhow::Result<ClientConfig> {
+  let versions: &[&SupportedProtocolVersion] = match options.min_version {
+    Some(TlsVersion::Tls13) => &[&version::TLS13],
+    Some(TlsVersion::Tls12) | None => rustls::DEFAULT_VERSIONS,
+  };
+  let provider = Arc::new(crypto::aws_lc_rs::default_provider());
+  let builder = ClientConfig::builder_with_provider(provider.clone())
+    .with_protocol_versions(versions)
+    .context("Failed to set TLS versions")?;
+  let builder = if options.insecure {
+    builder
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+  } else {
+    builder.with_root_certificates(root_store(options)?)
+  };
+  let Some(cert) = &options.client_cert else {
+    return Ok(builder.with_no_client_auth());
+  };
+  let chain = rustls_pemfile::certs(&mut open(cert)?)
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Failed to read client certificate {}", cert.display()))?;
+  if chain.is_empty() {
+    bail!("No certificates in {}", cert.display());
+  }
+  let key_path = options.client_key.as_ref().unwrap_or(cert);
+  let key = rustls_pemfile::private_key(&mut open(key_path)?)
+    .with_context(|| format!("Failed to read private key {}", key_path.display()))?
+    .with_context(|| format!("No private key in {}", key_path.display()))?;
+  builder
+    .with_client_auth_cert(chain, key)
+    .context("Invalid client certificate or key")
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+  let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+  Ok(BufReader::new(file))
+}
+
+/// Native roots plus the ones from `--cacert`, which may stand in for them.
+fn root_store(options: &TlsOptions) -> anyhow::Result<RootCertStore> {
+  let mut roots = RootCertStore::empty();
+  let native = rustls_native_certs::load_native_certs();
+  if let Ok(certs) = &native {
+    roots.add_parsable_certificates(certs.iter().cloned());
+  }
+  if let Some(path) = &options.ca_cert {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+      .collect::<Result<Vec<_>, _>>()
+      .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+      bail!("No valid certificates in CA bundle {}", path.display());
+    }
+  } else if roots.is_empty() {
+    return Err(match native {
+      Err(err) => anyhow::Error::new(err),
+      Ok(_) => anyhow::anyhow!("No valid native root certificates found"),
+    })
+    .context("Failed to load native root certificates");
+  }
+  Ok(roots)
+}
+
+/// Skips certificate checks for `--insecure`, while still checking handshake
+/// signatures so the connection works like a verified one.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.0.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+/// Whether a handshake failure looks like the peer rejecting our protocol versions.
+pub fn is_version_mismatch(err: &rustls::Error) -> bool {
+  matches!(
+    err,
+    rustls::Error::PeerIncompatible(_)
+      | rustls::Error::AlertReceived(
+        AlertDescription::ProtocolVersion | AlertDescription::HandshakeFailure
+      )
+  )
+}
+
+/// Finds the [`rustls::Error`] behind a connection failure, if it was a TLS error.
+pub fn find_tls_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a rustls::Error> {
+  let mut current = Some(err);
+  while let Some(err) = current {
+    if let Some(tls) = err.downcast_ref::<rustls::Error>() {
+      return Some(tls);
+    }
+    // `io::Error` skips its wrapped error in `source()`, so look inside explicitly.
+    current = match err
+      .downcast_ref::<std::io::Error>()
+      .and_then(|io| io.get_ref())
+    {
+      Some(inner) => Some(inner),
+      None => err.source(),
+    };
+  }
+  None
+}
@@ -0,0 +1,291 @@
+use std::{
+  fmt::Write as _,
+  future::Future,
+  sync::Arc,
+  thread::{self, JoinHandle},
+  time::Duration,
+};
+
+use anyhow::bail;
+use console::{style, Key, Term};
+use hyper::{Method, Uri};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use spt::{
+  output::{self, RenderOptions},
+  printer::Printer,
+  Target, TestResult,
+};
+
+const HELP: &str = "↑/↓ select, s skip, q cancel all";
+/// Seconds of throughput shown for each test
+const GRAPH_WIDTH: usize = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+  Waiting,
+  Running,
+  Done,
+  Skipped,
+}
+
+struct Entry {
+  label: String,
+  uri: Uri,
+  method: Method,
+  printer: Printer,
+  status: Status,
+  skip: Arc<Notify>,
+  /// Bytes received in each second the test ran
+  samples: Vec<u64>,
+  /// Position of the printer's bars when the last sample was taken
+  sampled: u64,
+}
+
+struct State {
+  entries: Vec<Entry>,
+  selected: usize,
+  /// Ranking of the tests finished so far
+  leaderboard: String,
+  finished: bool,
+}
+
+/// Full screen view of a run, with the ranking so far, a throughput graph
+/// for each test and keys to skip tests.
+pub struct Dashboard {
+  state: Arc<Mutex<State>>,
+  term: Term,
+  draw: tokio::task::JoinHandle<()>,
+  keys: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+  /// Switches the terminal on stderr to the dashboard.
+  pub fn start(targets: &[Target], render_options: &RenderOptions) -> anyhow::Result<Dashboard> {
+    let term = Term::stderr();
+    if !term.is_term() {
+      bail!("--tui needs a terminal on stderr");
+    }
+    let entries = targets
+      .iter()
+      .map(|target| Entry {
+        label: render_options.display_uri(target.request.uri()),
+        uri: target.request.uri().clone(),
+        method: target.request.method().clone(),
+        printer: Printer::observed(),
+        status: Status::Waiting,
+        skip: Arc::default(),
+        samples: Vec::new(),
+        sampled: 0,
+      })
+      .collect();
+    let state = Arc::new(Mutex::new(State {
+      entries,
+      selected: 0,
+      leaderboard: String::new(),
+      finished: false,
+    }));
+    term.write_str("\x1b[?1049h")?;
+    term.hide_cursor()?;
+
+    let draw = tokio::spawn({
+      let (state, term) = (state.clone(), term.clone());
+      async move {
+        let mut ticks = tokio::time::interval(Duration::from_millis(250));
+        for tick in 0u64.. {
+          ticks.tick().await;
+          let mut state = state.lock();
+          if tick % 4 == 0 {
+            state.sample();
+          }
+          let _ = term.write_str(&state.frame(term.size()));
+        }
+      }
+    });
+    let keys = thread::spawn({
+      let (state, term) = (state.clone(), term.clone());
+      move || read_keys(&state, &term)
+    });
+    Ok(Dashboard {
+      state,
+      term,
+      draw,
+      keys: Some(keys),
+    })
+  }
+
+  /// Where test `id` should report its progress.
+  pub fn printer(&self, id: usize) -> Printer {
+    self.state.lock().entries[id].printer.clone()
+  }
+
+  /// Runs `test` for target `id`, unless the user skips it.
+  pub async fn run(&self, id: usize, test: impl Future<Output = TestResult>) -> TestResult {
+    let skip = {
+      let mut state = self.state.lock();
+      let entry = &mut state.entries[id];
+      if entry.status == Status::Skipped {
+        return entry.skipped(id);
+      }
+      entry.status = Status::Running;
+      entry.sampled = entry.printer.position();
+      entry.samples.clear();
+      entry.skip.clone()
+    };
+    tokio::select! {
+      data = test => {
+        let mut state = self.state.lock();
+        let entry = &mut state.entries[id];
+        if entry.status == Status::Running {
+          entry.status = Status::Done;
+        }
+        data
+      },
+      // Dropping the test aborts its download
+      _ = skip.notified() => self.state.lock().entries[id].skipped(id),
+    }
+  }
+
+  /// Shows `results`, sorted from fastest to slowest, as the ranking so far.
+  pub fn set_results(&self, results: &[TestResult], render_options: &RenderOptions) {
+    self.state.lock().leaderboard = output::table(results, render_options).to_string();
+  }
+
+  /// Waits for a key once all tests are done, then restores the terminal.
+  pub async fn close(mut self) -> anyhow::Result<()> {
+    self.state.lock().finished = true;
+    if let Some(keys) = self.keys.take() {
+      tokio::task::spawn_blocking(move || keys.join())
+        .await?
+        .map_err(|_| anyhow::anyhow!("Key reader panicked"))?;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for Dashboard {
+  fn drop(&mut self) {
+    self.draw.abort();
+    let _ = self.term.show_cursor();
+    let _ = self.term.write_str("\x1b[?1049l");
+  }
+}
+
+impl Entry {
+  fn skipped(&self, id: usize) -> TestResult {
+    TestResult {
+      id,
+      uri: self.uri.clone(),
+      method: self.method.clone(),
+      error: Some("Skipped".to_string()),
+      ..Default::default()
+    }
+  }
+}
+
+impl State {
+  /// Records the bytes running tests received since the last sample.
+  fn sample(&mut self) {
+    for entry in &mut self.entries {
+      if entry.status == Status::Running {
+        let position = entry.printer.position();
+        entry.samples.push(position.saturating_sub(entry.sampled));
+        entry.sampled = position;
+      }
+    }
+  }
+
+  fn frame(&self, (height, width): (u16, u16)) -> String {
+    let done = self
+      .entries
+      .iter()
+      .filter(|entry| matches!(entry.status, Status::Done | Status::Skipped))
+      .count();
+    let help = if self.finished {
+      "finished, press any key to exit"
+    } else {
+      HELP
+    };
+    let mut lines = vec![format!(
+      "{} {done}/{} tested  {}",
+      style("spt").magenta().bold(),
+      self.entries.len(),
+      style(help).dim()
+    )];
+    lines.extend(self.leaderboard.lines().map(str::to_string));
+    lines.push(String::new());
+
+    // Scroll the tests so the selected one stays in view
+    let rows = (height as usize).saturating_sub(lines.len() + 1).max(1);
+    let first = self.selected.saturating_sub(rows - 1);
+    for (idx, entry) in self.entries.iter().enumerate().skip(first).take(rows) {
+      let marker = if idx == self.selected { ">" } else { " " };
+      let status = match entry.status {
+        Status::Waiting => style("waiting").dim(),
+        Status::Running => style("running").cyan(),
+        Status::Done => style("done   ").green(),
+        Status::Skipped => style("skipped").yellow(),
+      };
+      let recent = &entry.samples[entry.samples.len().saturating_sub(GRAPH_WIDTH)..];
+      let speed = match (entry.status, recent.last()) {
+        (Status::Running, Some(bytes)) => output::format_speed(Some(*bytes)),
+        _ => String::new(),
+      };
+      lines.push(format!(
+        "{marker} {status} {:>12} {} {}",
+        speed,
+        // Padded before styling, which would count towards the width
+        style(format!("{:<GRAPH_WIDTH$}", output::sparkline(recent))).cyan(),
+        entry.label
+      ));
+    }
+    if let Some(line) = self
+      .entries
+      .get(self.selected)
+      .and_then(|entry| entry.printer.last_line())
+    {
+      lines.push(style(line).dim().to_string());
+    }
+
+    let mut frame = String::from("\x1b[H");
+    for line in lines.iter().take(height as usize) {
+      let _ = write!(
+        frame,
+        "{}\x1b[K\r\n",
+        console::truncate_str(line, width as usize, "…")
+      );
+    }
+    frame.push_str("\x1b[J");
+    frame
+  }
+}
+
+/// Handles keys until one is pressed after the run finished.
+fn read_keys(state: &Mutex<State>, term: &Term) {
+  // Fails without a terminal to read from, leaving the tests to run through
+  while let Ok(key) = term.read_key_raw() {
+    let mut state = state.lock();
+    if state.finished {
+      return;
+    }
+    let last = state.entries.len().saturating_sub(1);
+    match key {
+      Key::ArrowUp | Key::Char('k') => state.selected = state.selected.saturating_sub(1),
+      Key::ArrowDown | Key::Char('j') => state.selected = (state.selected + 1).min(last),
+      Key::Char('s') => {
+        let selected = state.selected;
+        skip(&mut state.entries[selected]);
+      },
+      Key::Char('q') | Key::Escape | Key::CtrlC => state.entries.iter_mut().for_each(skip),
+      _ => {},
+    }
+  }
+}
+
+fn skip(entry: &mut Entry) {
+  if matches!(entry.status, Status::Waiting | Status::Running) {
+    entry.status = Status::Skipped;
+    entry.skip.notify_one();
+  }
+}
@@ -0,0 +1,319 @@
+use anyhow::{bail, Context};
+use hyper::Uri;
+
+/// Normalizes a URI following RFC 3986 section 6.2.2 and 6.2.3: scheme and
+/// host are lowercased, default ports are dropped, percent-encodings are
+/// uppercased (and decoded for unreserved characters), dot segments are
+/// removed and an empty path becomes `/`.
+pub fn normalize(uri: &Uri) -> anyhow::Result<Uri> {
+  let Some(scheme) = uri.scheme_str() else {
+    return Ok(uri.clone());
+  };
+  let scheme = scheme.to_ascii_lowercase();
+
+  let mut normalized = format!("{scheme}://");
+  if let Some(authority) = uri.authority() {
+    if let Some((userinfo, _)) = authority.as_str().rsplit_once('@') {
+      normalized.push_str(&normalize_percent_encoding(userinfo));
+      normalized.push('@');
+    }
+    normalized.push_str(&uri.host().unwrap_or_default().to_ascii_lowercase());
+    let default_port = match scheme.as_str() {
+      "http" | "ws" => Some(80),
+      "https" | "wss" => Some(443),
+      _ => None,
+    };
+    match uri.port_u16() {
+      Some(port) if Some(port) != default_port => normalized.push_str(&format!(":{port}")),
+      _ => {},
+    }
+  }
+
+  let path = remove_dot_segments(&normalize_percent_encoding(uri.path()));
+  normalized.push_str(if path.is_empty() { "/" } else { &path });
+  if let Some(query) = uri.query() {
+    normalized.push('?');
+    normalized.push_str(&normalize_percent_encoding(query));
+  }
+
+  normalized
+    .parse()
+    .with_context(|| format!("Failed to normalize URL {uri}"))
+}
+
+/// Resolves a URI reference, like the value of a `Location` header, against
+/// `base` following RFC 3986 section 5.2.
+pub fn resolve(base: &Uri, reference: &str) -> anyhow::Result<Uri> {
+  // Fragments are never sent to the server
+  let reference = reference.split('#').next().unwrap_or_default();
+  let scheme = base.scheme_str().unwrap_or("http");
+  let authority = base.authority().map(|a| a.as_str()).unwrap_or_default();
+  let has_scheme = reference
+    .split_once(':')
+    .is_some_and(|(scheme, _)| !scheme.is_empty() && !scheme.contains(['/', '?']));
+  let (path, query) = match reference.find('?') {
+    Some(idx) => reference.split_at(idx),
+    None => (reference, ""),
+  };
+
+  let resolved = if has_scheme {
+    reference.to_string()
+  } else if let Some(rest) = reference.strip_prefix("//") {
+    format!("{scheme}://{rest}")
+  } else if path.starts_with('/') {
+    format!("{scheme}://{authority}{}{query}", remove_dot_segments(path))
+  } else if path.is_empty() {
+    // Without a query of its own the reference keeps that of the base
+    let query = match (query, base.query()) {
+      ("", Some(base)) => format!("?{base}"),
+      (query, _) => query.to_string(),
+    };
+    format!("{scheme}://{authority}{}{query}", base.path())
+  } else {
+    let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+    let path = remove_dot_segments(&format!("{dir}/{path}"));
+    format!("{scheme}://{authority}{path}{query}")
+  };
+  resolved
+    .parse()
+    .with_context(|| format!("Invalid URI reference {reference}"))
+}
+
+/// Appends `key=value` to the query string of a URI.
+pub fn append_query(uri: &Uri, key: &str, value: &str) -> anyhow::Result<Uri> {
+  let mut parts = uri.clone().into_parts();
+  let path_and_query = match uri.query() {
+    Some(query) if !query.is_empty() => format!("{}?{query}&{key}={value}", uri.path()),
+    _ => format!("{}?{key}={value}", uri.path()),
+  };
+  parts.path_and_query = Some(
+    path_and_query
+      .parse()
+      .with_context(|| format!("Failed to add query parameter to {uri}"))?,
+  );
+  Uri::from_parts(parts).with_context(|| format!("Failed to add query parameter to {uri}"))
+}
+
+/// Formats a URI without its query string.
+pub fn strip_query(uri: &Uri) -> String {
+  let mut stripped = String::new();
+  if let Some(scheme) = uri.scheme_str() {
+    stripped.push_str(scheme);
+    stripped.push_str("://");
+  }
+  if let Some(authority) = uri.authority() {
+    stripped.push_str(authority.as_str());
+  }
+  stripped.push_str(uri.path());
+  stripped
+}
+
+/// Name to save the resource at `uri` under: the last path segment, decoded,
+/// or `index.html` for paths ending in a slash.
+pub fn file_name(uri: &Uri) -> String {
+  let segment = uri.path().rsplit('/').next().unwrap_or_default();
+  let name = percent_decode(segment).replace(['/', '\\'], "_");
+  match name.as_str() {
+    "" | "." | ".." => "index.html".to_string(),
+    _ => name,
+  }
+}
+
+/// Upper bound of URLs a single template may expand to.
+const MAX_EXPANDED: usize = 10_000;
+
+/// Expands numeric ranges like `file-{1..10}.bin` into one URL per number,
+/// similar to curl's globbing. Several ranges produce every combination, and
+/// a zero-padded start like `{01..10}` pads all numbers to its width.
+pub fn expand_ranges(template: &str) -> anyhow::Result<Vec<String>> {
+  let mut expanded = vec![String::new()];
+  let mut rest = template;
+  while let Some((open, close)) = rest
+    .find('{')
+    .and_then(|open| Some((open, open + rest[open..].find('}')?)))
+  {
+    let (literal, range) = (&rest[..open], &rest[open + 1..close]);
+    for url in &mut expanded {
+      url.push_str(literal);
+    }
+    let Some((start_str, end_str)) = range.split_once("..") else {
+      for url in &mut expanded {
+        url.push_str(&rest[open..=close]);
+      }
+      rest = &rest[close + 1..];
+      continue;
+    };
+    let start: u64 = start_str
+      .parse()
+      .with_context(|| format!("Invalid range start in {{{range}}}"))?;
+    let end: u64 = end_str
+      .parse()
+      .with_context(|| format!("Invalid range end in {{{range}}}"))?;
+    if start > end {
+      bail!("Range {{{range}}} is empty, the start is after the end");
+    }
+    let count = usize::try_from(end - start + 1).unwrap_or(usize::MAX);
+    if count.saturating_mul(expanded.len()) > MAX_EXPANDED {
+      bail!("URL expands to more than {MAX_EXPANDED} URLs");
+    }
+    let width = if start_str.len() > 1 && start_str.starts_with('0') {
+      start_str.len()
+    } else {
+      0
+    };
+    expanded = expanded
+      .iter()
+      .flat_map(|url| (start..=end).map(move |num| format!("{url}{num:0width$}")))
+      .collect();
+    rest = &rest[close + 1..];
+  }
+  for url in &mut expanded {
+    url.push_str(rest);
+  }
+  Ok(expanded)
+}
+
+/// Decodes percent-encoded bytes, leaving invalid escapes as they are.
+pub fn percent_decode(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut idx = 0;
+  while idx < bytes.len() {
+    let hex = bytes
+      .get(idx + 1..idx + 3)
+      .and_then(|hex| std::str::from_utf8(hex).ok())
+      .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    match (bytes[idx], hex) {
+      (b'%', Some(decoded)) => {
+        out.push(decoded);
+        idx += 3;
+      },
+      (byte, _) => {
+        out.push(byte);
+        idx += 1;
+      },
+    }
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_unreserved(byte: u8) -> bool {
+  byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Uppercases percent-encoded triplets and decodes those of unreserved characters.
+fn normalize_percent_encoding(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = String::with_capacity(s.len());
+  let mut idx = 0;
+  while idx < bytes.len() {
+    let hex = bytes
+      .get(idx + 1..idx + 3)
+      .and_then(|hex| std::str::from_utf8(hex).ok())
+      .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    match (bytes[idx], hex) {
+      (b'%', Some(decoded)) if is_unreserved(decoded) => {
+        out.push(decoded as char);
+        idx += 3;
+      },
+      (b'%', Some(decoded)) => {
+        out.push_str(&format!("%{decoded:02X}"));
+        idx += 3;
+      },
+      (byte, _) => {
+        out.push(byte as char);
+        idx += 1;
+      },
+    }
+  }
+  out
+}
+
+/// The `remove_dot_segments` algorithm of RFC 3986 section 5.2.4.
+fn remove_dot_segments(path: &str) -> String {
+  let mut output: Vec<&str> = Vec::new();
+  let segments: Vec<&str> = path.split('/').collect();
+  for (idx, segment) in segments.iter().enumerate() {
+    let is_last = idx == segments.len() - 1;
+    match *segment {
+      "." => {
+        if is_last {
+          output.push("");
+        }
+      },
+      ".." => {
+        if output.len() > 1 {
+          output.pop();
+        }
+        if is_last {
+          output.push("");
+        }
+      },
+      segment => output.push(segment),
+    }
+  }
+  output.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_references() {
+    let base = Uri::from_static("http://a/b/c/d;p?q");
+    // The examples of RFC 3986 section 5.4.1
+    for (reference, resolved) in [
+      ("g", "http://a/b/c/g"),
+      ("/g", "http://a/g"),
+      ("//g", "http://g/"),
+      ("?y", "http://a/b/c/d;p?y"),
+      ("g?y", "http://a/b/c/g?y"),
+      ("#s", "http://a/b/c/d;p?q"),
+      ("", "http://a/b/c/d;p?q"),
+      ("../g", "http://a/b/g"),
+      ("https://g/h", "https://g/h"),
+    ] {
+      assert_eq!(resolve(&base, reference).unwrap(), resolved, "{reference}");
+    }
+  }
+
+  #[test]
+  fn expands_ranges() {
+    assert_eq!(
+      expand_ranges("http://a/file-{1..3}.bin").unwrap(),
+      [
+        "http://a/file-1.bin",
+        "http://a/file-2.bin",
+        "http://a/file-3.bin"
+      ]
+    );
+    assert_eq!(
+      expand_ranges("http://a/{08..10}").unwrap(),
+      ["http://a/08", "http://a/09", "http://a/10"]
+    );
+    assert_eq!(
+      expand_ranges("http://a/{1..2}/{3..4}").unwrap(),
+      [
+        "http://a/1/3",
+        "http://a/1/4",
+        "http://a/2/3",
+        "http://a/2/4"
+      ]
+    );
+    // Braces without a range are kept as they are
+    assert_eq!(
+      expand_ranges("http://a/{x}?q={5..5}").unwrap(),
+      ["http://a/{x}?q=5"]
+    );
+    assert_eq!(expand_ranges("http://a/{1..").unwrap(), ["http://a/{1.."]);
+  }
+
+  #[test]
+  fn rejects_invalid_ranges() {
+    assert!(expand_ranges("http://a/{3..1}").is_err());
+    assert!(expand_ranges("http://a/{a..z}").is_err());
+    assert!(expand_ranges("http://a/{1..}").is_err());
+    assert!(expand_ranges("http://a/{1..100}/{1..101}").is_err());
+  }
+}
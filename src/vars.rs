@@ -0,0 +1,65 @@
+use std::env;
+
+use anyhow::{bail, Context};
+
+/// Values of the `${NAME}` placeholders in URL files, taken from `--var`
+/// flags first and the environment second.
+#[derive(Debug, Default)]
+pub struct Vars {
+  vars: Vec<(String, String)>,
+}
+
+impl Vars {
+  pub fn new(vars: &[(String, String)]) -> Vars {
+    Vars {
+      vars: vars.to_vec(),
+    }
+  }
+
+  fn lookup(&self, name: &str) -> Option<String> {
+    // Later flags override earlier ones, like config defaults do
+    match self.vars.iter().rev().find(|(key, _)| key == name) {
+      Some((_, value)) => Some(value.clone()),
+      None => env::var(name).ok(),
+    }
+  }
+
+  /// Replaces `${NAME}` with the value of `NAME` and `${NAME:-DEFAULT}` with
+  /// `DEFAULT` if it is unset, `$${` keeps a literal `${`.
+  pub fn expand(&self, text: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+      expanded.push_str(&rest[..start]);
+      rest = &rest[start..];
+      if let Some(escaped) = rest.strip_prefix("$${") {
+        expanded.push_str("${");
+        rest = escaped;
+        continue;
+      }
+      let Some(placeholder) = rest.strip_prefix("${") else {
+        expanded.push('$');
+        rest = &rest[1..];
+        continue;
+      };
+      let end = placeholder
+        .find('}')
+        .context("Unterminated ${ in variable")?;
+      let (name, default) = match placeholder[..end].split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (&placeholder[..end], None),
+      };
+      if name.is_empty() || !name.chars().all(|ch| ch.is_alphanumeric() || ch == '_') {
+        bail!("Invalid variable name {name:?}");
+      }
+      match (self.lookup(name), default) {
+        (Some(value), _) => expanded.push_str(&value),
+        (None, Some(default)) => expanded.push_str(default),
+        (None, None) => bail!("Variable {name} is not set, give it with --var {name}=VALUE"),
+      }
+      rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+  }
+}
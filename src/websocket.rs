@@ -0,0 +1,110 @@
+use std::time::Instant;
+
+use anyhow::{bail, Context};
+use aws_lc_rs::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use console::style;
+use hyper::{header, http::uri::Scheme, Request, StatusCode, Uri};
+
+use crate::{output, printer::Printer, random_u64, Body, TestOptions, TestResult, TlsHyper};
+
+/// Appended to the key before hashing it into `Sec-WebSocket-Accept`, see RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub fn is_websocket(uri: &Uri) -> bool {
+  matches!(uri.scheme_str(), Some("ws" | "wss"))
+}
+
+/// Performs the WebSocket opening handshake and measures the time until the
+/// server switches protocols, without exchanging any frames.
+pub async fn test(
+  client: &TlsHyper,
+  id: usize,
+  request: Request<Body>,
+  options: &TestOptions,
+  printer: &Printer,
+) -> anyhow::Result<TestResult> {
+  let uri = request.uri().clone();
+  printer.println(format_args!(
+    "{} {} {}",
+    style("==>").magenta(),
+    style("UPGRADE").green(),
+    uri,
+  ));
+
+  let (mut parts, body) = request.into_parts();
+  parts.uri = http_uri(&uri)?;
+  let key = STANDARD.encode([random_u64().to_le_bytes(), random_u64().to_le_bytes()].concat());
+  let headers = &mut parts.headers;
+  headers.insert(header::CONNECTION, "Upgrade".parse()?);
+  headers.insert(header::UPGRADE, "websocket".parse()?);
+  headers.insert(header::SEC_WEBSOCKET_VERSION, "13".parse()?);
+  headers.insert(header::SEC_WEBSOCKET_KEY, key.parse()?);
+  let request = Request::from_parts(parts, body);
+
+  let start = Instant::now();
+  let resp = tokio::time::timeout(options.request_timeout, client.request(request))
+    .await
+    .with_context(|| format!("Timed out for {:?}", options.request_timeout))?
+    .context("Failed to send request")?;
+  let latency = start.elapsed();
+
+  printer.println(format_args!(
+    "{:?} {} {}",
+    resp.version(),
+    resp.status(),
+    output::format_millis(latency, options.time_precision)
+  ));
+  printer.println("");
+
+  if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+    bail!("Server did not switch protocols to WebSocket");
+  }
+  // Proves the server understood the handshake rather than a cache replaying one
+  let expected = accept(&key);
+  match resp.headers().get(header::SEC_WEBSOCKET_ACCEPT) {
+    Some(value) if value.as_bytes() == expected.as_bytes() => {},
+    Some(value) => bail!(
+      "Server answered with Sec-WebSocket-Accept {}, expected {expected}",
+      String::from_utf8_lossy(value.as_bytes())
+    ),
+    None => bail!("Server switched protocols without Sec-WebSocket-Accept"),
+  }
+
+  Ok(TestResult {
+    id,
+    uri,
+    latency: Some(latency),
+    ..Default::default()
+  })
+}
+
+/// The `Sec-WebSocket-Accept` a server must answer `key` with.
+fn accept(key: &str) -> String {
+  let hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, format!("{key}{GUID}").as_bytes());
+  STANDARD.encode(hash.as_ref())
+}
+
+/// Maps `ws://` and `wss://` URIs to the HTTP URIs the handshake is sent to.
+fn http_uri(uri: &Uri) -> anyhow::Result<Uri> {
+  let mut parts = uri.clone().into_parts();
+  parts.scheme = Some(match uri.scheme_str() {
+    Some("wss") => Scheme::HTTPS,
+    _ => Scheme::HTTP,
+  });
+  Uri::from_parts(parts).context("Invalid WebSocket URL")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn accepts_like_rfc_6455() {
+    // The example of section 1.3
+    assert_eq!(
+      accept("dGhlIHNhbXBsZSBub25jZQ=="),
+      "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+  }
+}
@@ -0,0 +1,481 @@
+use anyhow::{bail, Context};
+
+use spt::json::{Json, MAX_DEPTH};
+
+/// Parses the YAML subset request specs are written in: block mappings and
+/// sequences, plain and quoted scalars, `|` and `>` block scalars, and flow
+/// collections like `{url: x, runs: 2}` on a single line. Anchors, tags and
+/// multiple documents are not supported.
+pub fn parse(content: &str) -> anyhow::Result<Json> {
+  let lines = content
+    .lines()
+    .enumerate()
+    .filter(|(_, raw)| raw.trim() != "---")
+    .map(|(idx, raw)| {
+      let text = strip_comment(raw).trim_end();
+      let trimmed = text.trim_start();
+      Line {
+        num: idx + 1,
+        indent: text.len() - trimmed.len(),
+        text: trimmed,
+        raw,
+      }
+    })
+    .collect();
+  let mut parser = Parser {
+    lines,
+    pos: 0,
+    depth: 0,
+  };
+  let Some(first) = parser.peek() else {
+    return Ok(Json::Null);
+  };
+  let value = parser.block(first.indent)?;
+  if let Some(line) = parser.peek() {
+    bail!("Line {}: unexpected indentation", line.num);
+  }
+  Ok(value)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line<'a> {
+  num: usize,
+  indent: usize,
+  /// The line without indentation and comments, empty for blank lines
+  text: &'a str,
+  raw: &'a str,
+}
+
+struct Parser<'a> {
+  lines: Vec<Line<'a>>,
+  pos: usize,
+  /// Blocks the parser is in
+  depth: usize,
+}
+
+impl<'a> Parser<'a> {
+  /// The next line which isn't blank.
+  fn peek(&mut self) -> Option<Line<'a>> {
+    while self.lines.get(self.pos)?.text.is_empty() {
+      self.pos += 1;
+    }
+    self.lines.get(self.pos).copied()
+  }
+
+  fn block(&mut self, indent: usize) -> anyhow::Result<Json> {
+    let Some(line) = self.peek() else {
+      return Ok(Json::Null);
+    };
+    if self.depth >= MAX_DEPTH {
+      bail!("Line {}: nested deeper than {MAX_DEPTH} levels", line.num);
+    }
+    self.depth += 1;
+    let value = self.block_at(line, indent);
+    self.depth -= 1;
+    value
+  }
+
+  fn block_at(&mut self, line: Line<'a>, indent: usize) -> anyhow::Result<Json> {
+    if line.text.starts_with(['[', '{']) {
+      self.pos += 1;
+      scalar(line.text).with_context(|| format!("Line {}", line.num))
+    } else if is_item(line.text) {
+      self.sequence(indent)
+    } else if split_key(line.text).is_some() {
+      self.mapping(indent)
+    } else {
+      self.pos += 1;
+      scalar(line.text).with_context(|| format!("Line {}", line.num))
+    }
+  }
+
+  fn sequence(&mut self, indent: usize) -> anyhow::Result<Json> {
+    let mut items = Vec::new();
+    while let Some(line) = self.peek() {
+      if line.indent < indent || (line.indent == indent && !is_item(line.text)) {
+        break;
+      }
+      if line.indent > indent {
+        bail!("Line {}: unexpected indentation", line.num);
+      }
+      let rest = line.text[1..].trim_start();
+      if rest.is_empty() {
+        self.pos += 1;
+        items.push(self.nested(indent)?);
+        continue;
+      }
+      // The item's content is parsed as if it started a line of its own
+      let column = line.indent + line.text.len() - rest.len();
+      self.lines[self.pos].indent = column;
+      self.lines[self.pos].text = rest;
+      items.push(self.block(column)?);
+    }
+    Ok(Json::Array(items))
+  }
+
+  fn mapping(&mut self, indent: usize) -> anyhow::Result<Json> {
+    let mut members = Vec::new();
+    while let Some(line) = self.peek() {
+      if line.indent < indent {
+        break;
+      }
+      if line.indent > indent {
+        bail!("Line {}: unexpected indentation", line.num);
+      }
+      let (key, value) =
+        split_key(line.text).with_context(|| format!("Line {}: expected key: value", line.num))?;
+      let key = match scalar(key) {
+        Ok(Json::String(key)) => key,
+        _ => key.to_string(),
+      };
+      self.pos += 1;
+      let value = match value {
+        "" => match self.peek() {
+          // Sequences may sit at the indentation of their key
+          Some(next) if next.indent == indent && is_item(next.text) => self.sequence(indent)?,
+          _ => self.nested(indent)?,
+        },
+        "|" | "|-" | ">" | ">-" => self.block_scalar(indent, value),
+        value => scalar(value).with_context(|| format!("Line {}", line.num))?,
+      };
+      members.push((key, value));
+    }
+    Ok(Json::Object(members))
+  }
+
+  /// The block indented deeper than `indent`, null if there is none.
+  fn nested(&mut self, indent: usize) -> anyhow::Result<Json> {
+    match self.peek() {
+      Some(next) if next.indent > indent => self.block(next.indent),
+      _ => Ok(Json::Null),
+    }
+  }
+
+  /// Lines indented deeper than `indent` taken verbatim, kept apart with
+  /// newlines by `|` and joined with spaces by `>`.
+  fn block_scalar(&mut self, indent: usize, style: &str) -> Json {
+    let mut lines = Vec::new();
+    let mut column = None;
+    while let Some(line) = self.lines.get(self.pos) {
+      let blank = line.raw.trim().is_empty();
+      let line_indent = line.raw.len() - line.raw.trim_start().len();
+      if !blank && line_indent <= indent {
+        break;
+      }
+      let column = *column.get_or_insert(line_indent);
+      lines.push(line.raw.get(column..).unwrap_or_default());
+      self.pos += 1;
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+      lines.pop();
+    }
+    let mut value = if style.starts_with('|') {
+      lines.join("\n")
+    } else {
+      lines.join(" ")
+    };
+    if !style.ends_with('-') {
+      value.push('\n');
+    }
+    Json::String(value)
+  }
+}
+
+fn is_item(text: &str) -> bool {
+  text == "-" || text.starts_with("- ")
+}
+
+/// Splits `key: value`, the value being empty if it is on the next lines.
+fn split_key(text: &str) -> Option<(&str, &str)> {
+  if text.starts_with(['"', '\'']) {
+    let quote = text.as_bytes()[0] as char;
+    let end = text[1..].find(quote)? + 2;
+    let rest = text[end..].strip_prefix(':')?;
+    return (rest.is_empty() || rest.starts_with(' ')).then(|| (&text[..end], rest.trim()));
+  }
+  if let Some(key) = text.strip_suffix(':') {
+    if !key.contains(": ") {
+      return Some((key, ""));
+    }
+  }
+  text
+    .split_once(": ")
+    .map(|(key, value)| (key.trim_end(), value.trim()))
+}
+
+fn scalar(text: &str) -> anyhow::Result<Json> {
+  if text.starts_with(['[', '{']) {
+    let mut flow = Flow {
+      input: text,
+      pos: 0,
+      depth: 0,
+    };
+    let value = flow.value()?;
+    flow.skip_whitespace();
+    if flow.pos < text.len() {
+      bail!("unexpected {} after the value", &text[flow.pos..]);
+    }
+    return Ok(value);
+  }
+  if text.starts_with('"') {
+    return text
+      .parse()
+      .map_err(anyhow::Error::msg)
+      .with_context(|| format!("invalid value {text}, quoted strings must be valid JSON"));
+  }
+  if let Some(quoted) = text.strip_prefix('\'') {
+    let quoted = quoted.strip_suffix('\'').context("unterminated string")?;
+    return Ok(Json::String(quoted.replace("''", "'")));
+  }
+  Ok(match text {
+    "null" | "~" => Json::Null,
+    "true" => Json::Bool(true),
+    "false" => Json::Bool(false),
+    text => match text.parse() {
+      Ok(num) if text.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-') => Json::Number(num),
+      _ => Json::String(text.to_string()),
+    },
+  })
+}
+
+/// Removes a `#` comment, which in YAML has to follow whitespace.
+fn strip_comment(line: &str) -> &str {
+  let mut quote = None;
+  let mut prev = ' ';
+  for (idx, ch) in line.char_indices() {
+    match (quote, ch) {
+      (Some(q), ch) if ch == q => quote = None,
+      (None, '"' | '\'') if prev == ' ' || prev == '-' || prev == ':' => quote = Some(ch),
+      (None, '#') if prev.is_whitespace() => return &line[..idx],
+      _ => {},
+    }
+    prev = ch;
+  }
+  line
+}
+
+/// Parses a flow collection, in which plain scalars end at `,`, `]` or `}`,
+/// and keys at `:`.
+struct Flow<'a> {
+  input: &'a str,
+  pos: usize,
+  depth: usize,
+}
+
+impl Flow<'_> {
+  fn skip_whitespace(&mut self) {
+    let rest = &self.input[self.pos..];
+    self.pos += rest.len() - rest.trim_start().len();
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.input.as_bytes().get(self.pos).copied()
+  }
+
+  fn value(&mut self) -> anyhow::Result<Json> {
+    self.skip_whitespace();
+    match self.peek() {
+      Some(open @ (b'[' | b'{')) => {
+        if self.depth >= MAX_DEPTH {
+          bail!("nested deeper than {MAX_DEPTH} levels");
+        }
+        self.depth += 1;
+        self.pos += 1;
+        let value = match open {
+          b'[' => self.sequence(),
+          _ => self.mapping(),
+        };
+        self.depth -= 1;
+        value
+      },
+      _ => self.scalar(b",]}"),
+    }
+  }
+
+  fn sequence(&mut self) -> anyhow::Result<Json> {
+    let mut items = Vec::new();
+    loop {
+      self.skip_whitespace();
+      if self.peek() == Some(b']') {
+        self.pos += 1;
+        return Ok(Json::Array(items));
+      }
+      items.push(self.value()?);
+      if !self.separator(b']')? {
+        self.pos += 1;
+        return Ok(Json::Array(items));
+      }
+    }
+  }
+
+  fn mapping(&mut self) -> anyhow::Result<Json> {
+    let mut members = Vec::new();
+    loop {
+      self.skip_whitespace();
+      if self.peek() == Some(b'}') {
+        self.pos += 1;
+        return Ok(Json::Object(members));
+      }
+      let key = match self.scalar(b":,}")? {
+        Json::String(key) => key,
+        key => key.to_string(),
+      };
+      self.skip_whitespace();
+      let value = match self.peek() {
+        Some(b':') => {
+          self.pos += 1;
+          self.value()?
+        },
+        // `{key}` is a key with a null value
+        _ => Json::Null,
+      };
+      members.push((key, value));
+      if !self.separator(b'}')? {
+        self.pos += 1;
+        return Ok(Json::Object(members));
+      }
+    }
+  }
+
+  /// Skips a `,`, returning false at `close` instead.
+  fn separator(&mut self, close: u8) -> anyhow::Result<bool> {
+    self.skip_whitespace();
+    match self.peek() {
+      Some(b',') => {
+        self.pos += 1;
+        Ok(true)
+      },
+      Some(byte) if byte == close => Ok(false),
+      _ => bail!("expected , or {} in flow collection", close as char),
+    }
+  }
+
+  /// A quoted scalar, or a plain one ending before any of `ends`.
+  fn scalar(&mut self, ends: &[u8]) -> anyhow::Result<Json> {
+    self.skip_whitespace();
+    let rest = &self.input[self.pos..];
+    let len = match self.peek() {
+      Some(b'"') => {
+        let mut escaped = false;
+        let end = rest[1..]
+          .char_indices()
+          .find(|&(_, ch)| {
+            let end = ch == '"' && !escaped;
+            escaped = ch == '\\' && !escaped;
+            end
+          })
+          .context("unterminated string")?
+          .0;
+        end + 2
+      },
+      Some(b'\'') => {
+        let mut end = 1;
+        loop {
+          end += rest[end..].find('\'').context("unterminated string")? + 1;
+          if !rest[end..].starts_with('\'') {
+            break end;
+          }
+          end += 1;
+        }
+      },
+      _ => rest
+        .find(|ch: char| ch.is_ascii() && ends.contains(&(ch as u8)))
+        .unwrap_or(rest.len()),
+    };
+    self.pos += len;
+    match rest[..len].trim() {
+      "" => Ok(Json::Null),
+      // Collections as keys
+      text if text.starts_with(['[', '{']) => bail!("unexpected {text}"),
+      text => scalar(text),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn json(s: &str) -> Json {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn parses_blocks() {
+    let yaml = r#"
+# comment
+requests:
+- url: http://a/x # trailing
+  runs: 2
+  headers:
+    A: "quoted # not a comment"
+    'B': 'it''s'
+  body: |
+    line 1
+    line 2
+  folded: >-
+    a
+    b
+- - nested
+  - ~
+empty:
+"#;
+    assert_eq!(
+      parse(yaml).unwrap(),
+      json(
+        r#"{"requests": [
+          {"url": "http://a/x", "runs": 2,
+           "headers": {"A": "quoted # not a comment", "B": "it's"},
+           "body": "line 1\nline 2\n", "folded": "a b"},
+          ["nested", null]
+        ], "empty": null}"#
+      )
+    );
+  }
+
+  #[test]
+  fn parses_flow_collections() {
+    assert_eq!(
+      parse("- {url: 'http://a/x?b=1', runs: 2, headers: {A: \"b, c\"}}\n- [1, x y, [], {}]\n- {a, b: }")
+        .unwrap(),
+      json(
+        r#"[
+          {"url": "http://a/x?b=1", "runs": 2, "headers": {"A": "b, c"}},
+          [1, "x y", [], {}],
+          {"a": null, "b": null}
+        ]"#
+      )
+    );
+    assert_eq!(
+      parse(r#"headers: {"A": "1"}"#).unwrap(),
+      json(r#"{"headers": {"A": "1"}}"#)
+    );
+  }
+
+  #[test]
+  fn rejects_invalid() {
+    for yaml in [
+      "a: 1\n   b: 2",
+      "- a\n  - b",
+      "a: [1, 2",
+      "a: {b: 1} c",
+      "a: \"open",
+      "a: 'open",
+      "a: {{b}: 1}",
+    ] {
+      assert!(parse(yaml).is_err(), "{yaml}");
+    }
+  }
+
+  #[test]
+  fn limits_depth() {
+    assert!(parse(&"[".repeat(200_000)).is_err());
+    assert!(parse(&"{".repeat(200_000)).is_err());
+    assert!(parse(&"- ".repeat(200_000)).is_err());
+    let nested: String = (0..1_000)
+      .map(|depth| format!("{}a:\n", " ".repeat(depth)))
+      .collect();
+    assert!(parse(&nested).is_err());
+    assert!(parse(&"- ".repeat(10)).is_ok());
+  }
+}